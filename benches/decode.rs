@@ -0,0 +1,52 @@
+//! Compares the interpreter with and without the decode cache, and the
+//! block backend. Run with `cargo bench --bench decode`.
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use chip8_rust::block::Backend;
+use chip8_rust::bus::Bus;
+use chip8_rust::cpu::CPU;
+
+const CYCLES: u32 = 5_000_000;
+
+// An arithmetic loop in the style of a game's update routine:
+// 0x200: ADD V0, 1 ; XOR V1, V0 ; ADD V2, V1 ; SHR V3 ; SE V0, 0 ; LD V3, V2 ; LD V4, V2 ; JP 0x200
+const ALU_LOOP: [u8; 16] = [
+    0x70, 0x01, 0x81, 0x03, 0x82, 0x14, 0x83, 0x36,
+    0x30, 0x00, 0x83, 0x20, 0x84, 0x20, 0x12, 0x00,
+];
+
+fn run(program: &[u8], predecode: bool, backend: Backend) -> Duration {
+    let mut cpu = CPU::default();
+    cpu.load_sprites();
+    cpu.memory.load(0x200, program);
+    cpu.predecode = predecode;
+    cpu.backend = backend;
+
+    let start = Instant::now();
+    cpu.run(CYCLES);
+    black_box(&cpu.v);
+    start.elapsed()
+}
+
+fn report(name: &str, program: &[u8]) {
+    // Warm up once so both runs see the same cache and branch predictor state.
+    run(program, true, Backend::Interpreter);
+    let plain = run(program, false, Backend::Interpreter);
+    let cached = run(program, true, Backend::Interpreter);
+    let blocks = run(program, true, Backend::Blocks);
+    let mips = |time: Duration| CYCLES as f64 / time.as_secs_f64() / 1e6;
+    println!(
+        "{:<12} decode every cycle: {:>7.1} MIPS   decode cache: {:>7.1} MIPS ({:.2}x)   blocks: {:>7.1} MIPS ({:.2}x)",
+        name,
+        mips(plain),
+        mips(cached),
+        plain.as_secs_f64() / cached.as_secs_f64(),
+        mips(blocks),
+        plain.as_secs_f64() / blocks.as_secs_f64()
+    );
+}
+
+fn main() {
+    report("alu loop", &ALU_LOOP);
+    report("corax+", include_bytes!("../src/roms/3-corax+.ch8"));
+}
@@ -0,0 +1,110 @@
+//! Loads the core as a shared library, the way a libretro frontend does,
+//! runs a ROM for a few seconds and prints the screen.
+//!
+//! cargo build && cargo run --example libretro_harness -- target/debug/libchip8_rust.so src/roms/2-ibm-logo.ch8
+
+use std::ffi::{c_char, c_uint, c_void, CStr};
+use std::ptr;
+use std::sync::Mutex;
+use chip8_rust::libretro::{
+    RetroAudioSampleBatch, RetroEnvironment, RetroGameInfo, RetroInputPoll, RetroInputState, RetroSystemAvInfo,
+    RetroSystemInfo, RetroVideoRefresh,
+};
+use libloading::{Library, Symbol};
+
+const FRAMES: usize = 180;
+
+static SCREEN: Mutex<(Vec<u32>, usize)> = Mutex::new((Vec::new(), 0));
+
+extern "C" fn environment(_cmd: c_uint, _data: *mut c_void) -> bool {
+    // Accept the pixel format and options, report every option as unset.
+    true
+}
+
+extern "C" fn video_refresh(data: *const c_void, width: c_uint, height: c_uint, pitch: usize) {
+    let mut screen = SCREEN.lock().unwrap();
+    screen.0.clear();
+    for y in 0..height as usize {
+        // SAFETY: the core passes `height` rows of `pitch` bytes.
+        let row = unsafe { std::slice::from_raw_parts((data as *const u8).add(y * pitch) as *const u32, width as usize) };
+        screen.0.extend_from_slice(row);
+    }
+    screen.1 = width as usize;
+}
+
+extern "C" fn audio_sample_batch(_data: *const i16, frames: usize) -> usize {
+    frames
+}
+
+extern "C" fn input_poll() {}
+
+extern "C" fn input_state(_port: c_uint, _device: c_uint, _index: c_uint, _id: c_uint) -> i16 {
+    0
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(1);
+    let library = args.next().unwrap_or_else(|| "target/debug/libchip8_rust.so".to_string());
+    let rom = std::fs::read(args.next().unwrap_or_else(|| "src/roms/2-ibm-logo.ch8".to_string()))?;
+
+    // SAFETY: the library is this crate's core, whose functions have the
+    // signatures used here.
+    unsafe {
+        let core = Library::new(&library)?;
+        let api_version: Symbol<extern "C" fn() -> c_uint> = core.get(b"retro_api_version")?;
+        let get_system_info: Symbol<unsafe extern "C" fn(*mut RetroSystemInfo)> = core.get(b"retro_get_system_info")?;
+        let get_av_info: Symbol<unsafe extern "C" fn(*mut RetroSystemAvInfo)> = core.get(b"retro_get_system_av_info")?;
+        let set_environment: Symbol<extern "C" fn(RetroEnvironment)> = core.get(b"retro_set_environment")?;
+        let set_video_refresh: Symbol<extern "C" fn(RetroVideoRefresh)> = core.get(b"retro_set_video_refresh")?;
+        let set_audio_sample_batch: Symbol<extern "C" fn(RetroAudioSampleBatch)> = core.get(b"retro_set_audio_sample_batch")?;
+        let set_input_poll: Symbol<extern "C" fn(RetroInputPoll)> = core.get(b"retro_set_input_poll")?;
+        let set_input_state: Symbol<extern "C" fn(RetroInputState)> = core.get(b"retro_set_input_state")?;
+        let init: Symbol<extern "C" fn()> = core.get(b"retro_init")?;
+        let load_game: Symbol<unsafe extern "C" fn(*const RetroGameInfo) -> bool> = core.get(b"retro_load_game")?;
+        let run: Symbol<extern "C" fn()> = core.get(b"retro_run")?;
+        let serialize_size: Symbol<extern "C" fn() -> usize> = core.get(b"retro_serialize_size")?;
+        let serialize: Symbol<unsafe extern "C" fn(*mut c_void, usize) -> bool> = core.get(b"retro_serialize")?;
+        let unserialize: Symbol<unsafe extern "C" fn(*const c_void, usize) -> bool> = core.get(b"retro_unserialize")?;
+        let unload_game: Symbol<extern "C" fn()> = core.get(b"retro_unload_game")?;
+        let deinit: Symbol<extern "C" fn()> = core.get(b"retro_deinit")?;
+
+        let mut info = std::mem::zeroed::<RetroSystemInfo>();
+        get_system_info(&mut info);
+        let name = |text: *const c_char| CStr::from_ptr(text).to_string_lossy();
+        println!("{} {} (API {})", name(info.library_name), name(info.library_version), api_version());
+
+        set_environment(environment);
+        set_video_refresh(video_refresh);
+        set_audio_sample_batch(audio_sample_batch);
+        set_input_poll(input_poll);
+        set_input_state(input_state);
+        init();
+
+        let game = RetroGameInfo { path: ptr::null(), data: rom.as_ptr() as *const c_void, size: rom.len(), meta: ptr::null() };
+        if !load_game(&game) {
+            return Err("Core refused the ROM".into());
+        }
+        let mut av_info = std::mem::zeroed::<RetroSystemAvInfo>();
+        get_av_info(&mut av_info);
+        println!("{}x{} at {} fps", av_info.geometry.base_width, av_info.geometry.base_height, av_info.timing.fps);
+
+        for _ in 0..FRAMES {
+            run();
+        }
+        let mut state = vec![0u8; serialize_size()];
+        if !serialize(state.as_mut_ptr() as *mut c_void, state.len()) || !unserialize(state.as_ptr() as *const c_void, state.len()) {
+            return Err("Save state round trip failed".into());
+        }
+        println!("Save state: {} bytes", state.len());
+
+        unload_game();
+        deinit();
+    }
+
+    let screen = SCREEN.lock().unwrap();
+    let (background, width) = (screen.0.first().copied().unwrap_or(0), screen.1.max(1));
+    for row in screen.0.chunks(width) {
+        println!("{}", row.iter().map(|&pixel| if pixel == background { ' ' } else { '#' }).collect::<String>());
+    }
+    Ok(())
+}
@@ -7,13 +7,28 @@ const CHIP8_KEYMAP = {
     'KeyA': 0xA, 'KeyB': 0xB, 'KeyC': 0xC, 'KeyD': 0xD, 'KeyE': 0xE, 'KeyF': 0xF
 };
 
+// The arrow keys, Z and X play the actions of the ROM database's `keys`,
+// on the keys most games use unless the database says otherwise.
+const ACTION_CODES = {
+    up: 'ArrowUp', down: 'ArrowDown', left: 'ArrowLeft', right: 'ArrowRight', a: 'KeyZ', b: 'KeyX'
+};
+const DEFAULT_ACTION_KEYS = { up: 0x5, down: 0x8, left: 0x7, right: 0x9, a: 0x6, b: 0x4 };
+
+function setActionKeys(romInfo) {
+    const keys = { ...DEFAULT_ACTION_KEYS, ...JSON.parse(romInfo).keys };
+    for (const [action, code] of Object.entries(ACTION_CODES)) {
+        CHIP8_KEYMAP[code] = keys[action];
+    }
+}
+
 const keyStates = new Array(16).fill(false);
 
-function setupKeyListeners() {
+function setupKeyListeners(onChange) {
     window.addEventListener('keydown', (event) => {
         const chip8Key = CHIP8_KEYMAP[event.code];
         if (chip8Key !== undefined) {
             keyStates[chip8Key] = 1;
+            onChange(chip8Key, true);
         }
     });
 
@@ -21,53 +36,168 @@ function setupKeyListeners() {
         const chip8Key = CHIP8_KEYMAP[event.code];
         if (chip8Key !== undefined) {
             keyStates[chip8Key] = 0;
+            onChange(chip8Key, false);
         }
     });
 }
 
-async function run() {
-    await __wbg_init(); 
-    console.log("WASM module loaded successfully!");
+// RPL user flags (SUPER-CHIP high scores) are kept in localStorage per ROM.
+function loadFlags(sha1) {
+    return Uint8Array.from(JSON.parse(localStorage.getItem(`chip8-flags-${sha1}`) || '[]'));
+}
 
-    const canvas = document.getElementById('chip8-canvas');
-    const context = canvas.getContext('2d');
-    console.log("Canvas created");
+function saveFlags(sha1, flags) {
+    localStorage.setItem(`chip8-flags-${sha1}`, JSON.stringify(Array.from(flags)));
+}
+
+const CAPTURE_SCALE = 4;
+
+function download(bytes, name, type) {
+    const link = document.createElement('a');
+    link.href = URL.createObjectURL(new Blob([bytes], { type }));
+    link.download = name;
+    link.click();
+    URL.revokeObjectURL(link.href);
+}
+
+// Wires the capture buttons to `send`, which asks the emulator for a
+// screenshot or to start or stop recording and hands back the file, if any.
+function setupCapture(send) {
+    const record = document.getElementById('record');
+    let recording = false;
+    document.getElementById('screenshot').addEventListener('click', () => {
+        send({ type: 'screenshot', scale: CAPTURE_SCALE });
+    });
+    record.addEventListener('click', () => {
+        recording = !recording;
+        record.textContent = recording ? 'Stop recording' : 'Record GIF';
+        send({ type: recording ? 'start-recording' : 'stop-recording', scale: CAPTURE_SCALE });
+    });
+}
+
+function saveCapture(request, bytes) {
+    if (request === 'screenshot') {
+        download(bytes, 'chip8.png', 'image/png');
+    } else if (request === 'stop-recording' && bytes.length > 0) {
+        download(bytes, 'chip8.gif', 'image/gif');
+    }
+}
+
+// Emulation in a worker needs SharedArrayBuffer, which browsers only offer
+// on cross-origin isolated pages (COOP/COEP headers).
+function runInWorker(context, scale, updateRateDisplay) {
+    const worker = new Worker('./worker.js', { type: 'module' });
+    const keys = new Int32Array(new SharedArrayBuffer(2 * Int32Array.BYTES_PER_ELEMENT));
+    const frameBuffer = new SharedArrayBuffer(128 * 64);
+    const frame = new Uint8Array(frameBuffer);
+
+    setupKeyListeners((key, pressed) => {
+        if (pressed) {
+            Atomics.or(keys, 0, 1 << key);
+        } else {
+            Atomics.and(keys, 0, ~(1 << key));
+        }
+    });
+
+    setupCapture((request) => worker.postMessage(request));
+
+    worker.onmessage = (event) => {
+        if (event.data.type === 'file') {
+            saveCapture(event.data.request, event.data.bytes);
+            return;
+        }
+        if (event.data.type === 'flags') {
+            saveFlags(event.data.sha1, event.data.flags);
+            return;
+        }
+        if (event.data.type !== 'ready') {
+            return;
+        }
+        const { width, height, colors, romInfo, sha1 } = event.data;
+        setActionKeys(romInfo);
+        worker.postMessage({ type: 'start', flags: loadFlags(sha1) });
+        updateRateDisplay.textContent = 'Running in a Web Worker';
+
+        let lastFrame = -1;
+        function renderLoop() {
+            const frameCount = Atomics.load(keys, 1);
+            if (frameCount !== lastFrame) {
+                lastFrame = frameCount;
+                context.fillStyle = colors[0];
+                context.fillRect(0, 0, width * scale, height * scale);
+                context.fillStyle = colors[1];
+                for (let y = 0; y < height; y++) {
+                    for (let x = 0; x < width; x++) {
+                        if (frame[y * width + x]) {
+                            context.fillRect(x * scale, y * scale, scale, scale);
+                        }
+                    }
+                }
+            }
+            requestAnimationFrame(renderLoop);
+        }
+        renderLoop();
+    };
+    worker.postMessage({ type: 'init', frame: frameBuffer, keys: keys.buffer });
+}
 
+function runOnMainThread(context, scale, updateRateDisplay) {
     const chip8 = new Chip8();
     chip8.init();
-    setupKeyListeners();
+    chip8.set_flags(loadFlags(chip8.rom_sha1()));
+    setActionKeys(chip8.rom_info());
+    setupKeyListeners(() => {});
+    setupCapture((request) => {
+        switch (request.type) {
+            case 'screenshot':
+                saveCapture(request.type, chip8.screenshot(request.scale));
+                break;
+            case 'start-recording':
+                chip8.start_recording(request.scale);
+                break;
+            case 'stop-recording':
+                saveCapture(request.type, chip8.stop_recording());
+                break;
+        }
+    });
+    updateRateDisplay.textContent = `CPU Clock: ${chip8.clock_hz()} Hz`;
+
+    let lastTime = performance.now();
 
-    const scale = 10;
-    let lastLogicTime = performance.now();
-    const LOGIC_INTERVAL = 1.85;
-    
-    let stepsCount = 0;
-    let lastFrequencyTime = performance.now();
-    
-    const updateRateDisplay = document.getElementById("update-rate");
-    
     function mainLoop() {
         const now = performance.now();
-    
-        while (now - lastLogicTime >= LOGIC_INTERVAL) {
-            chip8.update_keyboard(keyStates);
-            chip8.step();
-            lastLogicTime += LOGIC_INTERVAL;
-            stepsCount++;
+
+        chip8.update_keyboard(keyStates);
+        if (chip8.run_for(now - lastTime)) {
+            chip8.render(context, scale);
         }
-    
-        if (now - lastFrequencyTime >= 1000) {
-            const frequencyHz = stepsCount;
-            updateRateDisplay.textContent = `CPU Frequency: ${frequencyHz} Hz`;
-            stepsCount = 0;
-            lastFrequencyTime = now;
+        if (chip8.take_flags_changed()) {
+            saveFlags(chip8.rom_sha1(), chip8.flags());
         }
-    
-        chip8.render(context, scale);
-    
+        lastTime = now;
+
         requestAnimationFrame(mainLoop);
     };
+    chip8.render(context, scale);
     mainLoop();
 }
 
+async function run() {
+    await __wbg_init();
+    console.log("WASM module loaded successfully!");
+
+    const canvas = document.getElementById('chip8-canvas');
+    const context = canvas.getContext('2d');
+    console.log("Canvas created");
+
+    const scale = 10;
+    const updateRateDisplay = document.getElementById("update-rate");
+
+    if (self.crossOriginIsolated) {
+        runInWorker(context, scale, updateRateDisplay);
+    } else {
+        runOnMainThread(context, scale, updateRateDisplay);
+    }
+}
+
 run();
@@ -0,0 +1,73 @@
+import __wbg_init, { Chip8 } from './chip8_rust.js';
+
+// Runs the emulator off the main thread. The page shares two buffers:
+//   frame: one byte per pixel, written here whenever the screen changes
+//   keys:  Int32Array, [0] = key bitmask (set by the page),
+//                      [1] = frame counter (bumped here after each new frame)
+// Workers have no localStorage, so the page sends the ROM's saved RPL flags
+// with 'start' and gets them back in a 'flags' message whenever they change.
+const TICK_MS = 4;
+
+let chip8;
+let frame;
+let keys;
+let lastTime;
+
+function tick() {
+    const now = performance.now();
+    chip8.sync_keys(keys);
+    chip8.run_for(now - lastTime);
+    lastTime = now;
+    if (chip8.take_flags_changed()) {
+        self.postMessage({ type: 'flags', sha1: chip8.rom_sha1(), flags: chip8.flags() });
+    }
+
+    if (chip8.export_framebuffer(frame)) {
+        Atomics.add(keys, 1, 1);
+    }
+    setTimeout(tick, TICK_MS);
+}
+
+// Captures asked for by the page, answered with the file's bytes.
+function capture(request) {
+    switch (request.type) {
+        case 'screenshot':
+            return chip8.screenshot(request.scale);
+        case 'start-recording':
+            chip8.start_recording(request.scale);
+            return null;
+        case 'stop-recording':
+            return chip8.stop_recording();
+    }
+    return null;
+}
+
+self.onmessage = async (event) => {
+    if (event.data.type === 'start') {
+        chip8.set_flags(event.data.flags);
+        lastTime = performance.now();
+        tick();
+        return;
+    }
+    if (event.data.type !== 'init') {
+        const bytes = capture(event.data);
+        if (bytes) {
+            self.postMessage({ type: 'file', request: event.data.type, bytes }, [bytes.buffer]);
+        }
+        return;
+    }
+    await __wbg_init();
+    frame = new Uint8Array(event.data.frame);
+    keys = new Int32Array(event.data.keys);
+
+    chip8 = new Chip8();
+    chip8.init();
+    self.postMessage({
+        type: 'ready',
+        width: chip8.display_width(),
+        height: chip8.display_height(),
+        colors: chip8.pixel_colors(),
+        romInfo: chip8.rom_info(),
+        sha1: chip8.rom_sha1(),
+    });
+};
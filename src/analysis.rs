@@ -0,0 +1,389 @@
+//! Static control flow analysis of ROMs. Code is found by following every
+//! path from the entry point, everything it does not reach is data.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use crate::chip8x;
+use crate::cpu::{Byte, Word};
+use crate::decode::Instruction;
+use crate::megachip;
+use crate::platform::Platform;
+
+/// How control gets from one basic block to the next.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flow {
+    /// The next instruction, including a skip that is not taken.
+    Fallthrough,
+    Jump,
+    /// A skip instruction that is taken.
+    Skip,
+    Call,
+    /// An entry of a BNNN jump table.
+    Table,
+}
+
+/// Straight-line code from `start` to `end` (exclusive).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub end: usize,
+    pub successors: Vec<(usize, Flow)>,
+}
+
+/// The longest jump table guessed for a BNNN, V0 can index 128 entries.
+const MAX_TABLE_ENTRIES: usize = 128;
+
+pub struct Analysis {
+    /// Address the ROM is loaded at.
+    pub origin: usize,
+    /// Decides which opcodes exist.
+    pub platform: Platform,
+    rom: Vec<Byte>,
+    /// Reachable instructions by address.
+    pub code: BTreeMap<usize, Instruction>,
+    pub blocks: BTreeMap<usize, BasicBlock>,
+    /// Entry points of CALLed routines.
+    pub subroutines: BTreeSet<usize>,
+    /// Entries found after each BNNN, by the address of the BNNN.
+    pub jump_tables: BTreeMap<usize, Vec<usize>>,
+    /// Sprites drawn from an `LD I` address, with their length in bytes.
+    pub sprites: BTreeMap<usize, usize>,
+    /// Every address loaded into I.
+    pub data_refs: BTreeSet<usize>,
+    /// Addresses jumped to or called, which get labels.
+    targets: BTreeSet<usize>,
+}
+
+/// Analyses the CHIP-8 program `rom` as loaded at `origin`, starting
+/// execution there.
+pub fn analyze(rom: &[Byte], origin: usize) -> Analysis {
+    analyze_for(Platform::Chip8, rom, origin)
+}
+
+/// Like `analyze`, for a program written for `platform`.
+pub fn analyze_for(platform: Platform, rom: &[Byte], origin: usize) -> Analysis {
+    let mut analysis = Analysis {
+        origin,
+        platform,
+        rom: rom.to_vec(),
+        code: BTreeMap::new(),
+        blocks: BTreeMap::new(),
+        subroutines: BTreeSet::new(),
+        jump_tables: BTreeMap::new(),
+        sprites: BTreeMap::new(),
+        data_refs: BTreeSet::new(),
+        targets: BTreeSet::from([origin]),
+    };
+    let mut leaders = BTreeSet::from([origin]);
+    let mut exits = BTreeMap::new();
+    let mut pending = vec![origin];
+
+    while let Some(addr) = pending.pop() {
+        if analysis.code.contains_key(&addr) {
+            continue;
+        }
+        let Some(ins) = analysis.fetch(addr) else {
+            continue;
+        };
+        analysis.code.insert(addr, ins);
+        if let Instruction::LdI(target) = ins {
+            analysis.data_refs.insert(target as usize);
+        }
+
+        let Some(successors) = analysis.control_flow(addr, ins) else {
+            pending.push(addr + 2);
+            continue;
+        };
+        for &(target, flow) in &successors {
+            leaders.insert(target);
+            if matches!(flow, Flow::Jump | Flow::Call | Flow::Table) {
+                analysis.targets.insert(target);
+            }
+            pending.push(target);
+        }
+        exits.insert(addr, successors);
+    }
+
+    for &start in &leaders {
+        if !analysis.code.contains_key(&start) {
+            continue;
+        }
+        let mut addr = start;
+        let successors = loop {
+            if let Some(successors) = exits.get(&addr) {
+                break successors.clone();
+            }
+            addr += 2;
+            if !analysis.code.contains_key(&addr) {
+                break Vec::new();
+            }
+            if leaders.contains(&addr) {
+                break vec![(addr, Flow::Fallthrough)];
+            }
+        };
+        let end = exits.get(&addr).map_or(addr, |_| addr + 2);
+        analysis.blocks.insert(start, BasicBlock { start, end, successors });
+    }
+
+    analysis.find_sprites();
+    analysis
+}
+
+impl Analysis {
+    fn fetch(&self, addr: usize) -> Option<Instruction> {
+        let offset = addr.checked_sub(self.origin)?;
+        let bytes = self.rom.get(offset..offset + 2)?;
+        Some(self.platform.decode(((bytes[0] as Word) << 8) | bytes[1] as Word))
+    }
+
+    /// Where an instruction that ends a basic block can go next, or `None`
+    /// for instructions that just continue with the next one.
+    fn control_flow(&mut self, addr: usize, ins: Instruction) -> Option<Vec<(usize, Flow)>> {
+        let next = addr + 2;
+        Some(match ins {
+            Instruction::Jp(target) => vec![(target as usize, Flow::Jump)],
+            Instruction::Call(target) => {
+                self.subroutines.insert(target as usize);
+                vec![(target as usize, Flow::Call), (next, Flow::Fallthrough)]
+            }
+            Instruction::SeVx(..)
+            | Instruction::SneVx(..)
+            | Instruction::SeVxVy(..)
+            | Instruction::SneVxVy(..)
+            | Instruction::SkpVx(_)
+            | Instruction::SknpVx(_)
+            | Instruction::Chip8X(chip8x::Op::SkpVx2(_) | chip8x::Op::SknpVx2(_)) => vec![(next, Flow::Fallthrough), (next + 2, Flow::Skip)],
+            Instruction::JpV0(table) => {
+                // Usually BNNN indexes a list of JPs at NNN. Take every JP
+                // from there on as an entry.
+                let entries = (0..MAX_TABLE_ENTRIES)
+                    .map(|entry| table as usize + entry * 2)
+                    .take_while(|&entry| matches!(self.fetch(entry), Some(Instruction::Jp(_))))
+                    .collect::<Vec<_>>();
+                let entries = if entries.is_empty() { vec![table as usize] } else { entries };
+                self.jump_tables.insert(addr, entries.clone());
+                entries.into_iter().map(|entry| (entry, Flow::Table)).collect()
+            }
+            // MegaChip's long I load is followed by the low 16 bits.
+            Instruction::Mega(megachip::Op::LdILong(_)) => vec![(next + 2, Flow::Fallthrough)],
+            // Machine code calls and unknown opcodes are most likely data
+            // reached by a wrong guess, so stop there.
+            Instruction::Ret | Instruction::Sys(_) | Instruction::Unknown(_) => Vec::new(),
+            _ => return None,
+        })
+    }
+
+    /// Takes `LD I, NNN` followed by `DRW` in the same block as a sprite at
+    /// NNN, as tall as the tallest such draw.
+    fn find_sprites(&mut self) {
+        for block in self.blocks.values() {
+            let mut i = None;
+            for addr in (block.start..block.end).step_by(2) {
+                match self.code[&addr] {
+                    Instruction::LdI(target) => i = Some(target as usize),
+                    Instruction::AddIVx(_) | Instruction::LdFVx(_) => i = None,
+                    Instruction::DrwVxVy(_, _, n) => {
+                        if let Some(sprite) = i {
+                            // DXY0 draws 16x16 on SUPER-CHIP.
+                            let len = if n == 0 { 32 } else { n as usize };
+                            let entry = self.sprites.entry(sprite).or_default();
+                            *entry = (*entry).max(len);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// `len` bytes of the ROM from `addr`, if they are all inside it.
+    pub fn bytes(&self, addr: usize, len: usize) -> Option<&[Byte]> {
+        let offset = addr.checked_sub(self.origin)?;
+        self.rom.get(offset..offset + len)
+    }
+
+    pub fn is_code(&self, addr: usize) -> bool {
+        self.code.contains_key(&addr) || (addr > 0 && self.code.contains_key(&(addr - 1)))
+    }
+
+    /// The label of `addr`, if anything refers to it.
+    pub fn label(&self, addr: usize) -> Option<String> {
+        if addr == self.origin {
+            Some("start".to_string())
+        } else if self.subroutines.contains(&addr) {
+            Some(format!("sub_{:03X}", addr))
+        } else if self.targets.contains(&addr) {
+            Some(format!("L{:03X}", addr))
+        } else if self.sprites.contains_key(&addr) {
+            Some(format!("sprite_{:03X}", addr))
+        } else if self.data_refs.contains(&addr) {
+            Some(format!("data_{:03X}", addr))
+        } else {
+            None
+        }
+    }
+
+    fn instruction(&self, ins: Instruction) -> String {
+        let mut text = String::new();
+        ins.write(&mut text, |nnn| self.label(nnn as usize).unwrap_or_else(|| format!("0x{:03X}", nnn)))
+            .unwrap();
+        text
+    }
+
+    /// A disassembly with labels, code and data told apart and sprites
+    /// drawn as pixels.
+    pub fn disassembly(&self) -> String {
+        let mut out = String::new();
+        let end = self.origin + self.rom.len();
+        writeln!(
+            out,
+            "; 0x{:03X}-0x{:03X}: {} instructions, {} subroutines, {} sprites",
+            self.origin,
+            end,
+            self.code.len(),
+            self.subroutines.len(),
+            self.sprites.len()
+        )
+        .unwrap();
+
+        let mut addr = self.origin;
+        let mut sprite_end = 0;
+        while addr < end {
+            if let Some(label) = self.label(addr) {
+                writeln!(out, "\n{}:", label).unwrap();
+            }
+            if let Some(&ins) = self.code.get(&addr) {
+                let word = ((self.rom[addr - self.origin] as Word) << 8) | self.rom[addr + 1 - self.origin] as Word;
+                writeln!(out, "  0x{:03X}  {:04X}  {}", addr, word, self.instruction(ins)).unwrap();
+                addr += 2;
+                continue;
+            }
+            if let Some(len) = self.sprites.get(&addr) {
+                sprite_end = addr + len;
+            }
+            if addr < sprite_end {
+                let byte = self.rom[addr - self.origin];
+                let pixels = (0..8).map(|bit| if byte & (0x80 >> bit) != 0 { '#' } else { '.' }).collect::<String>();
+                writeln!(out, "  0x{:03X}  {:02X}    {}", addr, byte, pixels).unwrap();
+                addr += 1;
+                continue;
+            }
+
+            // Plain data, up to 8 bytes a line, broken where something starts.
+            let start = addr;
+            let mut bytes = Vec::new();
+            while addr < end && bytes.len() < 8 && !self.code.contains_key(&addr) && !self.sprites.contains_key(&addr) {
+                if addr != start && self.label(addr).is_some() {
+                    break;
+                }
+                bytes.push(format!("{:02X}", self.rom[addr - self.origin]));
+                addr += 1;
+            }
+            writeln!(out, "  0x{:03X}  DB {}", start, bytes.join(" ")).unwrap();
+        }
+        out
+    }
+
+    /// The control flow graph in Graphviz DOT format, one node per basic
+    /// block. Calls are dashed, taken skips and table jumps are labelled.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph cfg {{").unwrap();
+        writeln!(out, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+        for block in self.blocks.values() {
+            let mut label = String::new();
+            if let Some(name) = self.label(block.start) {
+                write!(label, "{}:\\l", name).unwrap();
+            }
+            for addr in (block.start..block.end).step_by(2) {
+                write!(label, "0x{:03X}  {}\\l", addr, self.instruction(self.code[&addr])).unwrap();
+            }
+            let style = if self.subroutines.contains(&block.start) { ", style=bold" } else { "" };
+            writeln!(out, "    n{:03X} [label=\"{}\"{}];", block.start, label, style).unwrap();
+        }
+        for block in self.blocks.values() {
+            for &(target, flow) in &block.successors {
+                if !self.blocks.contains_key(&target) {
+                    continue;
+                }
+                let attributes = match flow {
+                    Flow::Fallthrough | Flow::Jump => "",
+                    Flow::Skip => " [label=\"skip\"]",
+                    Flow::Call => " [style=dashed]",
+                    Flow::Table => " [label=\"table\"]",
+                };
+                writeln!(out, "    n{:03X} -> n{:03X}{};", block.start, target, attributes).unwrap();
+            }
+        }
+        writeln!(out, "}}").unwrap();
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: [Byte; 27] = [
+        0xA2, 0x16, // 200: LD I, 0x216
+        0xD0, 0x15, // 202: DRW V0, V1, 5
+        0x22, 0x10, // 204: CALL 0x210
+        0x30, 0x01, // 206: SE V0, 0x01
+        0xB2, 0x0C, // 208: JP V0, 0x20C
+        0x12, 0x00, // 20A: JP 0x200
+        0x12, 0x00, // 20C: JP 0x200
+        0x12, 0x0A, // 20E: JP 0x20A
+        0x70, 0x01, // 210: ADD V0, 0x01
+        0x00, 0xEE, // 212: RET
+        0x00, 0x00, // 214: unreachable
+        0xF0, 0x90, 0x90, 0x90, 0xF0, // 216: sprite
+    ];
+
+    #[test]
+    fn test_analyze() {
+        let analysis = analyze(&PROGRAM, 0x200);
+
+        assert_eq!(analysis.code.len(), 10, "Wrong number of reachable instructions");
+        assert!(!analysis.is_code(0x214), "Unreachable bytes taken as code");
+        assert_eq!(analysis.subroutines, BTreeSet::from([0x210]), "Subroutine not found");
+        assert_eq!(analysis.jump_tables[&0x208], [0x20C, 0x20E], "Jump table not found");
+        assert_eq!(analysis.sprites, BTreeMap::from([(0x216, 5)]), "Sprite not found");
+
+        assert_eq!(analysis.blocks.len(), 7, "Wrong number of basic blocks");
+        assert_eq!(
+            analysis.blocks[&0x200],
+            BasicBlock { start: 0x200, end: 0x206, successors: vec![(0x210, Flow::Call), (0x206, Flow::Fallthrough)] },
+            "Call did not end the block"
+        );
+        assert_eq!(
+            analysis.blocks[&0x206].successors,
+            [(0x208, Flow::Fallthrough), (0x20A, Flow::Skip)],
+            "Skip not followed both ways"
+        );
+    }
+
+    #[test]
+    fn test_output() {
+        let analysis = analyze(&PROGRAM, 0x200);
+
+        let disassembly = analysis.disassembly();
+        assert!(disassembly.contains("start:\n  0x200  A216  LD I, sprite_216"), "Sprite label not used");
+        assert!(disassembly.contains("  0x204  2210  CALL sub_210"), "Subroutine label not used");
+        assert!(disassembly.contains("  0x214  DB 00 00"), "Data not shown as bytes");
+        assert!(disassembly.contains("  0x216  F0    ####...."), "Sprite not drawn");
+
+        let dot = analysis.to_dot();
+        assert!(dot.starts_with("digraph cfg {"), "Not a DOT graph");
+        assert!(dot.contains("n200 -> n210 [style=dashed];"), "Call edge missing");
+        assert!(dot.contains("n208 -> n20E [label=\"table\"];"), "Table edge missing");
+    }
+
+    #[test]
+    fn test_analyze_roms() {
+        for rom in [&include_bytes!("roms/3-corax+.ch8")[..], include_bytes!("roms/5-quirks.ch8")] {
+            let analysis = analyze(rom, 0x200);
+            assert!(analysis.code.len() > 50, "Too little code found");
+            assert!(analysis.code.keys().all(|&addr| addr >= 0x200 && addr < 0x200 + rom.len()), "Code outside the ROM");
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//! The sound output: a 1-bit pattern played while ST is non-zero. Plain
+//! CHIP-8 gets a fixed square wave, XO-CHIP programs load their own 128-bit
+//! pattern with F002 and set its playback rate with FX3A.
+
+use crate::bus::Bus;
+use crate::cpu::{Byte, CPU};
+
+/// 16 bytes, played most significant bit first.
+pub type Pattern = [Byte; 16];
+
+/// A 500 Hz square wave at the default pitch.
+pub const BUZZER: Pattern = [0xF0; 16];
+
+pub(crate) const VOLUME: i16 = 8_192;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Audio {
+    pub pattern: Pattern,
+    /// Playback rate, 4000 * 2^((pitch - 64) / 48) bits per second.
+    pub pitch: Byte,
+    /// Position in the pattern, in bits.
+    position: f64,
+}
+
+impl Default for Audio {
+    fn default() -> Self {
+        Audio { pattern: BUZZER, pitch: Audio::DEFAULT_PITCH, position: 0.0 }
+    }
+}
+
+impl Audio {
+    pub const DEFAULT_PITCH: Byte = 64;
+
+    /// Pattern bits played per second.
+    pub fn rate(&self) -> f64 {
+        4000.0 * 2f64.powf((self.pitch as f64 - 64.0) / 48.0)
+    }
+
+    /// Appends one 60 Hz frame of mono samples, `sample_rate / 60` of them.
+    /// Each sound starts at the beginning of the pattern.
+    pub fn render_frame(&mut self, sounding: bool, sample_rate: u32, out: &mut Vec<i16>) {
+        let samples = (sample_rate / 60) as usize;
+        if !sounding {
+            self.position = 0.0;
+            out.resize(out.len() + samples, 0);
+            return;
+        }
+        let step = self.rate() / sample_rate as f64;
+        for _ in 0..samples {
+            let bit = self.position as usize;
+            let on = self.pattern[bit / 8] & (0x80 >> (bit % 8)) != 0;
+            out.push(if on { VOLUME } else { -VOLUME });
+            self.position = (self.position + step) % 128.0;
+        }
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    /// Appends the sound of the frame just run, see `Audio::render_frame`.
+    /// MegaChip sound, while playing, replaces the buzzer.
+    pub fn render_audio(&mut self, sample_rate: u32, out: &mut Vec<i16>) {
+        if self.render_mega_audio(sample_rate, out) {
+            return;
+        }
+        let sounding = self.st > 0;
+        self.audio.render_frame(sounding, sample_rate, out);
+    }
+}
+
+/// 16-bit mono PCM samples as a WAV file.
+pub fn wav(samples: &[i16], sample_rate: u32) -> Vec<u8> {
+    let data_len = samples.len() as u32 * 2;
+    let mut out = Vec::with_capacity(44 + data_len as usize);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(36 + data_len).to_le_bytes());
+    out.extend_from_slice(b"WAVEfmt ");
+    out.extend_from_slice(&16u32.to_le_bytes());
+    out.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    out.extend_from_slice(&1u16.to_le_bytes()); // mono
+    out.extend_from_slice(&sample_rate.to_le_bytes());
+    out.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+    out.extend_from_slice(&2u16.to_le_bytes());
+    out.extend_from_slice(&16u16.to_le_bytes());
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples {
+        out.extend_from_slice(&sample.to_le_bytes());
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+
+    #[test]
+    fn test_render_frame() {
+        let mut audio = Audio::default();
+        let mut out = Vec::new();
+        audio.render_frame(true, 48_000, &mut out);
+        assert_eq!(out.len(), 800, "Wrong number of samples");
+        // 4000 bits per second at 48 kHz are 12 samples a bit.
+        assert!(out[..48].iter().all(|&sample| sample == VOLUME), "First half wave wrong");
+        assert!(out[48..96].iter().all(|&sample| sample == -VOLUME), "Second half wave wrong");
+
+        audio.pitch = 112;
+        assert_eq!(audio.rate(), 8000.0, "48 steps of pitch are not an octave");
+
+        out.clear();
+        audio.render_frame(false, 48_000, &mut out);
+        assert!(out.iter().all(|&sample| sample == 0), "Sound while ST is zero");
+    }
+
+    #[test]
+    fn test_pattern_opcodes() {
+        let mut cpu = CPU::default();
+        cpu.i = 0x300;
+        for offset in 0..16 {
+            cpu.memory[0x300 + offset] = offset as Byte;
+        }
+        cpu.v[0x3] = 100;
+        for (offset, byte) in [0xF0, 0x02, 0xF3, 0x3A].into_iter().enumerate() {
+            cpu.memory[0x200 + offset] = byte;
+        }
+        cpu.execute();
+        cpu.execute();
+
+        assert_eq!(cpu.audio.pattern, std::array::from_fn(|offset| offset as Byte), "F002 did not load the pattern");
+        assert_eq!(cpu.audio.pitch, 100, "FX3A did not set the pitch");
+    }
+
+    #[test]
+    fn test_render_audio() {
+        let mut cpu = CPU::default();
+        // LD V0, 0x03; LD ST, V0; JP 0x204
+        for (offset, byte) in [0x60, 0x03, 0xF0, 0x18, 0x12, 0x04].into_iter().enumerate() {
+            cpu.memory[0x200 + offset] = byte;
+        }
+        let mut frames = Vec::new();
+        for _ in 0..5 {
+            cpu.run_frame();
+            let mut samples = Vec::new();
+            cpu.render_audio(6_000, &mut samples);
+            frames.push(samples.iter().any(|&sample| sample != 0));
+        }
+        assert_eq!(frames, [true, true, false, false, false], "Sound not as long as ST");
+    }
+
+    #[test]
+    fn test_wav() {
+        let wav = wav(&[0, 1, -1], 44_100);
+        assert_eq!(&wav[..4], b"RIFF", "Not a RIFF file");
+        assert_eq!(&wav[8..16], b"WAVEfmt ", "Not a WAV file");
+        assert_eq!(u32::from_le_bytes(wav[40..44].try_into().unwrap()), 6, "Wrong data length");
+        assert_eq!(&wav[44..], [0, 0, 1, 0, 0xFF, 0xFF], "Wrong samples");
+    }
+}
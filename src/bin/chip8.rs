@@ -0,0 +1,308 @@
+//! Command line tools for working with ROMs.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::ExitCode;
+use chip8_rust::analysis::analyze_for;
+use chip8_rust::audio;
+use chip8_rust::bus::{Bus, Ram16M, Ram64K};
+use chip8_rust::capture::{self, GifRecorder};
+use chip8_rust::cheats;
+use chip8_rust::flags;
+use chip8_rust::patch;
+use chip8_rust::cpu::CPU;
+use chip8_rust::database::{Database, RomConfig};
+use chip8_rust::platform::Platform;
+use chip8_rust::probes::{ProbeSet, Probes};
+use chip8_rust::rom::Rom;
+use chip8_rust::sprites;
+
+const USAGE: &str = "\
+Usage: chip8 <command> <rom> [options]
+
+Commands:
+  disasm <rom>     Labelled disassembly, code and data told apart
+  cfg <rom>        Control flow graph in Graphviz DOT format
+  sprites <rom>    Sprites the ROM draws, as text or a PNG sprite sheet
+  run <rom>        Run the ROM for a while and save the screen
+  diff <rom> <modified> <patch>
+                   Write the changes from <rom> to <modified> as a patch,
+                   BPS if <patch> ends in .bps, IPS otherwise
+
+Options:
+  --origin <addr>  Load address, 0x200 by default
+  --platform <id>  Run as on a platform from the ROM database, such as
+                   chip8x, instead of the ROM's own
+  --frames <n>     run: frames to run, 600 by default
+                   sprites: run the ROM for n frames and take the sprites
+                   it draws instead of those found by the analysis
+  --png <file>     run: write a screenshot of the last frame
+                   sprites: write a sprite sheet instead of text
+  --gif <file>     run: record every frame into an animated GIF
+  --wav <file>     run: record the sound into a WAV file (44.1 kHz mono)
+  --cheats <dir>   Apply the cheat codes in <dir>/<sha1>.cht, if there
+  --patch <file>   Apply an IPS or BPS patch to the ROM first
+  --flags <dir>    run: keep the RPL flags (FX75/FX85) in <dir>/<sha1>.flags
+  --keys <file>    run: replay input, lines of <frame> <keys> holding the keys
+                   (a hex bitmask) from that frame on
+  --probes <file>  run: print when the values in a probe file change, and
+                   fail unless its expected values are reached
+  --scale <n>      Pixel size in images, 4 by default";
+
+const SAMPLE_RATE: u32 = 44_100;
+
+struct Options {
+    origin: Option<usize>,
+    platform: Option<String>,
+    frames: Option<u32>,
+    png: Option<String>,
+    gif: Option<String>,
+    wav: Option<String>,
+    cheats: Option<String>,
+    patch: Option<String>,
+    flags: Option<String>,
+    keys: Option<String>,
+    probes: Option<String>,
+    /// The ROM before `patch`, which the database knows.
+    base: Option<Rom>,
+    scale: usize,
+}
+
+fn parse_addr(text: &str) -> Result<usize, String> {
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| format!("Not an address: {}", text))
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut options = Options { origin: None, platform: None, frames: None, png: None, gif: None, wav: None, cheats: None, patch: None, flags: None, keys: None, probes: None, base: None, scale: 4 };
+    let mut args = args.iter();
+    while let Some(option) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} needs a value", option));
+        let number = |text: &String| text.parse::<usize>().map_err(|_| format!("Not a number: {}", text));
+        match option.as_str() {
+            "--origin" => options.origin = Some(parse_addr(value()?)?),
+            "--platform" => options.platform = Some(value()?.clone()),
+            "--frames" => options.frames = Some(number(value()?)? as u32),
+            "--png" => options.png = Some(value()?.clone()),
+            "--gif" => options.gif = Some(value()?.clone()),
+            "--wav" => options.wav = Some(value()?.clone()),
+            "--cheats" => options.cheats = Some(value()?.clone()),
+            "--patch" => options.patch = Some(value()?.clone()),
+            "--flags" => options.flags = Some(value()?.clone()),
+            "--keys" => options.keys = Some(value()?.clone()),
+            "--probes" => options.probes = Some(value()?.clone()),
+            "--scale" => options.scale = number(value()?)?.max(1),
+            _ => return Err(format!("Unknown option: {}\n\n{}", option, USAGE)),
+        }
+    }
+    Ok(options)
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|err| format!("Cannot read {}: {}", path, err))
+}
+
+fn write_file(path: &str, data: &[u8]) -> Result<(), String> {
+    std::fs::write(path, data).map_err(|err| format!("Cannot write {}: {}", path, err))
+}
+
+/// How to run the ROM: as the ROM database says, unless the options
+/// ask for a platform or load address.
+fn config(rom: &Rom, options: &Options) -> Result<RomConfig, String> {
+    let db = Database::embedded();
+    let mut config = match &options.platform {
+        Some(id) => db.platform_config(id).ok_or(format!("Unknown platform: {}", id))?,
+        None => options.base.as_ref().unwrap_or(rom).config(db),
+    };
+    if let Some(origin) = options.origin {
+        config.start_address = Some(origin as u16);
+    }
+    Ok(config)
+}
+
+/// The platform the ROM is written for and where it is loaded.
+fn platform(data: &[u8], options: &Options) -> Result<(Platform, usize), String> {
+    let config = config(&Rom::new(data), options)?;
+    let platform = config.platform.as_deref().map(Platform::from_id).unwrap_or_default();
+    Ok((platform, config.start_address.unwrap_or(platform.start_address()) as usize))
+}
+
+/// `cpu` with the ROM loaded as `config` says and its cheats, and its
+/// colours.
+fn load<B: Bus>(mut cpu: CPU<B>, data: &[u8], options: &Options) -> Result<(CPU<B>, capture::Palette), String> {
+    let rom = Rom::new(data);
+    let config = config(&rom, options)?;
+    cpu.load_rom(&rom, &config).map_err(|err| err.to_string())?;
+    if let Some(dir) = &options.cheats {
+        let path = Path::new(dir).join(cheats::file_name(&rom.sha1));
+        if let Ok(text) = std::fs::read_to_string(&path) {
+            cpu.cheats = cheats::parse(&text).map_err(|err| format!("{}: {}", path.display(), err))?;
+        }
+    }
+    Ok((cpu, capture::palette(config.colors.as_ref())))
+}
+
+/// MegaChip programs get their 16M of memory.
+fn run_rom(data: &[u8], options: &Options) -> Result<(), String> {
+    match platform(data, options)?.0.memory_size() {
+        Ram16M::SIZE => run_on(CPU::with_bus(Ram16M::new()), data, options),
+        Ram64K::SIZE => run_on(CPU::with_bus(Ram64K::new()), data, options),
+        _ => run_on(CPU::default(), data, options),
+    }
+}
+
+/// Key changes by frame from a `--keys` file.
+fn parse_keys(text: &str) -> Result<BTreeMap<u32, u16>, String> {
+    text.lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let error = || format!("Not \"<frame> <keys>\": {}", line);
+            let (frame, keys) = line.split_once(char::is_whitespace).ok_or_else(error)?;
+            let keys = keys.trim().trim_start_matches("0x");
+            Ok((frame.parse().map_err(|_| error())?, u16::from_str_radix(keys, 16).map_err(|_| error())?))
+        })
+        .collect()
+}
+
+fn run_on<B: Bus>(cpu: CPU<B>, data: &[u8], options: &Options) -> Result<(), String> {
+    let (mut cpu, palette) = load(cpu, data, options)?;
+    // Keyed by the ROM the database knows, so patches share the flags.
+    let sha1 = options.base.as_ref().map_or_else(|| Rom::new(data).sha1, |base| base.sha1.clone());
+    let flags = options.flags.as_ref().map(|dir| Path::new(dir).join(flags::file_name(&sha1)));
+    if let Some(saved) = flags.as_ref().and_then(|path| std::fs::read(path).ok()) {
+        cpu.set_flags(&saved);
+    }
+    let keys = match &options.keys {
+        Some(path) => parse_keys(&String::from_utf8_lossy(&read_file(path)?))?,
+        None => BTreeMap::new(),
+    };
+    let mut probes = match &options.probes {
+        Some(path) => {
+            let set = ProbeSet::parse(&String::from_utf8_lossy(&read_file(path)?)).map_err(|err| format!("{}: {}", path, err))?;
+            let probes = Probes::new(set);
+            probes.watch(&mut cpu);
+            Some(probes)
+        }
+        None => None,
+    };
+    let mut recorder = options.gif.as_ref().map(|_| GifRecorder::new(palette, options.scale));
+    let mut samples = Vec::new();
+    for frame in 0..options.frames.unwrap_or(600) {
+        if let Some(&mask) = keys.get(&frame) {
+            cpu.set_keys(mask);
+        }
+        cpu.run_frame();
+        if let Some(probes) = probes.as_mut() {
+            for event in probes.update(&cpu) {
+                println!("{}", event);
+            }
+        }
+        if let Some(recorder) = recorder.as_mut() {
+            recorder.add_cpu_frame(&cpu);
+        }
+        if options.wav.is_some() {
+            cpu.render_audio(SAMPLE_RATE, &mut samples);
+        }
+    }
+
+    if let Some(path) = &options.png {
+        write_file(path, &capture::cpu_screenshot(&cpu, palette, options.scale).to_png())?;
+    }
+    if let (Some(path), Some(recorder)) = (&options.gif, recorder) {
+        write_file(path, &recorder.finish())?;
+    }
+    if let Some(path) = &options.wav {
+        write_file(path, &audio::wav(&samples, SAMPLE_RATE))?;
+    }
+    if let Some(path) = flags.filter(|_| cpu.take_flags_changed()) {
+        std::fs::write(&path, cpu.flags).map_err(|err| format!("Cannot write {}: {}", path.display(), err))?;
+    }
+    if let Some((name, value)) = probes.as_ref().and_then(|probes| probes.unmet().first().copied()) {
+        return Err(format!("{} never reached {}", name, value));
+    }
+    Ok(())
+}
+
+fn extract_sprites(data: &[u8], options: &Options) -> Result<(), String> {
+    let found = match options.frames {
+        None => {
+            let (platform, origin) = platform(data, options)?;
+            sprites::from_analysis(&analyze_for(platform, data, origin))
+        }
+        Some(frames) => {
+            let (mut cpu, _) = load(CPU::default(), data, options)?;
+            cpu.sprite_log = Some(Vec::new());
+            for _ in 0..frames {
+                cpu.run_frame();
+            }
+            sprites::from_draws(&cpu.sprite_log.unwrap_or_default())
+        }
+    };
+    match &options.png {
+        None => print!("{}", sprites::text_grid(&found)),
+        Some(path) => {
+            write_file(path, &sprites::sprite_sheet(&found, 8, options.scale).to_png())?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes a patch from the ROM at `original` to the one named in `args`.
+fn diff(original: &str, args: &[String]) -> Result<(), String> {
+    let [modified, out] = args else {
+        return Err(USAGE.to_string());
+    };
+    let (original, modified) = (read_file(original)?, read_file(modified)?);
+    let patch = if out.ends_with(".bps") {
+        patch::create_bps(&original, &modified)
+    } else {
+        patch::create_ips(&original, &modified).map_err(|err| err.to_string())?
+    };
+    write_file(out, &patch)
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let [command, path, options @ ..] = args else {
+        return Err(USAGE.to_string());
+    };
+    if command == "diff" {
+        return diff(path, options);
+    }
+    let mut options = parse_options(options)?;
+    let mut rom = read_file(path)?;
+    if let Some(patch) = &options.patch {
+        let base = Rom::new(&rom);
+        rom = base.patched(&read_file(patch)?).map_err(|err| format!("{}: {}", patch, err))?.data;
+        options.base = Some(base);
+    }
+
+    match command.as_str() {
+        "disasm" => {
+            let (platform, origin) = platform(&rom, &options)?;
+            print!("{}", analyze_for(platform, &rom, origin).disassembly());
+        }
+        "cfg" => {
+            let (platform, origin) = platform(&rom, &options)?;
+            print!("{}", analyze_for(platform, &rom, origin).to_dot());
+        }
+        "sprites" => extract_sprites(&rom, &options)?,
+        "run" => run_rom(&rom, &options)?,
+        _ => return Err(format!("Unknown command: {}\n\n{}", command, USAGE)),
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("{}", message);
+            ExitCode::FAILURE
+        }
+    }
+}
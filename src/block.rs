@@ -0,0 +1,263 @@
+use crate::bus::Bus;
+use crate::chip8x;
+use crate::cpu::{Word, CPU};
+use crate::decode::{decode, Instruction};
+use crate::megachip;
+
+/// Selects how `CPU::run` executes instructions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// Fetch, decode and execute one instruction at a time.
+    Interpreter,
+    /// Translate straight-line code into cached blocks and run those.
+    Blocks,
+}
+
+/// A straight-line run of instructions starting at `start`. Only the last
+/// instruction may change the control flow or write to memory.
+pub struct Block {
+    pub start: usize,
+    pub ops: Box<[Instruction]>,
+}
+
+impl Block {
+    pub const MAX_OPS: usize = 32;
+
+    pub fn end(&self) -> usize {
+        self.start + self.ops.len() * 2
+    }
+
+    pub fn covers(&self, addr: usize) -> bool {
+        addr >= self.start && addr < self.end()
+    }
+}
+
+/// Translated blocks indexed by their start address. The block being run is
+/// taken out of the cache and put back afterwards unless it was overwritten.
+pub struct BlockCache {
+    blocks: Vec<Option<Block>>,
+    running: Option<(usize, usize)>,
+    stale: bool,
+}
+
+impl BlockCache {
+    pub fn new(size: usize) -> Self {
+        let mut blocks = Vec::with_capacity(size);
+        blocks.resize_with(size, || None);
+        Self { blocks, running: None, stale: false }
+    }
+
+    pub fn get(&self, addr: usize) -> Option<&Block> {
+        self.blocks.get(addr).and_then(|block| block.as_ref())
+    }
+
+    pub fn take(&mut self, addr: usize) -> Option<Block> {
+        let block = self.blocks.get_mut(addr)?.take()?;
+        self.enter(&block);
+        Some(block)
+    }
+
+    /// Marks `block` as the one running, so writes into it are noticed.
+    pub fn enter(&mut self, block: &Block) {
+        self.running = Some((block.start, block.end()));
+        self.stale = false;
+    }
+
+    /// Whether the block taken last has been written to since.
+    pub fn is_stale(&self) -> bool {
+        self.stale
+    }
+
+    pub fn put_back(&mut self, block: Block) {
+        self.running = None;
+        if !self.stale {
+            self.insert(block);
+        }
+    }
+
+    pub fn insert(&mut self, block: Block) {
+        let start = block.start;
+        self.blocks[start] = Some(block);
+    }
+
+    /// Drops every block containing `addr`.
+    pub fn invalidate(&mut self, addr: usize) {
+        if self.running.is_some_and(|(start, end)| addr >= start && addr < end) {
+            self.stale = true;
+        }
+        let first = addr.saturating_sub(Block::MAX_OPS * 2 - 1);
+        for start in first..=addr.min(self.blocks.len() - 1) {
+            if self.blocks[start].as_ref().is_some_and(|block| block.covers(addr)) {
+                self.blocks[start] = None;
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.blocks.iter_mut().for_each(|block| *block = None);
+        self.stale = true;
+    }
+}
+
+/// Whether the block has to end after `ins`: anything that jumps, skips,
+/// waits or writes to memory (the write could patch the block itself).
+fn ends_block(ins: Instruction) -> bool {
+    matches!(
+        ins,
+        Instruction::Ret
+            | Instruction::Jp(_)
+            | Instruction::Call(_)
+            | Instruction::JpV0(_)
+            | Instruction::SeVx(..)
+            | Instruction::SneVx(..)
+            | Instruction::SeVxVy(..)
+            | Instruction::SneVxVy(..)
+            | Instruction::SkpVx(_)
+            | Instruction::SknpVx(_)
+            | Instruction::Chip8X(chip8x::Op::SkpVx2(_) | chip8x::Op::SknpVx2(_))
+            | Instruction::LdVxK(_)
+            | Instruction::DrwVxVy(..)
+            | Instruction::LdBVx(_)
+            | Instruction::LdStoIVx(_)
+            // Reads the next word.
+            | Instruction::Mega(megachip::Op::LdILong(_))
+    )
+}
+
+impl<B: Bus> CPU<B> {
+    fn translate(&mut self, start: usize) -> Block {
+        let size = self.memory.size();
+        let mut ops = Vec::new();
+        let mut addr = start;
+        while ops.len() < Block::MAX_OPS && addr + 1 < size {
+            let ins = self.platform.decode(((self.memory.read(addr) as Word) << 8) | self.memory.read(addr + 1) as Word);
+            ops.push(ins);
+            addr += 2;
+            if ends_block(ins) {
+                break;
+            }
+        }
+        Block { start, ops: ops.into() }
+    }
+
+    /// Runs up to `cycles` instructions from translated blocks and returns
+    /// how many were executed.
+    pub(crate) fn run_blocks(&mut self, cycles: u32) -> u32 {
+        let mut done = 0;
+        while done < cycles {
+            let start = self.pc as usize;
+            if self.vblank_wait || start + 1 >= self.memory.size() {
+                // Let the interpreter idle through the vertical blank wait and
+                // handle fetches that wrap around memory.
+                self.execute();
+                done += 1;
+                continue;
+            }
+            let block = match self.blocks.take(start) {
+                Some(block) => block,
+                None => {
+                    let block = self.translate(start);
+                    self.blocks.enter(&block);
+                    block
+                }
+            };
+            // Loops that jump back to their own start stay in the block.
+            loop {
+                // Only the last instruction of a block looks at PC, so it is
+                // updated once per block instead of once per instruction.
+                let len = block.ops.len().min((cycles - done) as usize);
+                let (last, body) = block.ops[..len].split_last().unwrap();
+                for (index, &ins) in body.iter().enumerate() {
+                    self.latch_pc((start + index * 2) as Word);
+                    self.tick();
+                    self.exec(ins);
+                }
+                self.latch_pc((start + body.len() * 2) as Word);
+                self.pc = (start + len * 2) as Word;
+                self.tick();
+                self.exec(*last);
+                done += len as u32;
+                if done == cycles || self.pc as usize != start || self.blocks.is_stale() {
+                    break;
+                }
+            }
+            self.blocks.put_back(block);
+        }
+        done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quirks::{Edge, MemoryQuirk, Quirks};
+
+    fn compare_backends(program: &[u8], cycles: u32, quirks: Quirks) {
+        let mut reference = CPU::default();
+        let mut blocks = CPU::default();
+        for cpu in [&mut reference, &mut blocks] {
+            cpu.load_sprites();
+            cpu.memory.load(0x200, program);
+            cpu.quirks = quirks;
+        }
+        blocks.backend = Backend::Blocks;
+
+        // Run in uneven slices so blocks get interrupted part way through.
+        let mut remaining = cycles;
+        while remaining > 0 {
+            let slice = remaining.min(97);
+            assert_eq!(reference.run(slice), slice);
+            assert_eq!(blocks.run(slice), slice);
+            remaining -= slice;
+
+            assert_eq!(blocks.pc, reference.pc, "PC diverged");
+            assert_eq!(blocks.v, reference.v, "Registers diverged at PC {:X}", reference.pc);
+            assert_eq!(blocks.i, reference.i, "I diverged at PC {:X}", reference.pc);
+            assert_eq!(blocks.sp, reference.sp, "SP diverged at PC {:X}", reference.pc);
+            assert_eq!(blocks.stack, reference.stack, "Stack diverged at PC {:X}", reference.pc);
+            assert_eq!((blocks.dt, blocks.st), (reference.dt, reference.st), "Timers diverged");
+        }
+        assert!(blocks.memory.bytes() == reference.memory.bytes(), "Memory diverged");
+        assert!(blocks.display == reference.display, "Display diverged");
+    }
+
+    #[test]
+    fn test_blocks_match_interpreter() {
+        let quirks = Quirks::default();
+        compare_backends(include_bytes!("roms/1-chip8-logo.ch8"), 2_000, quirks);
+        compare_backends(include_bytes!("roms/2-ibm-logo.ch8"), 2_000, quirks);
+        compare_backends(include_bytes!("roms/3-corax+.ch8"), 20_000, quirks);
+        compare_backends(include_bytes!("roms/4-flags.ch8"), 20_000, quirks);
+
+        let quirks = Quirks {
+            vf_reset: false,
+            shift: false,
+            memory: MemoryQuirk::IncrementByXPlusOne,
+            jump: true,
+            display_wait: true,
+            edge_x: Edge::Wrap,
+            edge_y: Edge::Wrap,
+            collision_rows: true,
+        };
+        compare_backends(include_bytes!("roms/3-corax+.ch8"), 50_000, quirks);
+    }
+
+    #[test]
+    fn test_blocks_self_modifying_code() {
+        let mut cpu = CPU::default();
+        cpu.backend = Backend::Blocks;
+
+        // 0x200: LD V0, 0x6E ; LD V1, 0x6E ; LD I, 0x20A ; LD [I], V1 ; LD VE, 0x01 (patched to LD VE, 0x6E)
+        let program = [0x60, 0x6E, 0x61, 0x6E, 0xA2, 0x0A, 0xF1, 0x55, 0x12, 0x0A, 0x6E, 0x01];
+        cpu.memory.load(0x200, &program);
+
+        // Translate the block containing 0x20A before it gets patched.
+        cpu.pc = 0x20A;
+        cpu.run(1);
+        assert_eq!(cpu.v[0xE], 0x01, "Original instruction did not run");
+
+        cpu.pc = 0x200;
+        cpu.run(6);
+        assert_eq!(cpu.v[0xE], 0x6E, "Stale block was executed after FX55");
+    }
+}
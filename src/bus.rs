@@ -0,0 +1,304 @@
+use std::collections::BTreeSet;
+use std::ops::{Index, IndexMut};
+use crate::cpu::{Byte, CPU};
+
+/// Memory as seen by the CPU. Every instruction goes through `read`/`write`,
+/// so an implementation can observe or redirect all memory traffic.
+/// Addresses past the end of the bus wrap around.
+pub trait Bus {
+    fn bytes(&self) -> &[Byte];
+    fn bytes_mut(&mut self) -> &mut [Byte];
+
+    fn size(&self) -> usize {
+        self.bytes().len()
+    }
+
+    fn read(&mut self, addr: usize) -> Byte {
+        let len = self.size();
+        self.bytes()[addr % len]
+    }
+
+    fn write(&mut self, addr: usize, value: Byte) {
+        let len = self.size();
+        self.bytes_mut()[addr % len] = value;
+    }
+
+    /// Whether accesses are being watched, so that every instruction fetch
+    /// has to go through `read` instead of the decode cache or a translated
+    /// block.
+    fn observed(&self) -> bool {
+        false
+    }
+
+    /// Copies `data` to `addr` without going through the access hooks,
+    /// used for loading fonts and programs.
+    fn load(&mut self, addr: usize, data: &[Byte]) {
+        let end = addr + data.len();
+        if end > self.size() {
+            panic!("Data at {:X}..{:X} does not fit into memory!", addr, end);
+        }
+        self.bytes_mut()[addr..end].copy_from_slice(data);
+    }
+}
+
+macro_rules! impl_ram {
+    ($name:ident, $size:expr) => {
+        impl $name {
+            pub const SIZE: usize = $size;
+
+            pub fn new() -> Self {
+                Self { bytes: vec![0; $size].into_boxed_slice() }
+            }
+        }
+
+        impl Default for $name {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        impl Bus for $name {
+            fn bytes(&self) -> &[Byte] {
+                &self.bytes
+            }
+
+            fn bytes_mut(&mut self) -> &mut [Byte] {
+                &mut self.bytes
+            }
+        }
+
+        impl Index<usize> for $name {
+            type Output = Byte;
+
+            fn index(&self, addr: usize) -> &Byte {
+                &self.bytes[addr]
+            }
+        }
+
+        impl IndexMut<usize> for $name {
+            fn index_mut(&mut self, addr: usize) -> &mut Byte {
+                &mut self.bytes[addr]
+            }
+        }
+    };
+}
+
+/// The original 4K address space.
+#[derive(Clone)]
+pub struct Ram4K {
+    bytes: Box<[Byte]>,
+}
+
+/// The 64K address space of XO-CHIP.
+#[derive(Clone)]
+pub struct Ram64K {
+    bytes: Box<[Byte]>,
+}
+
+/// The 16M address space of MegaChip, reached through its 24-bit I.
+#[derive(Clone)]
+pub struct Ram16M {
+    bytes: Box<[Byte]>,
+}
+
+impl_ram!(Ram4K, CPU::MEM_SIZE);
+impl_ram!(Ram64K, 0x10000);
+impl_ram!(Ram16M, 0x100_0000);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Access {
+    pub kind: AccessKind,
+    pub addr: usize,
+    pub value: Byte,
+}
+
+pub type AccessHook = Box<dyn FnMut(&Access) + Send>;
+
+/// Wraps another bus and records the traffic going through it: per-address
+/// read/write counters for heatmaps, an optional access log, watchpoint hits
+/// and a user hook called on every access.
+pub struct InstrumentedBus<B: Bus> {
+    pub inner: B,
+    pub reads: Vec<u32>,
+    pub writes: Vec<u32>,
+    pub log: Option<Vec<Access>>,
+    pub watchpoints: BTreeSet<usize>,
+    pub hits: Vec<Access>,
+    hook: Option<AccessHook>,
+}
+
+impl<B: Bus> InstrumentedBus<B> {
+    pub fn new(inner: B) -> Self {
+        let len = inner.size();
+        Self {
+            inner,
+            reads: vec![0; len],
+            writes: vec![0; len],
+            log: None,
+            watchpoints: BTreeSet::new(),
+            hits: Vec::new(),
+            hook: None,
+        }
+    }
+
+    /// Starts keeping every access in `log`.
+    pub fn record(&mut self) {
+        self.log = Some(Vec::new());
+    }
+
+    pub fn watch(&mut self, addr: usize) {
+        self.watchpoints.insert(addr % self.inner.size());
+    }
+
+    pub fn unwatch(&mut self, addr: usize) {
+        self.watchpoints.remove(&(addr % self.inner.size()));
+    }
+
+    pub fn set_hook(&mut self, hook: impl FnMut(&Access) + Send + 'static) {
+        self.hook = Some(Box::new(hook));
+    }
+
+    pub fn clear_hook(&mut self) {
+        self.hook = None;
+    }
+
+    /// Resets the counters, the log and the watchpoint hits.
+    pub fn clear(&mut self) {
+        self.reads.iter_mut().for_each(|n| *n = 0);
+        self.writes.iter_mut().for_each(|n| *n = 0);
+        if let Some(log) = self.log.as_mut() {
+            log.clear();
+        }
+        self.hits.clear();
+    }
+
+    fn observe(&mut self, access: Access) {
+        match access.kind {
+            AccessKind::Read => self.reads[access.addr] = self.reads[access.addr].saturating_add(1),
+            AccessKind::Write => self.writes[access.addr] = self.writes[access.addr].saturating_add(1),
+        }
+        if let Some(log) = self.log.as_mut() {
+            log.push(access);
+        }
+        if self.watchpoints.contains(&access.addr) {
+            self.hits.push(access);
+        }
+        if let Some(hook) = self.hook.as_mut() {
+            hook(&access);
+        }
+    }
+}
+
+impl<B: Bus> Bus for InstrumentedBus<B> {
+    fn bytes(&self) -> &[Byte] {
+        self.inner.bytes()
+    }
+
+    fn bytes_mut(&mut self) -> &mut [Byte] {
+        self.inner.bytes_mut()
+    }
+
+    fn read(&mut self, addr: usize) -> Byte {
+        let addr = addr % self.inner.size();
+        let value = self.inner.read(addr);
+        self.observe(Access { kind: AccessKind::Read, addr, value });
+        value
+    }
+
+    fn write(&mut self, addr: usize, value: Byte) {
+        let addr = addr % self.inner.size();
+        self.inner.write(addr, value);
+        self.observe(Access { kind: AccessKind::Write, addr, value });
+    }
+
+    fn observed(&self) -> bool {
+        true
+    }
+}
+
+impl<B: Bus> Index<usize> for InstrumentedBus<B> {
+    type Output = Byte;
+
+    fn index(&self, addr: usize) -> &Byte {
+        &self.inner.bytes()[addr]
+    }
+}
+
+impl<B: Bus> IndexMut<usize> for InstrumentedBus<B> {
+    fn index_mut(&mut self, addr: usize) -> &mut Byte {
+        &mut self.inner.bytes_mut()[addr]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use crate::block::Backend;
+
+    #[test]
+    fn test_ram_wraps() {
+        let mut ram = Ram4K::new();
+        ram.write(0x1005, 0xAB);
+        assert_eq!(ram[0x005], 0xAB, "Write past 4K did not wrap");
+        assert_eq!(ram.read(0x1005), 0xAB, "Read past 4K did not wrap");
+
+        let mut ram = Ram64K::new();
+        ram.write(0x1005, 0xAB);
+        assert_eq!(ram[0x1005], 0xAB, "64K RAM wrapped at 4K");
+    }
+
+    #[test]
+    fn test_instrumented_bus() {
+        let mut cpu = CPU::with_bus(InstrumentedBus::new(Ram4K::new()));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        cpu.memory.set_hook(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        cpu.memory.record();
+        cpu.memory.watch(0x300);
+
+        // LD I, 0x300 ; LD V0, 0x7B ; LD B, V0
+        cpu.memory[0x200] = 0xA3;
+        cpu.memory[0x201] = 0x00;
+        cpu.memory[0x202] = 0x60;
+        cpu.memory[0x203] = 0x7B;
+        cpu.memory[0x204] = 0xF0;
+        cpu.memory[0x205] = 0x33;
+        cpu.execute();
+        cpu.execute();
+        cpu.execute();
+
+        assert_eq!(cpu.memory.reads[0x200], 1, "Fetch was not counted as a read");
+        assert_eq!(cpu.memory.writes[0x300..0x303], [1, 1, 1], "BCD writes not counted");
+        assert_eq!(
+            cpu.memory.hits,
+            vec![Access { kind: AccessKind::Write, addr: 0x300, value: 1 }],
+            "Watchpoint did not record the write"
+        );
+        assert_eq!(cpu.memory.log.as_ref().unwrap().len(), 9, "Access log incomplete");
+        assert_eq!(calls.load(Ordering::Relaxed), 9, "Hook not called for every access");
+    }
+
+    #[test]
+    fn test_instrumented_loop() {
+        for backend in [Backend::Interpreter, Backend::Blocks] {
+            let mut cpu = CPU::with_bus(InstrumentedBus::new(Ram4K::new()));
+            cpu.backend = backend;
+            cpu.memory.watch(0x202);
+            // 0x200: LD V0, 0x01 ; JP 0x200
+            cpu.memory.load(0x200, &[0x60, 0x01, 0x12, 0x00]);
+            cpu.run(10);
+            assert_eq!(cpu.memory.reads[0x200..0x204], [5, 5, 5, 5], "Cached fetches not counted on {:?}", backend);
+            assert_eq!(cpu.memory.hits.len(), 5, "Read watchpoint missed cached fetches on {:?}", backend);
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! Screenshots and animated GIF recordings of the display. CHIP-8X
+//! programs are captured in the colours of their colour board, MegaChip
+//! programs in mega mode show the MegaChip screen.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use crate::bus::Bus;
+use crate::chip8x::{self, ColorBoard};
+use crate::cpu::CPU;
+use crate::database::Colors;
+use crate::display::{Framebuffer, Row};
+use crate::image::{parse_color, Image};
+use crate::megachip::{self, MegaChip};
+
+/// Background and foreground colour.
+pub type Palette = [[u8; 3]; 2];
+
+pub const DEFAULT_PALETTE: Palette = [[0x00, 0x00, 0x00], [0xFF, 0xFF, 0x00]];
+
+/// The palette from the ROM database's colours, falling back to black
+/// and yellow.
+pub fn palette(colors: Option<&Colors>) -> Palette {
+    let pixels = colors.map_or(&[][..], |colors| &colors.pixels[..]);
+    let color = |index: usize| pixels.get(index).and_then(|color| parse_color(color));
+    [color(0).unwrap_or(DEFAULT_PALETTE[0]), color(1).unwrap_or(DEFAULT_PALETTE[1])]
+}
+
+/// The colour of the pixel at (`x`, `y`), from the colour board if there
+/// is one.
+pub fn pixel_color(palette: Palette, color: Option<&ColorBoard>, x: usize, y: usize, on: bool) -> [u8; 3] {
+    match color {
+        Some(board) => board.pixel(x, y, on),
+        None => palette[on as usize],
+    }
+}
+
+/// The display as an image, each pixel `scale` x `scale` large.
+pub fn screenshot<R: Row>(display: &Framebuffer<R>, palette: Palette, color: Option<&ColorBoard>, scale: usize) -> Image {
+    let scale = scale.max(1);
+    let mut image = Image::new(display.width() * scale, display.height() * scale, palette[0]);
+    for y in 0..display.height() {
+        for x in 0..display.width() {
+            image.set_scaled(x, y, scale, pixel_color(palette, color, x, y, display.get(x, y)));
+        }
+    }
+    image
+}
+
+/// The MegaChip screen as an image.
+pub fn mega_screenshot(mega: &MegaChip, scale: usize) -> Image {
+    let scale = scale.max(1);
+    let mut image = Image::new(megachip::WIDTH * scale, megachip::HEIGHT * scale, [0; 3]);
+    for y in 0..megachip::HEIGHT {
+        for x in 0..megachip::WIDTH {
+            image.set_scaled(x, y, scale, mega.pixel(x, y));
+        }
+    }
+    image
+}
+
+/// What `cpu` shows: the MegaChip screen in mega mode, else the display.
+pub fn cpu_screenshot<B: Bus>(cpu: &CPU<B>, palette: Palette, scale: usize) -> Image {
+    match cpu.mega.as_ref().filter(|mega| mega.enabled) {
+        Some(mega) => mega_screenshot(mega, scale),
+        None => screenshot(&cpu.display, palette, cpu.color.as_ref(), scale),
+    }
+}
+
+struct Frame {
+    width: usize,
+    height: usize,
+    /// One byte per pixel, an index into the recorder's colours or `colors`.
+    pixels: Vec<u8>,
+    /// The frame's own colours, for MegaChip frames.
+    colors: Option<Vec<[u8; 3]>>,
+    /// How many 60 Hz frames it was shown for.
+    shown: u32,
+}
+
+/// Collects one frame per 60 Hz tick and encodes them as a looping GIF.
+/// Frames that repeat the previous one only make it stay longer.
+pub struct GifRecorder {
+    /// The palette followed by the colour board's colours.
+    colors: Vec<[u8; 3]>,
+    scale: usize,
+    frames: Vec<Frame>,
+}
+
+impl GifRecorder {
+    pub fn new(palette: Palette, scale: usize) -> Self {
+        let colors = palette.iter().chain(&chip8x::COLORS).copied().collect();
+        GifRecorder { colors, scale: scale.max(1), frames: Vec::new() }
+    }
+
+    pub fn add_frame<R: Row>(&mut self, display: &Framebuffer<R>, color: Option<&ColorBoard>) {
+        let (width, height) = (display.width(), display.height());
+        let mut pixels = vec![0; width * height];
+        display.write_pixels(&mut pixels);
+        if let Some(board) = color {
+            for (index, pixel) in pixels.iter_mut().enumerate() {
+                *pixel = 2 + board.pixel_index(index % width, index / width, *pixel != 0);
+            }
+        }
+        self.push(Frame { width, height, pixels, colors: None, shown: 1 });
+    }
+
+    /// Adds what `cpu` shows, the MegaChip screen in mega mode.
+    pub fn add_cpu_frame<B: Bus>(&mut self, cpu: &CPU<B>) {
+        match cpu.mega.as_ref().filter(|mega| mega.enabled) {
+            Some(mega) => self.add_mega_frame(mega),
+            None => self.add_frame(&cpu.display, cpu.color.as_ref()),
+        }
+    }
+
+    /// Adds the MegaChip screen in its own colours, or in 3-3-2 bit RGB
+    /// when blending made more than a GIF's 256.
+    fn add_mega_frame(&mut self, mega: &MegaChip) {
+        let (width, height) = (megachip::WIDTH, megachip::HEIGHT);
+        let screen = (0..width * height).map(|offset| mega.pixel(offset % width, offset / width));
+        let mut indices = HashMap::new();
+        let mut colors = Vec::new();
+        let mut pixels = Vec::with_capacity(width * height);
+        for rgb in screen.clone() {
+            let index = *indices.entry(rgb).or_insert_with(|| {
+                colors.push(rgb);
+                colors.len() - 1
+            });
+            pixels.push(index as u8);
+        }
+        if colors.len() > 256 {
+            let level = |value: usize, bits: u32| (value * 255 / ((1 << bits) - 1)) as u8;
+            colors = (0..256).map(|index| [level(index >> 5, 3), level((index >> 2) & 7, 3), level(index & 3, 2)]).collect();
+            pixels = screen.map(|[r, g, b]| (r & 0xE0) | ((g >> 3) & 0x1C) | (b >> 6)).collect();
+        }
+        self.push(Frame { width, height, pixels, colors: Some(colors), shown: 1 });
+    }
+
+    fn push(&mut self, frame: Frame) {
+        match self.frames.last_mut() {
+            Some(last) if (last.width, last.height, &last.pixels, &last.colors) == (frame.width, frame.height, &frame.pixels, &frame.colors) => {
+                last.shown += 1
+            }
+            _ => self.frames.push(frame),
+        }
+    }
+
+    /// Length of the recording in 60 Hz frames.
+    pub fn frames(&self) -> u32 {
+        self.frames.iter().map(|frame| frame.shown).sum()
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        let scale = self.scale;
+        let width = self.frames.iter().map(|frame| frame.width).max().unwrap_or(0) * scale;
+        let height = self.frames.iter().map(|frame| frame.height).max().unwrap_or(0) * scale;
+        let mut out = Vec::new();
+        {
+            // Writing into a Vec only fails for sizes over 65535 pixels.
+            let mut encoder = gif::Encoder::new(&mut out, width as u16, height as u16, self.colors.as_flattened())
+                .expect("Recording too large for a GIF");
+            encoder.set_repeat(gif::Repeat::Infinite).unwrap();
+
+            // GIF delays are in 1/100 s. Round the running time rather than
+            // each frame, and give frames shorter than 2/100 s, which
+            // browsers slow down, to the next one.
+            let mut elapsed = 0;
+            let mut written = 0;
+            let last = self.frames.len().saturating_sub(1);
+            for (index, frame) in self.frames.iter().enumerate() {
+                elapsed += frame.shown;
+                let delay = (elapsed * 100 + 30) / 60 - written;
+                if delay < 2 && index != last {
+                    continue;
+                }
+                written += delay;
+
+                let mut buffer = vec![0; frame.width * scale * frame.height * scale];
+                for y in 0..frame.height * scale {
+                    for x in 0..frame.width * scale {
+                        buffer[y * frame.width * scale + x] = frame.pixels[(y / scale) * frame.width + x / scale];
+                    }
+                }
+                let gif_frame = gif::Frame {
+                    width: (frame.width * scale) as u16,
+                    height: (frame.height * scale) as u16,
+                    buffer: Cow::Owned(buffer),
+                    palette: frame.colors.as_ref().map(|colors| colors.as_flattened().to_vec()),
+                    delay: delay as u16,
+                    ..gif::Frame::default()
+                };
+                encoder.write_frame(&gif_frame).expect("Recording too large for a GIF");
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Ram16M;
+    use crate::platform::Platform;
+    use crate::quirks::Edge;
+
+    #[test]
+    fn test_screenshot() {
+        let mut display: Framebuffer = Framebuffer::new(32);
+        display.set(1, 0, true);
+        let image = screenshot(&display, DEFAULT_PALETTE, None, 2);
+
+        assert_eq!((image.width, image.height), (128, 64), "Not scaled");
+        assert_eq!(&image.rgb[..3], DEFAULT_PALETTE[0], "Background not drawn");
+        assert_eq!(&image.rgb[2 * 3..3 * 3], DEFAULT_PALETTE[1], "Pixel not drawn");
+        assert_eq!(&image.rgb[(128 + 3) * 3..(128 + 4) * 3], DEFAULT_PALETTE[1], "Pixel not scaled");
+    }
+
+    #[test]
+    fn test_gif_recorder() {
+        let mut display: Framebuffer = Framebuffer::new(32);
+        let mut recorder = GifRecorder::new(DEFAULT_PALETTE, 1);
+        for frame in 0..12 {
+            if frame % 4 == 0 {
+                display.draw_sprite(frame, 0, &[0xFF], Edge::Clip, Edge::Clip);
+            }
+            recorder.add_frame(&display, None);
+        }
+        assert_eq!(recorder.frames(), 12, "Frames lost");
+        assert_eq!(recorder.frames.len(), 3, "Repeated frames not merged");
+
+        let gif = recorder.finish();
+        let mut decoder = gif::DecodeOptions::new().read_info(&gif[..]).unwrap();
+        let mut delays = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            delays.push(frame.delay);
+        }
+        assert_eq!(delays, [7, 6, 7], "Wrong delays");
+    }
+
+    #[test]
+    fn test_color_board() {
+        let mut display: Framebuffer = Framebuffer::new(32);
+        display.set(1, 0, true);
+        let board = ColorBoard::default();
+        let image = screenshot(&display, DEFAULT_PALETTE, Some(&board), 1);
+        assert_eq!(&image.rgb[..3], board.background(), "Background colour not drawn");
+        assert_eq!(&image.rgb[3..6], board.foreground(1, 0), "Zone colour not drawn");
+
+        let mut recorder = GifRecorder::new(DEFAULT_PALETTE, 1);
+        recorder.add_frame(&display, Some(&board));
+        let gif = recorder.finish();
+        let mut decoder = gif::DecodeOptions::new();
+        decoder.set_color_output(gif::ColorOutput::RGBA);
+        let mut decoder = decoder.read_info(&gif[..]).unwrap();
+        let frame = decoder.read_next_frame().unwrap().unwrap();
+        assert_eq!(&frame.buffer[4..7], board.foreground(1, 0), "Zone colour not recorded");
+    }
+
+    #[test]
+    fn test_mega_recording() {
+        let mut cpu = CPU::with_bus(Ram16M::new());
+        cpu.set_platform(Platform::MegaChip);
+        let mega = cpu.mega.as_mut().unwrap();
+        mega.enabled = true;
+        mega.palette[1] = [0x10, 0x20, 0x30];
+        mega.draw(1, 0, &[1]);
+        mega.flip();
+
+        let mut recorder = GifRecorder::new(DEFAULT_PALETTE, 1);
+        recorder.add_cpu_frame(&cpu);
+        let gif = recorder.finish();
+        let mut decoder = gif::DecodeOptions::new();
+        decoder.set_color_output(gif::ColorOutput::RGBA);
+        let mut decoder = decoder.read_info(&gif[..]).unwrap();
+        let frame = decoder.read_next_frame().unwrap().unwrap();
+        assert_eq!((frame.width as usize, frame.height as usize), (megachip::WIDTH, megachip::HEIGHT), "MegaChip screen not recorded");
+        assert_eq!(&frame.buffer[4..7], [0x10, 0x20, 0x30], "MegaChip colour not recorded");
+    }
+
+    #[test]
+    fn test_palette() {
+        let colors = Colors { pixels: vec!["#102030".to_string()], ..Colors::default() };
+        assert_eq!(palette(Some(&colors)), [[0x10, 0x20, 0x30], DEFAULT_PALETTE[1]], "Colours not used");
+        assert_eq!(palette(None), DEFAULT_PALETTE, "No default palette");
+    }
+}
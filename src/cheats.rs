@@ -0,0 +1,224 @@
+//! Cheats: searching memory for the bytes that hold lives or levels, and
+//! freezing memory or registers at a value every frame.
+//!
+//! Codes are text, `ADDR:NN` for memory or `VX:NN` for a register, all in
+//! hex, e.g. `2F0:09` or `V3:05`. A cheat file holds one code per line and
+//! `#` starts a comment; several codes on one line are joined by `+`, as
+//! libretro frontends send them. Files are named after the ROM's SHA-1, see
+//! `file_name`.
+
+use std::fmt;
+use std::str::FromStr;
+use crate::bus::Bus;
+use crate::cpu::{Byte, CPU};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+    Memory(usize),
+    Register(u8),
+}
+
+/// Keeps `target` at `value`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cheat {
+    pub target: Target,
+    pub value: Byte,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CheatError {
+    /// Not `ADDR:NN` or `VX:NN`.
+    Syntax(String),
+}
+
+impl fmt::Display for CheatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheatError::Syntax(code) => write!(f, "Not a cheat code: {}", code),
+        }
+    }
+}
+
+impl std::error::Error for CheatError {}
+
+impl FromStr for Cheat {
+    type Err = CheatError;
+
+    fn from_str(code: &str) -> Result<Self, Self::Err> {
+        let error = || CheatError::Syntax(code.to_string());
+        let (target, value) = code.trim().split_once(':').ok_or_else(error)?;
+        let hex = |text: &str| usize::from_str_radix(text.trim().trim_start_matches("0x"), 16).map_err(|_| error());
+        let target = match target.trim().strip_prefix(['V', 'v']) {
+            Some(register) => Target::Register(u8::from_str_radix(register, 16).ok().filter(|&x| x <= 0xF).ok_or_else(error)?),
+            None => Target::Memory(hex(target)?),
+        };
+        let value = Byte::try_from(hex(value)?).map_err(|_| error())?;
+        Ok(Cheat { target, value })
+    }
+}
+
+impl fmt::Display for Cheat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.target {
+            Target::Memory(addr) => write!(f, "{:03X}:{:02X}", addr, self.value),
+            Target::Register(x) => write!(f, "V{:X}:{:02X}", x, self.value),
+        }
+    }
+}
+
+/// Every code in `text`, skipping comments and empty lines.
+pub fn parse(text: &str) -> Result<Vec<Cheat>, CheatError> {
+    text.lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .flat_map(|line| line.split('+'))
+        .filter(|code| !code.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// `cheats` as a cheat file, one code per line.
+pub fn to_text(cheats: &[Cheat]) -> String {
+    cheats.iter().map(|cheat| format!("{}\n", cheat)).collect()
+}
+
+/// The name of the cheat file for the ROM with the given SHA-1.
+pub fn file_name(sha1: &str) -> String {
+    format!("{}.cht", sha1)
+}
+
+/// How a byte has to relate to its value at the last search step to stay a
+/// candidate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    /// Now holds this value.
+    Equal(Byte),
+    Changed,
+    Unchanged,
+    /// Went up by exactly this much, wrapping.
+    Increased(Byte),
+    /// Went down by exactly this much, wrapping.
+    Decreased(Byte),
+}
+
+impl Comparison {
+    fn matches(self, old: Byte, new: Byte) -> bool {
+        match self {
+            Comparison::Equal(value) => new == value,
+            Comparison::Changed => new != old,
+            Comparison::Unchanged => new == old,
+            Comparison::Increased(n) => new == old.wrapping_add(n),
+            Comparison::Decreased(n) => new == old.wrapping_sub(n),
+        }
+    }
+}
+
+/// Narrows down the addresses holding a value by comparing snapshots of
+/// memory, e.g. lose a life, keep what decreased by 1, repeat.
+#[derive(Clone, Debug)]
+pub struct Search {
+    snapshot: Vec<Byte>,
+    candidates: Vec<usize>,
+}
+
+impl Search {
+    /// Starts with every address of `memory` as a candidate.
+    pub fn new(memory: &[Byte]) -> Self {
+        Search { snapshot: memory.to_vec(), candidates: (0..memory.len()).collect() }
+    }
+
+    /// Keeps the candidates whose byte in `memory` compares to the last
+    /// snapshot as asked, then takes `memory` as the new snapshot. Returns
+    /// how many are left.
+    pub fn filter(&mut self, memory: &[Byte], comparison: Comparison) -> usize {
+        let snapshot = &self.snapshot;
+        self.candidates.retain(|&addr| {
+            memory.get(addr).is_some_and(|&new| comparison.matches(snapshot[addr], new))
+        });
+        self.snapshot = memory.to_vec();
+        self.candidates.len()
+    }
+
+    pub fn candidates(&self) -> &[usize] {
+        &self.candidates
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    /// Writes every frozen value, done by `tick` once a frame however the
+    /// instructions are run.
+    pub fn apply_cheats(&mut self) {
+        for i in 0..self.cheats.len() {
+            let Cheat { target, value } = self.cheats[i];
+            match target {
+                Target::Memory(addr) if self.memory.read(addr) != value => self.write_byte(addr, value),
+                Target::Memory(_) => {}
+                Target::Register(x) => self.v[x as usize & 0xF] = value,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::RomConfig;
+    use crate::rom::Rom;
+
+    #[test]
+    fn test_parse() {
+        let cheats = parse("# lives\n2F0:09 + v3:0x05\n\n").unwrap();
+        assert_eq!(
+            cheats,
+            [
+                Cheat { target: Target::Memory(0x2F0), value: 0x09 },
+                Cheat { target: Target::Register(0x3), value: 0x05 },
+            ],
+            "Codes not parsed"
+        );
+        assert_eq!(to_text(&cheats), "2F0:09\nV3:05\n", "Codes not written back");
+        assert_eq!(parse(&to_text(&cheats)).unwrap(), cheats, "Cheat file does not round-trip");
+
+        assert_eq!(parse("2F0"), Err(CheatError::Syntax("2F0".to_string())), "Missing value accepted");
+        assert!(parse("2F0:100").is_err(), "Value over a byte accepted");
+        assert!(parse("VG:01").is_err(), "Unknown register accepted");
+    }
+
+    #[test]
+    fn test_search() {
+        let mut memory = vec![0; 16];
+        memory[3] = 5;
+        memory[7] = 5;
+        let mut search = Search::new(&memory);
+        assert_eq!(search.filter(&memory, Comparison::Equal(5)), 2, "Equal search wrong");
+
+        memory[3] = 4;
+        assert_eq!(search.filter(&memory, Comparison::Decreased(1)), 1, "Decrease not found");
+        assert_eq!(search.candidates(), [3], "Wrong address left");
+
+        memory[3] = 4;
+        assert_eq!(search.filter(&memory, Comparison::Unchanged), 1, "Unchanged byte dropped");
+        memory[3] = 6;
+        assert_eq!(search.filter(&memory, Comparison::Increased(1)), 0, "Wrong increase kept");
+    }
+
+    #[test]
+    fn test_freeze() {
+        // LD V3, 1; LD I, 0x300; LD [I], V0..V0; JP 0x206
+        let rom = Rom::new(&[0x63, 0x01, 0xA3, 0x00, 0xF0, 0x55, 0x12, 0x06]);
+        let mut cpu = CPU::default();
+        cpu.load_rom(&rom, &RomConfig::default()).unwrap();
+        cpu.cheats = parse("V3:42 + 300:07").unwrap();
+        cpu.run_frame();
+        assert_eq!(cpu.v[0x3], 0x42, "Register not frozen");
+        assert_eq!(cpu.memory.read(0x300), 0x07, "Memory not frozen");
+
+        // Single steps reach the frame boundary as well.
+        let mut cpu = CPU::default();
+        cpu.load_rom(&rom, &RomConfig::default()).unwrap();
+        cpu.cheats = parse("V3:42").unwrap();
+        for _ in 0..cpu.cycles_per_frame {
+            cpu.execute();
+        }
+        assert_eq!(cpu.v[0x3], 0x42, "Register not frozen when stepping");
+    }
+}
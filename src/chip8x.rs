@@ -0,0 +1,248 @@
+//! CHIP-8X, the interpreter for the RCA VP-590 colour board and the VP-580
+//! second keypad. The board keeps a background colour and a foreground
+//! colour for every 8 pixel wide column of each row.
+
+use std::fmt;
+use crate::bus::Bus;
+use crate::cpu::{Word, CPU};
+use crate::decode::{decode as decode_chip8, Instruction};
+
+/// The platform id in the ROM database.
+pub const PLATFORM: &str = "chip8x";
+
+/// Where programs start, above the interpreter's larger work area.
+pub const START: Word = 0x300;
+
+pub const CHG_BG: Word = 0x02A0;
+pub const SKP2_VX: Word = 0xE0F2;
+pub const SKNP2_VX: Word = 0xE0F5;
+
+/// The board's eight colours.
+pub const COLORS: [[u8; 3]; 8] = [
+    [0x00, 0x00, 0x00], // black
+    [0xFF, 0x00, 0x00], // red
+    [0x00, 0x00, 0xFF], // blue
+    [0xFF, 0x00, 0xFF], // violet
+    [0x00, 0xFF, 0x00], // green
+    [0xFF, 0xFF, 0x00], // yellow
+    [0x00, 0xFF, 0xFF], // aqua
+    [0xFF, 0xFF, 0xFF], // white
+];
+
+/// 02A0 steps through these background colours.
+const BACKGROUNDS: [usize; 4] = [2, 0, 4, 1];
+
+const COLUMNS: usize = 8;
+const ROWS: usize = 32;
+/// BXY0 addresses rows in zones of this many.
+const ZONE_ROWS: usize = 4;
+
+pub(crate) const STATE_SIZE: usize = 1 + COLUMNS * ROWS;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ColorBoard {
+    /// Index into the background cycle.
+    background: usize,
+    /// Foreground colour for each row and 8 pixel column.
+    zones: [[u8; COLUMNS]; ROWS],
+}
+
+impl Default for ColorBoard {
+    /// Blue background, red foreground, as after power on.
+    fn default() -> Self {
+        ColorBoard { background: 0, zones: [[1; COLUMNS]; ROWS] }
+    }
+}
+
+impl ColorBoard {
+    /// 02A0.
+    pub fn cycle_background(&mut self) {
+        self.background = (self.background + 1) % BACKGROUNDS.len();
+    }
+
+    pub fn background(&self) -> [u8; 3] {
+        COLORS[BACKGROUNDS[self.background]]
+    }
+
+    pub fn foreground(&self, x: usize, y: usize) -> [u8; 3] {
+        COLORS[self.zones[y % ROWS][(x / 8) % COLUMNS] as usize]
+    }
+
+    /// The index in `COLORS` of the pixel at (`x`, `y`) when it is `on` or
+    /// off.
+    pub fn pixel_index(&self, x: usize, y: usize, on: bool) -> u8 {
+        if on { self.zones[y % ROWS][(x / 8) % COLUMNS] } else { BACKGROUNDS[self.background] as u8 }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize, on: bool) -> [u8; 3] {
+        COLORS[self.pixel_index(x, y, on) as usize]
+    }
+
+    /// Background index followed by the zones row by row, for save states.
+    pub(crate) fn to_bytes(&self) -> [u8; STATE_SIZE] {
+        let mut out = [0; STATE_SIZE];
+        out[0] = self.background as u8;
+        out[1..].copy_from_slice(self.zones.as_flattened());
+        out
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8; STATE_SIZE]) -> Self {
+        let mut board = ColorBoard { background: bytes[0] as usize % BACKGROUNDS.len(), ..ColorBoard::default() };
+        for (zone, &color) in board.zones.as_flattened_mut().iter_mut().zip(&bytes[1..]) {
+            *zone = color & 0x7;
+        }
+        board
+    }
+
+    /// BXY0: VX holds the first and last column (low and high nibble), VX+1
+    /// the first and last zone of 4 rows, VY the colour.
+    pub fn fill_zones(&mut self, columns: u8, zones: u8, color: u8) {
+        let (left, right) = ((columns & 0xF) as usize, (columns >> 4) as usize);
+        let (top, bottom) = ((zones & 0xF) as usize, (zones >> 4) as usize);
+        for zone in top..=bottom {
+            for y in zone * ZONE_ROWS..(zone + 1) * ZONE_ROWS {
+                for x in left..=right {
+                    self.zones[y % ROWS][x % COLUMNS] = color & 0x7;
+                }
+            }
+        }
+    }
+
+    /// BXYN: colours `rows` rows from the pixel row in VX+1 in the column
+    /// holding the pixel column in VX, VY is the colour.
+    pub fn fill_rows(&mut self, x: u8, y: u8, rows: u8, color: u8) {
+        for row in y as usize..y as usize + rows as usize {
+            self.zones[row % ROWS][(x as usize / 8) % COLUMNS] = color & 0x7;
+        }
+    }
+}
+
+/// The instructions CHIP-8X adds to CHIP-8.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    /// 02A0: step the background colour.
+    ChgBg,
+    /// BXY0: colour zones from VX and VX+1 in VY.
+    ColorZones(u8, u8),
+    /// BXYN: colour N rows from VX and VX+1 in VY.
+    ColorRows(u8, u8, u8),
+    /// EXF2: skip if the key in VX is down on the second keypad.
+    SkpVx2(u8),
+    /// EXF5: skip if the key in VX is up on the second keypad.
+    SknpVx2(u8),
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Op::ChgBg => write!(f, "CHGBG"),
+            Op::ColorZones(x, y) => write!(f, "COLOR V{:X}, V{:X}", x, y),
+            Op::ColorRows(x, y, n) => write!(f, "COLOR V{:X}, V{:X}, {}", x, y, n),
+            Op::SkpVx2(x) => write!(f, "SKP2 V{:X}", x),
+            Op::SknpVx2(x) => write!(f, "SKNP2 V{:X}", x),
+        }
+    }
+}
+
+/// CHIP-8 with the colour and second keypad opcodes, BXYN replacing BNNN.
+pub fn decode(ins: Word) -> Instruction {
+    let x = ((ins >> 8) & 0xF) as u8;
+    let y = ((ins >> 4) & 0xF) as u8;
+    let n = (ins & 0xF) as u8;
+    let op = match ins {
+        CHG_BG => Op::ChgBg,
+        _ if ins & 0xF00F == 0xB000 => Op::ColorZones(x, y),
+        _ if ins & 0xF000 == 0xB000 => Op::ColorRows(x, y, n),
+        _ if ins & 0xF0FF == SKP2_VX => Op::SkpVx2(x),
+        _ if ins & 0xF0FF == SKNP2_VX => Op::SknpVx2(x),
+        _ => return decode_chip8(ins),
+    };
+    Instruction::Chip8X(op)
+}
+
+impl<B: Bus> CPU<B> {
+    /// Runs `ins` if it is a CHIP-8X instruction, see `Platform::exec`.
+    pub(crate) fn exec_chip8x(&mut self, ins: Instruction) -> bool {
+        let Instruction::Chip8X(op) = ins else {
+            return false;
+        };
+        match op {
+            Op::SkpVx2(vx) if self.v[vx as usize] <= 0xF && self.keyboard2[self.v[vx as usize] as usize] == 1 => {
+                self.pc += 2;
+            }
+            Op::SknpVx2(vx) if self.v[vx as usize] <= 0xF && self.keyboard2[self.v[vx as usize] as usize] == 0 => {
+                self.pc += 2;
+            }
+            Op::SkpVx2(_) | Op::SknpVx2(_) => {}
+            Op::ChgBg | Op::ColorZones(..) | Op::ColorRows(..) => self.exec_color(op),
+        }
+        true
+    }
+
+    /// The colour opcodes. Without a colour board they do nothing.
+    fn exec_color(&mut self, op: Op) {
+        let Some(color) = self.color.as_mut() else {
+            return;
+        };
+        match op {
+            Op::ChgBg => color.cycle_background(),
+            Op::ColorZones(x, y) => {
+                color.fill_zones(self.v[x as usize], self.v[(x as usize + 1) & 0xF], self.v[y as usize]);
+            }
+            Op::ColorRows(x, y, n) => {
+                color.fill_rows(self.v[x as usize], self.v[(x as usize + 1) & 0xF], n, self.v[y as usize]);
+            }
+            Op::SkpVx2(_) | Op::SknpVx2(_) => return,
+        }
+        self.display.mark_all();
+        self.redraw = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::Platform;
+
+    #[test]
+    fn test_color_board() {
+        let mut board = ColorBoard::default();
+        assert_eq!(board.pixel(0, 0, false), COLORS[2], "Background does not start blue");
+        board.cycle_background();
+        assert_eq!(board.background(), COLORS[0], "Background did not cycle");
+
+        board.fill_zones(0x21, 0x10, 6);
+        assert_eq!(board.foreground(8, 7), COLORS[6], "Zone not coloured");
+        assert_eq!(board.foreground(23, 0), COLORS[6], "Last column not coloured");
+        assert_eq!(board.foreground(24, 0), COLORS[1], "Column past the end coloured");
+        assert_eq!(board.foreground(8, 8), COLORS[1], "Zone past the end coloured");
+
+        board.fill_rows(40, 3, 2, 5);
+        assert_eq!((board.foreground(47, 3), board.foreground(40, 4)), (COLORS[5], COLORS[5]), "Rows not coloured");
+        assert_eq!(board.foreground(40, 5), COLORS[1], "Row past the end coloured");
+    }
+
+    #[test]
+    fn test_chip8x_opcodes() {
+        let mut cpu = CPU::default();
+        cpu.set_platform(Platform::Chip8X);
+
+        cpu.exec(decode(0x02A0));
+        assert_eq!(cpu.color.as_ref().unwrap().background(), COLORS[0], "02A0 did not cycle the background");
+
+        // B130: column 0 from V1, zone 4 from V2, colour from V3.
+        cpu.v[0x1] = 0x00;
+        cpu.v[0x2] = 0x44;
+        cpu.v[0x3] = 4;
+        cpu.pc = 0x300;
+        cpu.exec(decode(0xB130));
+        assert_eq!(cpu.pc, 0x300, "BXY0 jumped");
+        assert_eq!(cpu.color.as_ref().unwrap().foreground(0, 16), COLORS[4], "BXY0 did not colour");
+
+        cpu.keyboard2[0x4] = 1;
+        cpu.exec(decode(0xE3F2));
+        assert_eq!(cpu.pc, 0x302, "EXF2 did not skip for a key on the second keypad");
+        cpu.exec(decode(0xE3F5));
+        assert_eq!(cpu.pc, 0x302, "EXF5 skipped for a pressed key");
+    }
+}
@@ -0,0 +1,77 @@
+/// Turns wall-clock time into whole 60 Hz frames to emulate.
+#[derive(Clone, Debug)]
+pub struct Clock {
+    /// The most frames run for one call, so a tab that was in the background
+    /// does not fast-forward through everything it missed.
+    pub max_catch_up: u32,
+    pending_ms: f64,
+    /// Instructions owed to `cycles_due`, kept apart from the frames.
+    pending_cycles: f64,
+}
+
+impl Clock {
+    pub const FRAME_MS: f64 = 1000.0 / 60.0;
+
+    pub fn new() -> Self {
+        Self { max_catch_up: 6, pending_ms: 0.0, pending_cycles: 0.0 }
+    }
+
+    /// Adds `elapsed_ms` and returns how many frames are due.
+    pub fn frames_due(&mut self, elapsed_ms: f64) -> u32 {
+        self.pending_ms += elapsed_ms.max(0.0);
+        let frames = (self.pending_ms / Clock::FRAME_MS) as u32;
+        if frames > self.max_catch_up {
+            self.pending_ms = 0.0;
+            return self.max_catch_up;
+        }
+        self.pending_ms -= frames as f64 * Clock::FRAME_MS;
+        frames
+    }
+
+    /// Adds `elapsed_ms` and returns how many instructions are due at
+    /// `cycles_per_frame` a frame, at most `max_catch_up` frames' worth.
+    pub fn cycles_due(&mut self, elapsed_ms: f64, cycles_per_frame: u32) -> u32 {
+        self.pending_cycles += elapsed_ms.max(0.0) * (cycles_per_frame * 60) as f64 / 1000.0;
+        let max = self.max_catch_up * cycles_per_frame;
+        if self.pending_cycles > max as f64 {
+            self.pending_cycles = 0.0;
+            return max;
+        }
+        let cycles = self.pending_cycles as u32;
+        self.pending_cycles -= cycles as f64;
+        cycles
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames_due() {
+        let mut clock = Clock::new();
+
+        assert_eq!(clock.frames_due(10.0), 0, "Frame ran before it was due");
+        assert_eq!(clock.frames_due(10.0), 1, "Leftover time was dropped");
+        assert_eq!(clock.frames_due(1000.0 / 60.0 * 2.0), 2, "Wrong number of frames");
+        assert_eq!(clock.frames_due(10_000.0), 6, "Catch-up not capped");
+        assert_eq!(clock.frames_due(10.0), 0, "Time past the cap was kept");
+    }
+
+    #[test]
+    fn test_cycles_due() {
+        let mut clock = Clock::new();
+
+        assert_eq!(clock.cycles_due(25.0, 10), 15, "Wrong number of instructions");
+        assert_eq!(clock.cycles_due(1.0, 10), 0, "Instruction ran before it was due");
+        assert_eq!(clock.cycles_due(1.0, 10), 1, "Leftover time was dropped");
+        assert_eq!(clock.cycles_due(10_000.0, 10), 60, "Catch-up not capped");
+        assert_eq!(clock.cycles_due(0.0, 10), 0, "Time past the cap was kept");
+    }
+}
@@ -1,26 +1,72 @@
 #![allow(unused)]
-use rand::rngs::SmallRng;
-use rand::SeedableRng;
-use rand::Rng;
+#![allow(clippy::needless_return)]
+use crate::audio::Audio;
+use crate::bus::{Bus, Ram4K};
+use crate::decode::{decode, Instruction};
+use crate::block::{Backend, BlockCache};
+use crate::cheats::Cheat;
+use crate::flags;
+use crate::chip8x::ColorBoard;
+use crate::display::Framebuffer;
+use crate::platform::Platform;
+use crate::megachip::MegaChip;
+use crate::random::{RandomSource, SeededRandom};
+use crate::sprites::SpriteDraw;
+use crate::quirks::{MemoryQuirk, Quirks};
 pub type Byte = u8;
 pub type Word = u16;
 
-pub struct CPU
+#[allow(clippy::upper_case_acronyms)]
+pub struct CPU<B: Bus = Ram4K>
 {
-    pub memory: [Byte; CPU::MEM_SIZE],
+    /// Writing here directly bypasses the decode cache, see `flush_decoded`.
+    pub memory: B,
     pub v: [Byte; 16],
     pub stack: [Word; 16],
-    pub display: [[bool; CPU::DISP_X]; CPU::DISP_Y], 
-    pub i: Word,
+    pub display: Framebuffer,
+    /// 24 bits wide for MegaChip, 16 bits are enough elsewhere.
+    pub i: u32,
     pub pc: Word,
     pub sp: Byte,
     pub dt: Byte,
     pub st: Byte,
     pub ticks: u32,
+    /// Instructions executed per 60 Hz frame.
+    pub cycles_per_frame: u32,
+    pub quirks: Quirks,
+    /// Set after a draw with the display wait quirk, instructions are
+    /// skipped until the next frame starts.
+    pub vblank_wait: bool,
     pub redraw: bool,
     pub keyboard: [u8; 16],
-    pub rng: SmallRng
-
+    /// The CHIP-8X second keypad, read by EXF2 and EXF5.
+    pub keyboard2: [u8; 16],
+    /// The CHIP-8X colour board, present on that platform.
+    pub color: Option<ColorBoard>,
+    /// The variant being emulated, see `set_platform`.
+    pub(crate) platform: Platform,
+    /// MegaChip state. While present, 0011 turns mega mode on, see
+    /// `megachip`.
+    pub mega: Option<MegaChip>,
+    /// What plays while ST is non-zero.
+    pub audio: Audio,
+    pub rng: Box<dyn RandomSource>,
+    /// Reuse decoded instructions instead of fetching and decoding them again,
+    /// unless the bus is `observed`.
+    pub predecode: bool,
+    decoded: Vec<Option<Instruction>>,
+    /// Execution backend used by `run`.
+    pub backend: Backend,
+    pub(crate) blocks: BlockCache,
+    /// Set to `Some` to record every sprite drawn.
+    pub sprite_log: Option<Vec<SpriteDraw>>,
+    /// Values written again after every frame, see `cheats`.
+    pub cheats: Vec<Cheat>,
+    /// The RPL user flags, which survive resets, see `flags`.
+    pub flags: [Byte; flags::COUNT],
+    pub(crate) flags_changed: bool,
+    /// Addresses from `watch_pc`, with whether PC reached them this frame.
+    pub(crate) pc_watch: Vec<(Word, bool)>,
 }
 
 impl CPU {
@@ -67,6 +113,10 @@ impl CPU {
     pub const LD_B_VX: Word = 0xF033;
     pub const LD_STO_I_VX: Word = 0xF055;
     pub const LD_STO_VX_I: Word = 0xF065;
+    pub const LD_AUDIO_I: Word = 0xF002;
+    pub const LD_PITCH_VX: Word = 0xF03A;
+    pub const LD_R_VX: Word = 0xF075;
+    pub const LD_VX_R: Word = 0xF085;
     pub const CHARACTERS: [Byte; 128] = [
         0xF0, 0x90, 0x90, 0x90, 0xF0, 0x00, 0x00, 0x00, // 0
         0x20, 0x60, 0x20, 0x20, 0x70, 0x00, 0x00, 0x00, // 1
@@ -86,21 +136,47 @@ impl CPU {
         0xF0, 0x80, 0xF0, 0x80, 0x80, 0x00, 0x00, 0x00, // F
     ];
 
+    #[allow(clippy::should_implement_trait)]
     pub fn default() -> Self {
+        CPU::with_bus(Ram4K::new())
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    pub fn with_bus(memory: B) -> Self {
+        // PC is 16 bits wide, so there is never code to cache past 64K.
+        let size = memory.size().min(0x10000);
         Self {
-            memory: [0; CPU::MEM_SIZE],
+            memory,
             v: [0; CPU::NREG],
             stack: [0; CPU::NREG],
-            display: [[false; CPU::DISP_X]; CPU::DISP_Y],
+            display: Framebuffer::new(CPU::DISP_Y),
             i: 0,
             pc: 0x200,
             sp: 0,
             dt: 0,
             st: 0,
             ticks: 0,
+            cycles_per_frame: 9,
+            quirks: Quirks::default(),
+            vblank_wait: false,
             redraw: false,
             keyboard: [0; 16],
-            rng: SmallRng::seed_from_u64(42)
+            keyboard2: [0; 16],
+            color: None,
+            platform: Platform::Chip8,
+            mega: None,
+            audio: Audio::default(),
+            rng: Box::new(SeededRandom::new(42)),
+            predecode: true,
+            decoded: vec![None; size],
+            backend: Backend::Interpreter,
+            blocks: BlockCache::new(size),
+            sprite_log: None,
+            cheats: Vec::new(),
+            flags: [0; flags::COUNT],
+            flags_changed: false,
+            pc_watch: Vec::new(),
         }
     }
 
@@ -114,57 +190,146 @@ impl CPU {
         self.pc = 0x200;
         self.sp = 0x0;
         self.i = 0x0;
+        self.audio = Audio::default();
+        if let Some(color) = self.color.as_mut() {
+            *color = ColorBoard::default();
+        }
+        if let Some(mega) = self.mega.as_mut() {
+            *mega = MegaChip::default();
+        }
+        self.flush_decoded();
+        self.pc_watch.iter_mut().for_each(|(_, hit)| *hit = false);
     }
 
     pub fn load_program(&mut self) {
         let program = include_bytes!("roms/5-quirks.ch8");
         let start_address = 0x200;
         let end_address = start_address + program.len();
-        if end_address <= self.memory.len() {
-            self.memory[start_address..end_address].copy_from_slice(program);
+        if end_address <= self.memory.size() {
+            self.memory.load(start_address, program);
+            self.flush_decoded();
         } else {
             panic!("Program is too large to fit into memory!")
         }
     }
 
     pub fn fetch(&mut self) -> Word {
-        let ins1: Byte = self.memory[self.pc as usize];
+        let ins1: Byte = self.memory.read(self.pc as usize);
         self.pc += 1;
-        let ins2: Byte = self.memory[self.pc as usize];
+        let ins2: Byte = self.memory.read(self.pc as usize);
         self.pc += 1;
         let data: Word = ((ins1 as Word) << 8) | (ins2 as Word);
         return data;
     }
 
+    /// Sets the keypad from a bitmask, bit N for key N.
+    pub fn set_keys(&mut self, mask: u16) {
+        for (key, state) in self.keyboard.iter_mut().enumerate() {
+            *state = ((mask >> key) & 1) as u8;
+        }
+    }
+
+    /// Sets the CHIP-8X second keypad from a bitmask, as `set_keys`.
+    pub fn set_keys2(&mut self, mask: u16) {
+        for (key, state) in self.keyboard2.iter_mut().enumerate() {
+            *state = ((mask >> key) & 1) as u8;
+        }
+    }
+
+    /// The opcode at PC, read without going through the bus hooks.
+    pub fn opcode(&self) -> Word {
+        let bytes = self.memory.bytes();
+        let pc = self.pc as usize;
+        ((bytes[pc % bytes.len()] as Word) << 8) | bytes[(pc + 1) % bytes.len()] as Word
+    }
+
     pub fn rand_byte(&mut self) -> Byte {
-        return self.rng.gen_range(0..=255);
+        return self.rng.next_byte(self.memory.bytes());
+    }
+
+    /// Restarts the random source from `seed`.
+    pub fn seed(&mut self, seed: u64) {
+        self.rng = Box::new(SeededRandom::new(seed));
     }
 
     pub fn load_sprites(&mut self) {
-        for (index, &val) in CPU::CHARACTERS.iter().enumerate() {
-            self.memory[index] = val;
-        }
+        self.memory.load(0, self.platform.font());
+        self.flush_decoded();
     }
 
     pub fn reset_display(&mut self) {
-        for row in self.display.iter_mut() {
-            for cell in row.iter_mut() {
-                *cell = false; // Reset each cell to false
+        self.display.clear();
+    }
+
+    pub fn get_display(&self) -> &Framebuffer {
+        return &self.display;
+    }
+
+    pub fn execute(&mut self) {
+        self.latch_pc(self.pc);
+        if self.vblank_wait {
+            self.tick();
+            return;
+        }
+        let pc = self.pc as usize;
+        let cached = self.predecode && !self.memory.observed();
+        let ins = match self.decoded.get(pc) {
+            Some(Some(ins)) if cached => {
+                self.pc += 2;
+                *ins
+            }
+            _ => {
+                let ins = self.platform.decode(self.fetch());
+                if cached {
+                    if let Some(slot) = self.decoded.get_mut(pc) {
+                        *slot = Some(ins);
+                    }
+                }
+                ins
+            }
+        };
+        self.tick();
+        self.exec(ins);
+    }
+
+    /// Runs `cycles` instructions on the selected backend.
+    pub fn run(&mut self, cycles: u32) -> u32 {
+        match self.backend {
+            Backend::Blocks if !self.memory.observed() => self.run_blocks(cycles),
+            // Watched buses see every fetch on the interpreter.
+            _ => {
+                for _ in 0..cycles {
+                    self.execute();
+                }
+                cycles
             }
         }
     }
 
-    pub fn get_display(&self) -> [[bool; CPU::DISP_X]; CPU::DISP_Y] {
-        return self.display;
+    /// Sets the instruction rate, rounded to a whole number of instructions
+    /// per 60 Hz frame.
+    pub fn set_clock_hz(&mut self, hz: u32) {
+        self.cycles_per_frame = ((hz + 30) / 60).max(1);
     }
 
-    pub fn execute(&mut self) {
-        let ins: Word = self.fetch();
-        let vx: Word = (ins >> 8) & 0x000F;
-        let vy: Word = (ins >> 4) & 0x000F;
+    pub fn clock_hz(&self) -> u32 {
+        self.cycles_per_frame * 60
+    }
+
+    /// Runs the instructions left until the next frame starts.
+    pub fn run_frame(&mut self) -> u32 {
+        let cycles = self.cycles_per_frame - self.ticks % self.cycles_per_frame;
+        self.run(cycles)
+    }
+
+    /// Counts one instruction, a new frame starts every `cycles_per_frame`
+    /// with the timers, the random source and the cheats.
+    #[inline]
+    pub(crate) fn tick(&mut self) {
         self.ticks += 1;
 
-        if self.ticks % 9 == 0 {
+        if self.ticks.is_multiple_of(self.cycles_per_frame) {
+            self.vblank_wait = false;
             if self.dt > 0
             {
                 self.dt -= 1;
@@ -173,15 +338,55 @@ impl CPU {
             {
                 self.st -= 1;
             }
+            self.rng.frame();
+            self.apply_cheats();
+        }
+    }
+
+    /// Writes a byte on behalf of the program and drops the decoded
+    /// instructions and blocks overlapping it.
+    pub fn write_byte(&mut self, addr: usize, value: Byte) {
+        self.memory.write(addr, value);
+        self.invalidate_decoded(addr);
+        self.blocks.invalidate(addr % self.memory.size());
+    }
+
+    /// Moves I past FX55/FX65's registers as the memory quirk asks.
+    fn advance_i(&mut self, vx: u8) {
+        self.i = match self.quirks.memory {
+            MemoryQuirk::IncrementByXPlusOne => self.i.wrapping_add(vx as u32 + 1),
+            MemoryQuirk::IncrementByX => self.i.wrapping_add(vx as u32),
+            MemoryQuirk::Unchanged => self.i,
+        };
+    }
+
+    fn invalidate_decoded(&mut self, addr: usize) {
+        let size = self.decoded.len();
+        let addr = addr % size;
+        self.decoded[addr] = None;
+        self.decoded[(addr + size - 1) % size] = None;
+    }
+
+    /// Forgets all decoded instructions and translated blocks. Needed after
+    /// writing to `memory` directly while a program is running.
+    pub fn flush_decoded(&mut self) {
+        self.decoded.iter_mut().for_each(|slot| *slot = None);
+        self.blocks.clear();
+    }
+
+    #[inline]
+    pub fn exec(&mut self, ins: Instruction) {
+        if self.platform.exec(self, ins) {
+            return;
         }
         match ins {
-            CPU::SYS_ADDR => { return }
-            CPU::CLS => {
+            Instruction::Sys(_) => { return }
+            Instruction::Cls => {
                 self.redraw = true;
                 self.reset_display();
                 return;
             }
-            CPU::RET => {
+            Instruction::Ret => {
                 if self.sp == 0 {
                     panic!("Stack Underflow at PC {:X}", self.pc);
                 }
@@ -189,223 +394,210 @@ impl CPU {
                 self.pc = self.stack[self.sp as usize];
                 return;
             }
-            _ => {}
-        }
-
-        match ins & 0xF000 {
-            CPU::JP_ADDR => {
-                self.pc = ins & 0xFFF;
+            Instruction::Jp(addr) => {
+                self.pc = addr;
                 return;
             }
-            CPU::CALL_ADDR => {
+            Instruction::Call(addr) => {
                 if self.pc == 15 {
                     panic!("Stack overflow at PC {:X}", self.pc);
                 }
                 self.stack[self.sp as usize] = self.pc;
                 self.sp += 1;
-                self.pc = (ins & 0x0FFF);
+                self.pc = addr;
                 return;
             }
-            CPU::SE_VX => {
-                if self.v[vx as usize] == (ins & 0x00FF) as Byte {
+            Instruction::SeVx(vx, value) => {
+                if self.v[vx as usize] == value {
                     self.pc += 2;
                 }
                 return;
             }
-            CPU::SNE_VX => {
-                if self.v[vx as usize] != (ins & 0x00FF) as Byte {
+            Instruction::SneVx(vx, value) => {
+                if self.v[vx as usize] != value {
                     self.pc += 2;
                 }
                 return;
             }
-            CPU::SE_VX_VY => {
-                if self.v[((ins >> 8) & 0x000F) as usize] == self.v[vy as usize] {
+            Instruction::SeVxVy(vx, vy) => {
+                if self.v[vx as usize] == self.v[vy as usize] {
                     self.pc += 2;
                 }
                 return;
             }
-            CPU::LD_VX => {
-                self.v[vx as usize] = (ins & 0x00ff) as Byte;
+            Instruction::LdVx(vx, value) => {
+                self.v[vx as usize] = value;
                 return;
             }
-            CPU::ADD_VX => {
-                let value = (ins & 0x00FF) as u8; // Extract the immediate value (lower 8 bits)
+            Instruction::AddVx(vx, value) => {
                 self.v[vx as usize] = self.v[vx as usize].wrapping_add(value);
                 return;
             }
-            CPU::COMP_INS => {
-                match ins & 0xF00F {
-                    CPU::LD_VX_VY => {
-                        self.v[vx as usize] = self.v[vy as usize];
-                        return;
-                    }
-                    CPU::OR_VX_VY => {
-                        self.v[vx as usize] = self.v[vx as usize] | self.v[vy as usize];
-                        self.v[0xf] = 0;
-                        return;
-                    }
-                    CPU::AND_VX_VY => {
-                        self.v[vx as usize] = self.v[vx as usize] & self.v[vy as usize];
-                        self.v[0xf] = 0;
-                        return;
-                    }
-                    CPU::XOR_VX_VY => {
-                        self.v[vx as usize] = self.v[vx as usize] ^ self.v[vy as usize];
-                        self.v[0xf] = 0;
-                        return;
-                    }
-                    CPU::ADD_VX_VY => {
-                        let (sum, carry) = self.v[vx as usize].overflowing_add(self.v[vy as usize]);
-                        self.v[vx as usize] = sum;
-                        self.v[0xf] = if carry {1} else {0};
-                        return;
-                    }
-                    CPU::SUB_VX_VY => {
-                        let (diff, carry) = self.v[vx as usize].overflowing_sub(self.v[vy as usize]);
-                        self.v[vx as usize] = diff;
-                        self.v[0xf] = if carry {0} else {1};
-                        return;
-                    }
-                    CPU::SHR_VX => {
-                        let lsb = self.v[vx as usize] & 0x01;
-                        self.v[0xF] = lsb;
-                        self.v[vx as usize] >>= 1;
-                        return;
-                    }
-                    CPU::SUBN_VX_VY => {
-                        self.v[0xF] = if self.v[vy as usize] >= self.v[vx as usize] { 1 } else { 0 };
-                        self.v[vx as usize] = self.v[vy as usize].wrapping_sub(self.v[vx as usize]);
-                    }
-                    CPU::SHL_VX => {
-                        let msb = (self.v[vx as usize] & 0x80) >> 7; // Extract the MSB (bit 7)
-                        self.v[0xF] = msb;                          // Set VF to the MSB (carry flag)
-                        self.v[vx as usize] <<= 1;                  // Perform the left shift
-                        return;
-                    }
-                    _ => {
-                    }
+            Instruction::LdVxVy(vx, vy) => {
+                self.v[vx as usize] = self.v[vy as usize];
+                return;
+            }
+            Instruction::OrVxVy(vx, vy) => {
+                self.v[vx as usize] |= self.v[vy as usize];
+                if self.quirks.vf_reset {
+                    self.v[0xf] = 0;
                 }
+                return;
             }
-            CPU::SNE_VX_VY => {
+            Instruction::AndVxVy(vx, vy) => {
+                self.v[vx as usize] &= self.v[vy as usize];
+                if self.quirks.vf_reset {
+                    self.v[0xf] = 0;
+                }
+                return;
+            }
+            Instruction::XorVxVy(vx, vy) => {
+                self.v[vx as usize] ^= self.v[vy as usize];
+                if self.quirks.vf_reset {
+                    self.v[0xf] = 0;
+                }
+                return;
+            }
+            Instruction::AddVxVy(vx, vy) => {
+                let (sum, carry) = self.v[vx as usize].overflowing_add(self.v[vy as usize]);
+                self.v[vx as usize] = sum;
+                self.v[0xf] = if carry {1} else {0};
+                return;
+            }
+            Instruction::SubVxVy(vx, vy) => {
+                let (diff, carry) = self.v[vx as usize].overflowing_sub(self.v[vy as usize]);
+                self.v[vx as usize] = diff;
+                self.v[0xf] = if carry {0} else {1};
+                return;
+            }
+            Instruction::ShrVx(vx, vy) => {
+                let src = if self.quirks.shift { self.v[vx as usize] } else { self.v[vy as usize] };
+                self.v[vx as usize] = src >> 1;
+                self.v[0xF] = src & 0x01;
+                return;
+            }
+            Instruction::SubnVxVy(vx, vy) => {
+                self.v[0xF] = if self.v[vy as usize] >= self.v[vx as usize] { 1 } else { 0 };
+                self.v[vx as usize] = self.v[vy as usize].wrapping_sub(self.v[vx as usize]);
+            }
+            Instruction::ShlVx(vx, vy) => {
+                let src = if self.quirks.shift { self.v[vx as usize] } else { self.v[vy as usize] };
+                self.v[vx as usize] = src << 1;
+                self.v[0xF] = (src & 0x80) >> 7; // VF is written last so it wins when X is F
+                return;
+            }
+            Instruction::SneVxVy(vx, vy) => {
                 if self.v[vx as usize] != self.v[vy as usize] {
                     self.pc += 2; // Skip the next instruction
                 }
                 return;
             }
-            CPU::LD_I => {
-                self.i = ins & 0x0FFF; // Load lower 12 bits of instruction into I
+            Instruction::LdI(addr) => {
+                self.i = addr as u32;
                 return;
             }
-            CPU::JP_V0 => {
-                self.pc = (ins & 0x0FFF) + self.v[0x0] as Word;
+            Instruction::JpV0(addr) => {
+                let reg = if self.quirks.jump { (addr >> 8) as usize & 0xF } else { 0 };
+                self.pc = addr + self.v[reg] as Word;
                 return;
             }
-            CPU::RND_VX => {
+            Instruction::RndVx(vx, mask) => {
                 let random_byte: Byte = self.rand_byte();
-                self.v[vx as usize] = random_byte & (ins & 0x00FF) as Byte;
+                self.v[vx as usize] = random_byte & mask;
                 return;
             }
-            CPU::DRW_VX_VY => {
+            Instruction::DrwVxVy(vx, vy, n) => {
                 self.redraw = true; // Mark the display for redraw
             
-                let xcoord = self.v[vx as usize] as usize % CPU::DISP_X;
-                let ycoord = self.v[vy as usize] as usize % CPU::DISP_Y;
-                self.v[0xF] = 0; // Reset collision flag
-            
-                let n = (ins & 0x000F) as usize; // Number of rows in the sprite
-            
-                for row in 0..n {
-                    let pixel_byte = self.memory[self.i as usize + row]; // Fetch sprite row
-            
-                    for x in (0..8).rev() {
-                        let pixel = (pixel_byte & (1 << x)) != 0; // Check if bit is set
-            
-                        let screen_x = (xcoord + (7 - x)) % CPU::DISP_X;
-                        let screen_y = (ycoord + row) % CPU::DISP_Y;
-            
-                        // Check for collision
-                        if pixel && self.display[screen_y][screen_x] {
-                            self.v[0xF] = 1; // Set collision flag
-                        }
-            
-                        // XOR the pixel onto the display
-                        self.display[screen_y][screen_x] ^= pixel;
-                    }
+                let xcoord = self.v[vx as usize] as usize % self.display.width();
+                let ycoord = self.v[vy as usize] as usize % self.display.height();
+
+                let mut sprite = [0; 15];
+                for (row, byte) in sprite.iter_mut().enumerate().take(n as usize) {
+                    *byte = self.memory.read(self.i as usize + row); // Fetch sprite row
                 }
-            
+                if let Some(log) = self.sprite_log.as_mut() {
+                    log.push(SpriteDraw { pc: self.pc - 2, addr: self.i as Word, bytes: sprite[..n as usize].to_vec() });
+                }
+
+                // XOR the sprite onto the display, VF is set on collision
+                let draw = self.display.draw_sprite(
+                    xcoord,
+                    ycoord,
+                    &sprite[..n as usize],
+                    self.quirks.edge_x,
+                    self.quirks.edge_y,
+                );
+                self.v[0xF] = if self.quirks.collision_rows {
+                    draw.collided_rows + draw.clipped_rows
+                } else {
+                    (draw.collided_rows > 0) as Byte
+                };
+                self.vblank_wait = self.quirks.display_wait;
                 return;
             }
-            CPU::KEY_OPS => {
-                match ins & 0xF0FF {
-                    CPU::SKP_VX => {
-                        // Skip next instruction if key with the value of Vx is pressed.
-                        if self.v[vx as usize] <= 0xF {
-                            if self.keyboard[self.v[vx as usize] as usize] == 1 {
-                                self.pc += 2; // Skip next instruction
-                            }
-                        }
-                    }
-                    CPU::SKNP_VX => {
-                        // Skip next instruction if key with the value of Vx is not pressed.
-                        if self.v[vx as usize] <= 0xF {
-                            if self.keyboard[self.v[vx as usize] as usize] == 0 {
-                                self.pc += 2; // Skip next instruction
-                            }
-                        }
+            // Skip next instruction if key with the value of Vx is pressed.
+            Instruction::SkpVx(vx) if self.v[vx as usize] <= 0xF && self.keyboard[self.v[vx as usize] as usize] == 1 => {
+                self.pc += 2; // Skip next instruction
+            }
+            // Skip next instruction if key with the value of Vx is not pressed.
+            Instruction::SknpVx(vx) if self.v[vx as usize] <= 0xF && self.keyboard[self.v[vx as usize] as usize] == 0 => {
+                self.pc += 2; // Skip next instruction
+            }
+            Instruction::LdVxDt(vx) => {
+                self.v[vx as usize] = self.dt;
+            }
+            Instruction::LdVxK(vx) => {
+                for i in 0..16 {
+                    if self.keyboard[i] == 1 {
+                        self.v[vx as usize] = i as Byte;
+                        break;
                     }
-                    _ => {}
                 }
+                self.pc -= 2;
             }
-            CPU::DTST_OPS => {
-                match ins & 0xF0FF {
-                    CPU::LD_VX_DT => {
-                        self.v[vx as usize] = self.dt;
-                    }
-                    CPU::LD_VX_K => {
-                        for i in 0..16 {
-                            if self.keyboard[i] == 1 {
-                                self.v[vx as usize] = i as Byte;
-                                break;
-                            }
-                        }
-                        self.pc -= 2;
-                    }
-                    CPU::LD_DT_VX => {
-                        self.dt = self.v[vx as usize];
-                    }
-                    CPU::LD_ST_VX => {
-                        self.st = self.v[vx as usize];
-                    }
-                    CPU::ADD_I_VX => {
-                        self.i += self.v[vx as usize] as Word;
-                    }
-                    CPU::LD_F_VX => {
-                        if self.v[vx as usize] < 16 {
-                            self.i = (self.v[vx as usize] as Word) * 5;
-                        }
-                    }
-                    CPU::LD_B_VX => {
-                        let value = self.v[vx as usize];
-                        self.memory[self.i as usize] = value / 100;
-                        self.memory[(self.i + 1) as usize] = (value / 10) % 10;
-                        self.memory[(self.i + 2) as usize] = value % 10;
-                    }
-                    CPU::LD_STO_I_VX => {
-                        // Store registers V0 through Vx in memory starting at location I
-                        for i in 0..=vx as usize {
-                            self.memory[self.i as usize + i] = self.v[i];
-                        }
-                    }
-                    CPU::LD_STO_VX_I => {
-                        // Read registers V0 through Vx from memory starting at location I
-                        for i in 0..=vx as usize {
-                            self.v[i] = self.memory[self.i as usize + i];
-                        }
-                    }
-                    _ => {}
+            Instruction::LdDtVx(vx) => {
+                self.dt = self.v[vx as usize];
+            }
+            Instruction::LdStVx(vx) => {
+                self.st = self.v[vx as usize];
+            }
+            Instruction::AddIVx(vx) => {
+                self.i += self.v[vx as usize] as u32;
+            }
+            Instruction::LdFVx(vx) if self.v[vx as usize] < 16 => {
+                self.i = (self.v[vx as usize] as u32) * 5;
+            }
+            Instruction::LdBVx(vx) => {
+                let value = self.v[vx as usize];
+                self.write_byte(self.i as usize, value / 100);
+                self.write_byte((self.i + 1) as usize, (value / 10) % 10);
+                self.write_byte((self.i + 2) as usize, value % 10);
+            }
+            Instruction::LdStoIVx(vx) => {
+                // Store registers V0 through Vx in memory starting at location I
+                for i in 0..=vx as usize {
+                    self.write_byte(self.i as usize + i, self.v[i]);
                 }
+                self.advance_i(vx);
+            }
+            Instruction::LdAudioI => {
+                for (offset, byte) in self.audio.pattern.iter_mut().enumerate() {
+                    *byte = self.memory.read(self.i as usize + offset);
+                }
+            }
+            Instruction::LdRVx(vx) => self.store_flags(vx),
+            Instruction::LdVxR(vx) => self.load_flags(vx),
+            Instruction::LdPitchVx(vx) => {
+                self.audio.pitch = self.v[vx as usize];
+            }
+            Instruction::LdStoVxI(vx) => {
+                // Read registers V0 through Vx from memory starting at location I
+                for i in 0..=vx as usize {
+                    self.v[i] = self.memory.read(self.i as usize + i);
+                }
+                self.advance_i(vx);
             }
-
             _ => {}
         }
 
@@ -432,6 +624,26 @@ mod tests {
         assert_eq!(fetched, 0x1AFA, "Fetched value does not match expected value");
     }
 
+    #[test]
+    fn test_opcode() {
+        let mut cpu = CPU::default();
+
+        cpu.memory[cpu.pc as usize] = 0xD1;
+        cpu.memory[(cpu.pc + 1) as usize] = 0x25;
+
+        assert_eq!(cpu.opcode(), 0xD125, "Opcode at PC does not match");
+        assert_eq!(cpu.pc, 0x200, "Reading the opcode moved PC");
+    }
+
+    #[test]
+    fn test_set_keys() {
+        let mut cpu = CPU::default();
+
+        cpu.set_keys(0b1000_0000_0010_0001);
+
+        assert_eq!(cpu.keyboard, [1, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1], "Keypad does not match mask");
+    }
+
     #[test]
     fn test_jmp() {
         let mut cpu = CPU::default();
@@ -699,7 +911,7 @@ mod tests {
                 let display_y = (cpu.v[0xB] as usize + y as usize) % CPU::DISP_Y;
 
                 assert_eq!(
-                    cpu.display[display_y][display_x],
+                    cpu.display.get(display_x, display_y),
                     expected_pixel,
                     "Display pixel at ({}, {}) did not match expected sprite pixel",
                     display_x,
@@ -710,9 +922,9 @@ mod tests {
 
         // 5. Clear the screen
         cpu.execute();
-        for row in cpu.display.iter() {
-            for &cell in row.iter() {
-                assert_eq!(cell, false, "Screen was not cleared properly");
+        for y in 0..CPU::DISP_Y {
+            for x in 0..CPU::DISP_X {
+                assert!(!cpu.display.get(x, y), "Screen was not cleared properly");
             }
         }
     }
@@ -798,6 +1010,119 @@ mod tests {
         cpu.execute();
         assert_eq!(cpu.pc, 0x0202, "RET did not return to correct address");
     }
+
+    #[test]
+    fn test_self_modifying_code() {
+        let mut cpu = CPU::default();
+
+        // 0x200: LD I, 0x206 ; 0x202: LD V0, 0x6E ; 0x204: JP 0x208
+        // 0x206: LD VE, 0x01 (overwritten with LD VE, 0x6E by the LD [I], V1 below)
+        // 0x208: LD V1, 0x6E ; 0x20A: LD [I], V1 ; 0x20C: JP 0x206
+        let program = [0xA2, 0x06, 0x60, 0x6E, 0x12, 0x08, 0x6E, 0x01, 0x61, 0x6E, 0xF1, 0x55, 0x12, 0x06];
+        for (offset, &byte) in program.iter().enumerate() {
+            cpu.memory[0x200 + offset] = byte;
+        }
+
+        // Decode 0x206 once so it sits in the cache before being overwritten.
+        cpu.pc = 0x206;
+        cpu.execute();
+        assert_eq!(cpu.v[0xE], 0x01, "Original instruction did not run");
+
+        cpu.pc = 0x200;
+        for _ in 0..7 {
+            cpu.execute();
+        }
+        assert_eq!(cpu.pc, 0x208, "Program did not reach the patched instruction");
+        assert_eq!(cpu.v[0xE], 0x6E, "Stale decoded instruction was executed");
+    }
+
+    #[test]
+    fn test_drw_collision_rows() {
+        let mut cpu = CPU::default();
+        cpu.quirks.collision_rows = true;
+        cpu.load_sprites();
+
+        // Draw the "0" glyph at (0, 29) twice: 3 rows collide, 2 are clipped.
+        cpu.v[0x1] = 29;
+        for offset in [0x200, 0x202] {
+            cpu.memory[offset] = 0xD0;
+            cpu.memory[offset + 1] = 0x15;
+        }
+        cpu.execute();
+        assert_eq!(cpu.v[0xF], 2, "Clipped rows not counted into VF");
+        cpu.execute();
+        assert_eq!(cpu.v[0xF], 5, "Collided and clipped rows not counted into VF");
+    }
+
+    #[test]
+    fn test_quirks() {
+        let mut cpu = CPU::default();
+        cpu.quirks = Quirks {
+            vf_reset: false,
+            shift: false,
+            memory: MemoryQuirk::IncrementByXPlusOne,
+            jump: true,
+            ..Quirks::default()
+        };
+
+        cpu.v[0xF] = 7;
+        cpu.exec(Instruction::OrVxVy(0x0, 0x1));
+        assert_eq!(cpu.v[0xF], 7, "VF reset without the quirk");
+
+        cpu.v[0x1] = 0x81;
+        cpu.exec(Instruction::ShrVx(0x0, 0x1));
+        assert_eq!((cpu.v[0x0], cpu.v[0xF]), (0x40, 1), "VY not shifted into VX");
+        cpu.exec(Instruction::ShlVx(0xF, 0x1));
+        assert_eq!(cpu.v[0xF], 1, "VF not set after the shift result");
+
+        cpu.i = 0x300;
+        cpu.exec(Instruction::LdStoIVx(0x2));
+        assert_eq!(cpu.i, 0x303, "I not incremented by X + 1");
+        cpu.quirks.memory = MemoryQuirk::IncrementByX;
+        cpu.exec(Instruction::LdStoVxI(0x2));
+        assert_eq!(cpu.i, 0x305, "I not incremented by X");
+
+        cpu.v[0x3] = 0x10;
+        cpu.exec(Instruction::JpV0(0x320));
+        assert_eq!(cpu.pc, 0x330, "BXNN did not add VX");
+    }
+
+    #[test]
+    fn test_clock_hz() {
+        let mut cpu = CPU::default();
+        assert_eq!(cpu.clock_hz(), 540, "Unexpected default clock");
+
+        cpu.set_clock_hz(1000);
+        assert_eq!(cpu.cycles_per_frame, 17, "Clock not rounded to whole instructions per frame");
+        cpu.set_clock_hz(0);
+        assert_eq!(cpu.cycles_per_frame, 1, "Clock allowed to stop");
+    }
+
+    #[test]
+    fn test_display_wait() {
+        let mut cpu = CPU::default();
+        cpu.quirks.display_wait = true;
+
+        // 0x200: DRW V0, V0, 1 ; ADD V1, 1 ; JP 0x200
+        let program = [0xD0, 0x01, 0x71, 0x01, 0x12, 0x00];
+        for (offset, &byte) in program.iter().enumerate() {
+            cpu.memory[0x200 + offset] = byte;
+        }
+
+        cpu.execute();
+        assert!(cpu.vblank_wait, "DRW did not wait for the vertical blank");
+        cpu.execute();
+        assert_eq!(cpu.v[0x1], 0, "Instruction ran during the vertical blank wait");
+
+        // Finish the frame the sprite was drawn in, after that the loop
+        // body runs once per frame.
+        cpu.run_frame();
+        assert_eq!(cpu.v[0x1], 0, "Instruction ran during the vertical blank wait");
+        for frame in 1..=5 {
+            cpu.run_frame();
+            assert_eq!(cpu.v[0x1], frame, "Draws were not limited to one per frame");
+        }
+    }
     
 
 
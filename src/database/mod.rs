@@ -0,0 +1,276 @@
+//! ROM metadata in the format of the community CHIP-8 database
+//! (https://github.com/chip-8/chip-8-database): programs, their SHA-1
+//! hashes, and the platforms they run on.
+//!
+//! The bundled files only list the test ROMs in `src/roms`. The community
+//! database is not checked in: `./update-database.sh` downloads and merges
+//! it, and until then other ROMs run with `UNKNOWN_PLATFORM`'s settings.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::OnceLock;
+use serde::{Deserialize, Serialize};
+use crate::cpu::Word;
+use crate::quirks::{Edge, MemoryQuirk, Quirks};
+
+/// The platform of ROMs the database does not know.
+pub const UNKNOWN_PLATFORM: &str = "modernChip8";
+
+/// A program, possibly with several ROM versions.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Program {
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub authors: Vec<String>,
+    pub roms: BTreeMap<String, RomEntry>,
+}
+
+/// One ROM of a program, keyed by its SHA-1 in `Program::roms`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RomEntry {
+    #[serde(default)]
+    pub file: String,
+    /// Platforms the ROM runs on, the preferred one first.
+    pub platforms: Vec<String>,
+    /// Quirks that differ from the platform's defaults for this ROM.
+    #[serde(default)]
+    pub quirky_platforms: BTreeMap<String, QuirkFlags>,
+    /// Instructions per frame, if not the platform's default.
+    pub tickrate: Option<u32>,
+    pub start_address: Option<Word>,
+    /// CHIP-8 keys for actions such as "up" or "a".
+    #[serde(default)]
+    pub keys: BTreeMap<String, u8>,
+    pub colors: Option<Colors>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Colors {
+    /// Background first, then the colours of each plane combination.
+    #[serde(default)]
+    pub pixels: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub buzzer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub silence: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Platform {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub display_resolutions: Vec<String>,
+    pub default_tickrate: u32,
+    pub quirks: QuirkFlags,
+}
+
+/// Quirks as named by the database. Missing flags are left to whatever
+/// they are merged onto.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QuirkFlags {
+    pub shift: Option<bool>,
+    pub memory_increment_by_x: Option<bool>,
+    pub memory_leave_i_unchanged: Option<bool>,
+    pub wrap: Option<bool>,
+    pub jump: Option<bool>,
+    pub vblank: Option<bool>,
+    pub logic: Option<bool>,
+}
+
+impl QuirkFlags {
+    /// `self` with the flags set in `other` replaced.
+    pub fn merge(self, other: QuirkFlags) -> QuirkFlags {
+        QuirkFlags {
+            shift: other.shift.or(self.shift),
+            memory_increment_by_x: other.memory_increment_by_x.or(self.memory_increment_by_x),
+            memory_leave_i_unchanged: other.memory_leave_i_unchanged.or(self.memory_leave_i_unchanged),
+            wrap: other.wrap.or(self.wrap),
+            jump: other.jump.or(self.jump),
+            vblank: other.vblank.or(self.vblank),
+            logic: other.logic.or(self.logic),
+        }
+    }
+
+    /// The emulator's quirks, with missing flags taken from `base`.
+    pub fn apply(self, base: Quirks) -> Quirks {
+        let memory = match (self.memory_leave_i_unchanged, self.memory_increment_by_x) {
+            (Some(true), _) => MemoryQuirk::Unchanged,
+            (_, Some(true)) => MemoryQuirk::IncrementByX,
+            (Some(false), _) | (_, Some(false)) => MemoryQuirk::IncrementByXPlusOne,
+            (None, None) => base.memory,
+        };
+        let (edge_x, edge_y) = match self.wrap {
+            Some(true) => (Edge::Wrap, Edge::Wrap),
+            Some(false) => (Edge::Clip, Edge::Clip),
+            None => (base.edge_x, base.edge_y),
+        };
+        Quirks {
+            vf_reset: self.logic.unwrap_or(base.vf_reset),
+            shift: self.shift.unwrap_or(base.shift),
+            memory,
+            jump: self.jump.unwrap_or(base.jump),
+            display_wait: self.vblank.unwrap_or(base.display_wait),
+            edge_x,
+            edge_y,
+            collision_rows: base.collision_rows,
+        }
+    }
+}
+
+/// How to run a ROM. Fields left as `None` keep the emulator's current
+/// setting, which is all an unknown ROM gets. Change fields before loading
+/// to override what the database says.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct RomConfig {
+    pub title: Option<String>,
+    pub authors: Vec<String>,
+    pub platform: Option<String>,
+    #[serde(skip)]
+    pub quirks: Option<Quirks>,
+    pub tickrate: Option<u32>,
+    pub start_address: Option<Word>,
+    pub keys: BTreeMap<String, u8>,
+    pub colors: Option<Colors>,
+}
+
+pub struct Database {
+    programs: Vec<Program>,
+    hashes: HashMap<String, usize>,
+    platforms: Vec<Platform>,
+}
+
+impl Database {
+    /// The database bundled with the crate, parsed on first use. It holds
+    /// the community database only after `./update-database.sh` has run.
+    pub fn embedded() -> &'static Database {
+        static EMBEDDED: OnceLock<Database> = OnceLock::new();
+        EMBEDDED.get_or_init(|| {
+            Database::from_json(
+                include_str!("programs.json"),
+                include_str!("sha1-hashes.json"),
+                include_str!("platforms.json"),
+            )
+            .expect("Bundled ROM database is invalid")
+        })
+    }
+
+    /// Parses the three files of the community database.
+    pub fn from_json(programs: &str, hashes: &str, platforms: &str) -> serde_json::Result<Database> {
+        Ok(Database {
+            programs: serde_json::from_str(programs)?,
+            hashes: serde_json::from_str(hashes)?,
+            platforms: serde_json::from_str(platforms)?,
+        })
+    }
+
+    pub fn program(&self, sha1: &str) -> Option<&Program> {
+        self.programs.get(*self.hashes.get(sha1)?)
+    }
+
+    pub fn platforms(&self) -> &[Platform] {
+        &self.platforms
+    }
+
+    pub fn platform(&self, id: &str) -> Option<&Platform> {
+        self.platforms.iter().find(|platform| platform.id == id)
+    }
+
+    /// The configuration for running any ROM on the platform `id`.
+    pub fn platform_config(&self, id: &str) -> Option<RomConfig> {
+        let platform = self.platform(id)?;
+        Some(RomConfig {
+            platform: Some(platform.id.clone()),
+            quirks: Some(platform.quirks.apply(Quirks::default())),
+            tickrate: Some(platform.default_tickrate),
+            ..RomConfig::default()
+        })
+    }
+
+    /// The configuration for the ROM with the given SHA-1 (lowercase hex),
+    /// on its preferred platform.
+    pub fn lookup(&self, sha1: &str) -> Option<RomConfig> {
+        let program = self.program(sha1)?;
+        let rom = program.roms.get(sha1)?;
+        let platform = rom.platforms.first().and_then(|id| self.platform(id));
+
+        let mut flags = platform.map(|platform| platform.quirks).unwrap_or_default();
+        if let Some(platform) = platform {
+            if let Some(overrides) = rom.quirky_platforms.get(&platform.id) {
+                flags = flags.merge(*overrides);
+            }
+        }
+
+        Some(RomConfig {
+            title: Some(program.title.clone()),
+            authors: program.authors.clone(),
+            platform: platform.map(|platform| platform.id.clone()),
+            quirks: platform.map(|_| flags.apply(Quirks::default())),
+            tickrate: rom.tickrate.or(platform.map(|platform| platform.default_tickrate)),
+            start_address: rom.start_address,
+            keys: rom.keys.clone(),
+            colors: rom.colors.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let db = Database::embedded();
+        let config = db.lookup("e2149cb836131a142ca7e2dc2f2283381ae5faaa").expect("Quirks test not found");
+
+        assert_eq!(config.title.as_deref(), Some("Quirks test"), "Wrong title");
+        assert_eq!(config.platform.as_deref(), Some("originalChip8"), "Not the preferred platform");
+        assert_eq!(config.tickrate, Some(15), "Platform tickrate not used");
+        let quirks = config.quirks.expect("No quirks for a known platform");
+        assert!(quirks.display_wait && quirks.vf_reset && !quirks.shift, "VIP quirks not applied");
+        assert_eq!(quirks.memory, MemoryQuirk::IncrementByXPlusOne, "VIP memory quirk not applied");
+        assert_eq!(config.colors.unwrap().pixels, ["#000000", "#ffff00"], "Colours not read");
+
+        assert!(db.lookup("0000000000000000000000000000000000000000").is_none(), "Unknown hash found");
+    }
+
+    #[test]
+    fn test_quirky_platforms() {
+        let programs = r#"[{
+            "title": "Test",
+            "roms": {
+                "abcd": {
+                    "platforms": ["superchip"],
+                    "quirkyPlatforms": { "superchip": { "wrap": true, "memoryIncrementByX": true, "memoryLeaveIUnchanged": false } },
+                    "tickrate": 100,
+                    "startAddress": 768,
+                    "keys": { "up": 5 }
+                }
+            }
+        }]"#;
+        let db = Database::from_json(programs, r#"{ "abcd": 0 }"#, include_str!("platforms.json")).unwrap();
+        let config = db.lookup("abcd").unwrap();
+        let quirks = config.quirks.unwrap();
+
+        assert_eq!(config.tickrate, Some(100), "ROM tickrate not preferred");
+        assert_eq!(config.start_address, Some(0x300), "Start address not read");
+        assert_eq!(config.keys.get("up"), Some(&5), "Keys not read");
+        assert_eq!(quirks.edge_x, Edge::Wrap, "Quirk override not applied");
+        assert_eq!(quirks.memory, MemoryQuirk::IncrementByX, "Memory override not applied");
+        assert!(quirks.jump && quirks.shift && !quirks.display_wait, "Platform quirks lost by the override");
+    }
+
+    #[test]
+    fn test_platform_config() {
+        let db = Database::embedded();
+        let config = db.platform_config("chip8x").expect("CHIP-8X platform missing");
+        assert_eq!(config.tickrate, Some(15), "Platform tickrate not used");
+        assert!(config.quirks.unwrap().vf_reset, "Platform quirks not applied");
+        assert!(db.platform_config("nope").is_none(), "Unknown platform found");
+    }
+}
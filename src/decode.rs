@@ -0,0 +1,223 @@
+use std::fmt;
+use crate::chip8x;
+use crate::cpu::{Byte, Word, CPU};
+use crate::megachip;
+
+/// A decoded instruction. Register operands are indices into `CPU::v`,
+/// addresses and immediates are already masked out of the opcode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    Sys(Word),
+    Cls,
+    Ret,
+    Jp(Word),
+    Call(Word),
+    SeVx(u8, Byte),
+    SneVx(u8, Byte),
+    SeVxVy(u8, u8),
+    LdVx(u8, Byte),
+    AddVx(u8, Byte),
+    LdVxVy(u8, u8),
+    OrVxVy(u8, u8),
+    AndVxVy(u8, u8),
+    XorVxVy(u8, u8),
+    AddVxVy(u8, u8),
+    SubVxVy(u8, u8),
+    ShrVx(u8, u8),
+    SubnVxVy(u8, u8),
+    ShlVx(u8, u8),
+    SneVxVy(u8, u8),
+    LdI(Word),
+    JpV0(Word),
+    RndVx(u8, Byte),
+    DrwVxVy(u8, u8, u8),
+    SkpVx(u8),
+    SknpVx(u8),
+    LdVxDt(u8),
+    LdVxK(u8),
+    LdDtVx(u8),
+    LdStVx(u8),
+    AddIVx(u8),
+    LdFVx(u8),
+    LdBVx(u8),
+    LdStoIVx(u8),
+    LdStoVxI(u8),
+    /// XO-CHIP F002: load the 16 byte audio pattern from I.
+    LdAudioI,
+    /// XO-CHIP FX3A: set the audio pitch to VX.
+    LdPitchVx(u8),
+    /// SUPER-CHIP FX75: save V0 through VX in the RPL user flags.
+    LdRVx(u8),
+    /// SUPER-CHIP FX85: load V0 through VX from the RPL user flags.
+    LdVxR(u8),
+    /// CHIP-8X's colour and second keypad opcodes, see `chip8x`.
+    Chip8X(chip8x::Op),
+    /// MegaChip's 0NNN opcodes, see `megachip`.
+    Mega(megachip::Op),
+    /// Anything the interpreter does not know, executed as a no-op.
+    Unknown(Word),
+}
+
+pub fn decode(ins: Word) -> Instruction {
+    let x = ((ins >> 8) & 0x000F) as u8;
+    let y = ((ins >> 4) & 0x000F) as u8;
+    let n = (ins & 0x000F) as u8;
+    let nn = (ins & 0x00FF) as Byte;
+    let nnn = ins & 0x0FFF;
+
+    match ins {
+        CPU::CLS => return Instruction::Cls,
+        CPU::RET => return Instruction::Ret,
+        CPU::LD_AUDIO_I => return Instruction::LdAudioI,
+        _ => {}
+    }
+
+    match ins & 0xF000 {
+        CPU::SYS_ADDR => Instruction::Sys(nnn),
+        CPU::JP_ADDR => Instruction::Jp(nnn),
+        CPU::CALL_ADDR => Instruction::Call(nnn),
+        CPU::SE_VX => Instruction::SeVx(x, nn),
+        CPU::SNE_VX => Instruction::SneVx(x, nn),
+        CPU::SE_VX_VY => Instruction::SeVxVy(x, y),
+        CPU::LD_VX => Instruction::LdVx(x, nn),
+        CPU::ADD_VX => Instruction::AddVx(x, nn),
+        CPU::COMP_INS => match ins & 0xF00F {
+            CPU::LD_VX_VY => Instruction::LdVxVy(x, y),
+            CPU::OR_VX_VY => Instruction::OrVxVy(x, y),
+            CPU::AND_VX_VY => Instruction::AndVxVy(x, y),
+            CPU::XOR_VX_VY => Instruction::XorVxVy(x, y),
+            CPU::ADD_VX_VY => Instruction::AddVxVy(x, y),
+            CPU::SUB_VX_VY => Instruction::SubVxVy(x, y),
+            CPU::SHR_VX => Instruction::ShrVx(x, y),
+            CPU::SUBN_VX_VY => Instruction::SubnVxVy(x, y),
+            CPU::SHL_VX => Instruction::ShlVx(x, y),
+            _ => Instruction::Unknown(ins),
+        },
+        CPU::SNE_VX_VY => Instruction::SneVxVy(x, y),
+        CPU::LD_I => Instruction::LdI(nnn),
+        CPU::JP_V0 => Instruction::JpV0(nnn),
+        CPU::RND_VX => Instruction::RndVx(x, nn),
+        CPU::DRW_VX_VY => Instruction::DrwVxVy(x, y, n),
+        CPU::KEY_OPS => match ins & 0xF0FF {
+            CPU::SKP_VX => Instruction::SkpVx(x),
+            CPU::SKNP_VX => Instruction::SknpVx(x),
+            _ => Instruction::Unknown(ins),
+        },
+        CPU::DTST_OPS => match ins & 0xF0FF {
+            CPU::LD_VX_DT => Instruction::LdVxDt(x),
+            CPU::LD_VX_K => Instruction::LdVxK(x),
+            CPU::LD_DT_VX => Instruction::LdDtVx(x),
+            CPU::LD_ST_VX => Instruction::LdStVx(x),
+            CPU::ADD_I_VX => Instruction::AddIVx(x),
+            CPU::LD_F_VX => Instruction::LdFVx(x),
+            CPU::LD_B_VX => Instruction::LdBVx(x),
+            CPU::LD_STO_I_VX => Instruction::LdStoIVx(x),
+            CPU::LD_STO_VX_I => Instruction::LdStoVxI(x),
+            CPU::LD_PITCH_VX => Instruction::LdPitchVx(x),
+            CPU::LD_R_VX => Instruction::LdRVx(x),
+            CPU::LD_VX_R => Instruction::LdVxR(x),
+            _ => Instruction::Unknown(ins),
+        },
+        _ => Instruction::Unknown(ins),
+    }
+}
+
+impl Instruction {
+    /// The address operand of instructions that refer to memory.
+    pub fn target(self) -> Option<Word> {
+        match self {
+            Instruction::Sys(addr)
+            | Instruction::Jp(addr)
+            | Instruction::Call(addr)
+            | Instruction::LdI(addr)
+            | Instruction::JpV0(addr) => Some(addr),
+            _ => None,
+        }
+    }
+
+    /// Writes the instruction in Cowgod's syntax, with the address operand
+    /// formatted by `addr` (e.g. as a label).
+    pub fn write(self, f: &mut impl fmt::Write, addr: impl Fn(Word) -> String) -> fmt::Result {
+        match self {
+            Instruction::Sys(nnn) => write!(f, "SYS {}", addr(nnn)),
+            Instruction::Cls => write!(f, "CLS"),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::Jp(nnn) => write!(f, "JP {}", addr(nnn)),
+            Instruction::Call(nnn) => write!(f, "CALL {}", addr(nnn)),
+            Instruction::SeVx(x, nn) => write!(f, "SE V{:X}, 0x{:02X}", x, nn),
+            Instruction::SneVx(x, nn) => write!(f, "SNE V{:X}, 0x{:02X}", x, nn),
+            Instruction::SeVxVy(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::LdVx(x, nn) => write!(f, "LD V{:X}, 0x{:02X}", x, nn),
+            Instruction::AddVx(x, nn) => write!(f, "ADD V{:X}, 0x{:02X}", x, nn),
+            Instruction::LdVxVy(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Instruction::OrVxVy(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::AndVxVy(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::XorVxVy(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::AddVxVy(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::SubVxVy(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Instruction::ShrVx(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::SubnVxVy(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Instruction::ShlVx(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SneVxVy(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::LdI(nnn) => write!(f, "LD I, {}", addr(nnn)),
+            Instruction::JpV0(nnn) => write!(f, "JP V0, {}", addr(nnn)),
+            Instruction::RndVx(x, nn) => write!(f, "RND V{:X}, 0x{:02X}", x, nn),
+            Instruction::DrwVxVy(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::SkpVx(x) => write!(f, "SKP V{:X}", x),
+            Instruction::SknpVx(x) => write!(f, "SKNP V{:X}", x),
+            Instruction::LdVxDt(x) => write!(f, "LD V{:X}, DT", x),
+            Instruction::LdVxK(x) => write!(f, "LD V{:X}, K", x),
+            Instruction::LdDtVx(x) => write!(f, "LD DT, V{:X}", x),
+            Instruction::LdStVx(x) => write!(f, "LD ST, V{:X}", x),
+            Instruction::AddIVx(x) => write!(f, "ADD I, V{:X}", x),
+            Instruction::LdFVx(x) => write!(f, "LD F, V{:X}", x),
+            Instruction::LdBVx(x) => write!(f, "LD B, V{:X}", x),
+            Instruction::LdStoIVx(x) => write!(f, "LD [I], V{:X}", x),
+            Instruction::LdStoVxI(x) => write!(f, "LD V{:X}, [I]", x),
+            Instruction::LdAudioI => write!(f, "LD AUDIO, [I]"),
+            Instruction::LdPitchVx(x) => write!(f, "LD PITCH, V{:X}", x),
+            Instruction::LdRVx(x) => write!(f, "LD R, V{:X}", x),
+            Instruction::LdVxR(x) => write!(f, "LD V{:X}, R", x),
+            Instruction::Chip8X(op) => write!(f, "{}", op),
+            Instruction::Mega(op) => write!(f, "{}", op),
+            Instruction::Unknown(ins) => write!(f, "DW 0x{:04X}", ins),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, |nnn| format!("0x{:03X}", nnn))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode(0x00E0), Instruction::Cls);
+        assert_eq!(decode(0x00EE), Instruction::Ret);
+        assert_eq!(decode(0x0123), Instruction::Sys(0x123));
+        assert_eq!(decode(0x1F4C), Instruction::Jp(0xF4C));
+        assert_eq!(decode(0x7A05), Instruction::AddVx(0xA, 0x05));
+        assert_eq!(decode(0x8EC7), Instruction::SubnVxVy(0xE, 0xC));
+        assert_eq!(decode(0x8EC9), Instruction::Unknown(0x8EC9));
+        assert_eq!(decode(0xDAB8), Instruction::DrwVxVy(0xA, 0xB, 8));
+        assert_eq!(decode(0xE3A1), Instruction::SknpVx(0x3));
+        assert_eq!(decode(0xF265), Instruction::LdStoVxI(0x2));
+        assert_eq!(decode(0xF002), Instruction::LdAudioI);
+        assert_eq!(decode(0xF43A), Instruction::LdPitchVx(0x4));
+        assert_eq!(decode(0xF775), Instruction::LdRVx(0x7));
+        assert_eq!(decode(0xF385), Instruction::LdVxR(0x3));
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(decode(0xA22A).to_string(), "LD I, 0x22A");
+        assert_eq!(decode(0xDAB8).to_string(), "DRW VA, VB, 8");
+        assert_eq!(decode(0xF255).to_string(), "LD [I], V2");
+        assert_eq!(decode(0x8EC9).to_string(), "DW 0x8EC9");
+    }
+}
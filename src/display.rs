@@ -0,0 +1,246 @@
+use std::ops::{BitAnd, BitXorAssign};
+use crate::cpu::Byte;
+use crate::quirks::Edge;
+
+/// One packed framebuffer row, the leftmost pixel in the most significant bit.
+pub trait Row: Copy + Default + PartialEq + BitAnd<Output = Self> + BitXorAssign {
+    const WIDTH: usize;
+
+    /// An 8 pixel sprite row starting at column `x`, clipped at or wrapped
+    /// around the right edge.
+    fn sprite(byte: Byte, x: usize, edge: Edge) -> Self;
+    fn pixel(self, x: usize) -> bool;
+    fn with_pixel(self, x: usize, on: bool) -> Self;
+
+    fn is_empty(self) -> bool {
+        self == Self::default()
+    }
+}
+
+macro_rules! impl_row {
+    ($ty:ty) => {
+        impl Row for $ty {
+            const WIDTH: usize = <$ty>::BITS as usize;
+
+            fn sprite(byte: Byte, x: usize, edge: Edge) -> Self {
+                let row = (byte as $ty) << (Self::WIDTH - 8);
+                match edge {
+                    Edge::Clip => row >> x,
+                    Edge::Wrap => row.rotate_right(x as u32),
+                }
+            }
+
+            fn pixel(self, x: usize) -> bool {
+                (self >> (Self::WIDTH - 1 - x)) & 1 != 0
+            }
+
+            fn with_pixel(self, x: usize, on: bool) -> Self {
+                let bit = 1 << (Self::WIDTH - 1 - x);
+                if on { self | bit } else { self & !bit }
+            }
+        }
+    };
+}
+
+// u64 rows for the 64 pixel wide lores screen, u128 for 128 pixel hires.
+impl_row!(u64);
+impl_row!(u128);
+
+/// The region of the screen changed since the renderer last asked,
+/// `x`/`y` inclusive and `x_end`/`y_end` exclusive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DirtyRect {
+    pub x: usize,
+    pub y: usize,
+    pub x_end: usize,
+    pub y_end: usize,
+}
+
+impl DirtyRect {
+    fn union(self, other: DirtyRect) -> DirtyRect {
+        DirtyRect {
+            x: self.x.min(other.x),
+            y: self.y.min(other.y),
+            x_end: self.x_end.max(other.x_end),
+            y_end: self.y_end.max(other.y_end),
+        }
+    }
+}
+
+/// The outcome of drawing a sprite.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Draw {
+    /// Rows in which a lit pixel was switched off.
+    pub collided_rows: u8,
+    /// Rows dropped at the bottom edge.
+    pub clipped_rows: u8,
+}
+
+/// A monochrome screen stored one packed row per line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Framebuffer<R: Row = u64> {
+    rows: Vec<R>,
+    dirty: Option<DirtyRect>,
+}
+
+impl<R: Row> Framebuffer<R> {
+    pub fn new(height: usize) -> Self {
+        let mut display = Self { rows: vec![R::default(); height], dirty: None };
+        display.mark_all();
+        display
+    }
+
+    pub fn width(&self) -> usize {
+        R::WIDTH
+    }
+
+    pub fn height(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn rows(&self) -> &[R] {
+        &self.rows
+    }
+
+    /// Replaces the whole screen, e.g. when restoring a save state.
+    pub fn load_rows(&mut self, rows: &[R]) {
+        self.rows.clear();
+        self.rows.extend_from_slice(rows);
+        self.mark_all();
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> bool {
+        self.rows[y].pixel(x)
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, on: bool) {
+        self.rows[y] = self.rows[y].with_pixel(x, on);
+        self.mark(DirtyRect { x, y, x_end: x + 1, y_end: y + 1 });
+    }
+
+    /// Unpacks the screen into one byte per pixel (0 or 1), row by row.
+    pub fn write_pixels(&self, out: &mut [u8]) {
+        for (y, &row) in self.rows.iter().enumerate() {
+            for x in 0..self.width() {
+                out[y * self.width() + x] = row.pixel(x) as u8;
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.rows.iter_mut().for_each(|row| *row = R::default());
+        self.mark_all();
+    }
+
+    /// XORs a sprite onto the screen at (`x`, `y`). The position wraps,
+    /// pixels past the edges are clipped or wrapped as given.
+    pub fn draw_sprite(&mut self, x: usize, y: usize, sprite: &[Byte], edge_x: Edge, edge_y: Edge) -> Draw {
+        let width = self.width();
+        let height = self.height();
+        let (x, y) = (x % width, y % height);
+        let mut draw = Draw::default();
+        for (row, &byte) in sprite.iter().enumerate() {
+            let screen_y = match edge_y {
+                Edge::Clip if y + row >= height => {
+                    draw.clipped_rows += 1;
+                    continue;
+                }
+                Edge::Clip => y + row,
+                Edge::Wrap => (y + row) % height,
+            };
+            if byte == 0 {
+                continue;
+            }
+            let bits = R::sprite(byte, x, edge_x);
+            if !(self.rows[screen_y] & bits).is_empty() {
+                draw.collided_rows += 1;
+            }
+            self.rows[screen_y] ^= bits;
+
+            let (x0, x1) = match edge_x {
+                Edge::Wrap if x + 8 > width => (0, width),
+                _ => (x, (x + 8).min(width)),
+            };
+            self.mark(DirtyRect { x: x0, y: screen_y, x_end: x1, y_end: screen_y + 1 });
+        }
+        draw
+    }
+
+    /// Returns the region changed since the last call and resets it.
+    pub fn take_dirty(&mut self) -> Option<DirtyRect> {
+        self.dirty.take()
+    }
+
+    /// Marks the whole screen for repainting.
+    pub fn mark_all(&mut self) {
+        self.dirty = Some(DirtyRect { x: 0, y: 0, x_end: self.width(), y_end: self.height() });
+    }
+
+    fn mark(&mut self, rect: DirtyRect) {
+        self.dirty = Some(match self.dirty {
+            Some(dirty) => dirty.union(rect),
+            None => rect,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_draw_sprite() {
+        let mut display: Framebuffer = Framebuffer::new(32);
+        display.take_dirty();
+
+        let draw = display.draw_sprite(10, 4, &[0xF0, 0x90], Edge::Clip, Edge::Clip);
+        assert_eq!(draw, Draw::default(), "Collision on empty screen");
+        assert!(display.get(10, 4) && display.get(13, 4) && !display.get(14, 4));
+        assert!(display.get(13, 5) && !display.get(11, 5));
+        assert_eq!(
+            display.take_dirty(),
+            Some(DirtyRect { x: 10, y: 4, x_end: 18, y_end: 6 }),
+            "Dirty region does not cover the sprite"
+        );
+        assert_eq!(display.take_dirty(), None, "Dirty region was not reset");
+
+        let draw = display.draw_sprite(13, 5, &[0x80], Edge::Clip, Edge::Clip);
+        assert_eq!(draw.collided_rows, 1, "Collision not detected");
+        assert!(!display.get(13, 5));
+    }
+
+    #[test]
+    fn test_draw_sprite_wraps() {
+        let mut display: Framebuffer<u128> = Framebuffer::new(64);
+        display.take_dirty();
+
+        display.draw_sprite(124, 63, &[0xFF, 0x81], Edge::Wrap, Edge::Wrap);
+        assert!(display.get(127, 63) && display.get(0, 63) && display.get(3, 63));
+        assert!(display.get(124, 0) && !display.get(127, 0) && display.get(3, 0));
+        assert_eq!(
+            display.take_dirty(),
+            Some(DirtyRect { x: 0, y: 0, x_end: 128, y_end: 64 }),
+            "Wrapped rows not marked in full"
+        );
+    }
+
+    #[test]
+    fn test_draw_sprite_clips() {
+        let mut display: Framebuffer = Framebuffer::new(32);
+        display.take_dirty();
+
+        let draw = display.draw_sprite(60, 30, &[0xFF, 0xFF, 0xFF, 0x00], Edge::Clip, Edge::Clip);
+        assert_eq!(draw.clipped_rows, 2, "Rows past the bottom edge not counted");
+        assert!(display.get(60, 30) && display.get(63, 31), "Visible part not drawn");
+        assert!(!display.get(0, 30) && !display.get(60, 0), "Clipped pixels wrapped");
+        assert_eq!(
+            display.take_dirty(),
+            Some(DirtyRect { x: 60, y: 30, x_end: 64, y_end: 32 }),
+            "Dirty region extends past the edge"
+        );
+
+        // The starting position wraps even when clipping.
+        display.draw_sprite(64 + 2, 32 + 1, &[0x80], Edge::Clip, Edge::Clip);
+        assert!(display.get(2, 1), "Starting position did not wrap");
+    }
+}
@@ -0,0 +1,294 @@
+//! A Gym-style reinforcement learning environment around `CPU`. The
+//! emulator is deterministic for a seed, so episodes replay exactly.
+//!
+//! An action is the keypad as a bitmask, held for `frameskip` frames. The
+//! reward is how much a score read from the machine went up during the
+//! step, e.g. `bcd_score` for the three digits FX33 writes, and the episode
+//! ends when a termination check such as `pc_at` holds. `VecEnv` steps many
+//! environments at once, each on a worker thread of its own.
+
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use crate::bus::{Bus, Ram4K};
+use crate::cpu::{Byte, Word, CPU};
+use crate::database::RomConfig;
+use crate::probes::Source;
+use crate::rom::{LoadError, Rom};
+
+/// Reads a score from the machine, the reward is its change.
+pub type ScoreFn<B> = Box<dyn Fn(&CPU<B>) -> f32 + Send>;
+/// Whether the episode is over.
+pub type DoneFn<B> = Box<dyn Fn(&CPU<B>) -> bool + Send>;
+
+/// The outcome of `Env::step`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Step {
+    /// The screen, one byte per pixel (0 or 1), row by row.
+    pub observation: Vec<u8>,
+    pub reward: f32,
+    pub done: bool,
+}
+
+pub struct Env<B: Bus = Ram4K> {
+    rom: Rom,
+    config: RomConfig,
+    cpu: CPU<B>,
+    score: ScoreFn<B>,
+    done: DoneFn<B>,
+}
+
+impl<B: Bus + Default> Env<B> {
+    /// An environment for `rom`, set up by `config`. Without `with_score`
+    /// every reward is 0, without `with_done` episodes never end.
+    pub fn new(rom: &Rom, config: RomConfig) -> Result<Self, LoadError> {
+        let mut cpu = CPU::with_bus(B::default());
+        cpu.load_rom(rom, &config)?;
+        Ok(Env { rom: rom.clone(), config, cpu, score: Box::new(|_| 0.0), done: Box::new(|_| false) })
+    }
+
+    pub fn with_score(mut self, score: impl Fn(&CPU<B>) -> f32 + Send + 'static) -> Self {
+        self.score = Box::new(score);
+        self
+    }
+
+    pub fn with_done(mut self, done: impl Fn(&CPU<B>) -> bool + Send + 'static) -> Self {
+        self.done = Box::new(done);
+        self
+    }
+
+    /// Has `pc_at(addr)` see the program pass through `addr` during a
+    /// frame, not only stop there at its end.
+    pub fn watch_pc(mut self, addr: Word) -> Self {
+        self.cpu.watch_pc(addr);
+        self
+    }
+
+    /// Starts a new episode on a fresh machine whose random numbers come
+    /// from `seed`, and returns the first observation.
+    pub fn reset(&mut self, seed: u64) -> Vec<u8> {
+        let mut cpu = CPU::with_bus(B::default());
+        cpu.seed(seed);
+        cpu.pc_watch = self.cpu.pc_watch.iter().map(|&(addr, _)| (addr, false)).collect();
+        cpu.load_rom(&self.rom, &self.config).expect("ROM loaded before");
+        self.cpu = cpu;
+        self.observation()
+    }
+
+    /// Holds the keys in `action` (bit N for key N) for `frameskip` frames,
+    /// stopping early when the episode ends.
+    pub fn step(&mut self, action: u16, frameskip: u32) -> Step {
+        let before = (self.score)(&self.cpu);
+        self.cpu.set_keys(action);
+        let mut done = (self.done)(&self.cpu);
+        for _ in 0..frameskip.max(1) {
+            if done {
+                break;
+            }
+            self.cpu.run_frame();
+            done = (self.done)(&self.cpu);
+        }
+        Step { observation: self.observation(), reward: (self.score)(&self.cpu) - before, done }
+    }
+
+    pub fn observation(&self) -> Vec<u8> {
+        let display = &self.cpu.display;
+        let mut out = vec![0; display.width() * display.height()];
+        display.write_pixels(&mut out);
+        out
+    }
+
+    pub fn cpu(&self) -> &CPU<B> {
+        &self.cpu
+    }
+}
+
+/// The number FX33 stored at `addr`: hundreds, tens and ones.
+pub fn bcd_score<B: Bus>(addr: usize) -> impl Fn(&CPU<B>) -> f32 + Send + 'static {
+    move |cpu| Source::Bcd(addr).read(cpu) as f32
+}
+
+/// The byte at `addr`.
+pub fn byte_score<B: Bus>(addr: usize) -> impl Fn(&CPU<B>) -> f32 + Send + 'static {
+    move |cpu| Source::Byte(addr).read(cpu) as f32
+}
+
+/// Register VX.
+pub fn register_score<B: Bus>(x: u8) -> impl Fn(&CPU<B>) -> f32 + Send + 'static {
+    move |cpu| Source::Register(x).read(cpu) as f32
+}
+
+/// Over once the program reaches `addr`, e.g. its game over loop. Only
+/// where a frame ends unless the environment has `watch_pc(addr)`.
+pub fn pc_at<B: Bus>(addr: Word) -> impl Fn(&CPU<B>) -> bool + Send + 'static {
+    move |cpu| cpu.reached(addr)
+}
+
+/// Over once the byte at `addr` holds `value`, e.g. no lives left.
+pub fn byte_equals<B: Bus>(addr: usize, value: Byte) -> impl Fn(&CPU<B>) -> bool + Send + 'static {
+    move |cpu| Source::Byte(addr).read(cpu) == value as u32
+}
+
+enum Job {
+    Reset(u64),
+    Step(u16, u32),
+}
+
+/// A thread that owns one environment and runs the jobs sent to it, for as
+/// long as the `VecEnv` lives. A reset answers with a step without reward.
+struct Worker<B: Bus> {
+    jobs: Sender<Job>,
+    steps: Receiver<Step>,
+    thread: JoinHandle<Env<B>>,
+}
+
+impl<B: Bus + Default + Send + 'static> Worker<B> {
+    fn spawn(mut env: Env<B>) -> Self {
+        let (jobs, queue) = mpsc::channel();
+        let (results, steps) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            for job in queue {
+                let step = match job {
+                    Job::Reset(seed) => Step { observation: env.reset(seed), reward: 0.0, done: false },
+                    Job::Step(action, frameskip) => env.step(action, frameskip),
+                };
+                if results.send(step).is_err() {
+                    break;
+                }
+            }
+            env
+        });
+        Worker { jobs, steps, thread }
+    }
+}
+
+/// Independent environments stepped in parallel, each on a worker thread
+/// started once by `new`.
+pub struct VecEnv<B: Bus = Ram4K> {
+    workers: Vec<Worker<B>>,
+}
+
+impl<B: Bus + Default + Send + 'static> VecEnv<B> {
+    pub fn new(envs: Vec<Env<B>>) -> Self {
+        VecEnv { workers: envs.into_iter().map(Worker::spawn).collect() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.workers.is_empty()
+    }
+
+    /// Resets environment N with seed N of `seeds`, one per environment.
+    pub fn reset(&mut self, seeds: &[u64]) -> Vec<Vec<u8>> {
+        assert_eq!(seeds.len(), self.len(), "Need one seed per environment");
+        self.run(seeds.iter().map(|&seed| Job::Reset(seed)))
+            .into_iter()
+            .map(|step| step.observation)
+            .collect()
+    }
+
+    /// Steps environment N with action N of `actions`, one per environment.
+    /// Finished episodes are not reset, that is up to the caller.
+    pub fn step(&mut self, actions: &[u16], frameskip: u32) -> Vec<Step> {
+        assert_eq!(actions.len(), self.len(), "Need one action per environment");
+        self.run(actions.iter().map(|&action| Job::Step(action, frameskip)))
+    }
+
+    /// Stops the workers and hands back their environments.
+    pub fn into_envs(self) -> Vec<Env<B>> {
+        self.workers.into_iter()
+            .map(|Worker { jobs, thread, .. }| {
+                drop(jobs);
+                thread.join().expect("Environment panicked")
+            })
+            .collect()
+    }
+
+    /// Gives every worker its job, then waits for all of them.
+    fn run(&mut self, jobs: impl Iterator<Item = Job>) -> Vec<Step> {
+        for (worker, job) in self.workers.iter().zip(jobs) {
+            worker.jobs.send(job).expect("Environment panicked");
+        }
+        self.workers.iter().map(|worker| worker.steps.recv().expect("Environment panicked")).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Once a frame, adds 1 to V1 if key 5 is down and stores it as BCD at
+    /// 0x300. V1 reaching 3 ends in the loop at 0x210.
+    const COUNTER: [Byte; 18] = [
+        0xA3, 0x00, // 200: LD I, 0x300
+        0x65, 0x05, // 202: LD V5, 5
+        0xE5, 0x9E, // 204: SKP V5
+        0x12, 0x0A, // 206: JP 0x20A
+        0x71, 0x01, // 208: ADD V1, 1
+        0xF1, 0x33, // 20A: LD B, V1
+        0x31, 0x03, // 20C: SE V1, 3
+        0x12, 0x04, // 20E: JP 0x204
+        0x12, 0x10, // 210: JP 0x210
+    ];
+
+    fn counter() -> Env {
+        // Five instructions a frame, one pass through the loop.
+        let config = RomConfig { tickrate: Some(5), ..RomConfig::default() };
+        Env::new(&Rom::new(&COUNTER), config).unwrap().with_score(bcd_score(0x300)).with_done(pc_at(0x210))
+    }
+
+    #[test]
+    fn test_env() {
+        let mut env = counter();
+        assert_eq!(env.reset(1).len(), 64 * 32, "Wrong observation size");
+
+        let step = env.step(0, 10);
+        assert_eq!((step.reward, step.done), (0.0, false), "Reward without pressing the key");
+        let step = env.step(1 << 5, 1);
+        assert_eq!((step.reward, step.done), (1.0, false), "Key not held for the step");
+        let step = env.step(1 << 5, 100);
+        assert_eq!((step.reward, step.done), (2.0, true), "Episode did not end");
+
+        env.reset(1);
+        assert_eq!((env.cpu().v[0x1], env.cpu().pc), (0, 0x200), "Reset did not start over");
+    }
+
+    #[test]
+    fn test_pc_at_watched() {
+        // ADD V1, 1 at 0x208 runs while key 5 is down, never at the end of
+        // a frame.
+        let mut env = counter().with_done(pc_at(0x208)).watch_pc(0x208);
+        env.reset(1);
+        assert!(!env.step(0, 3).done, "Episode ended without passing 0x208");
+        assert!(env.step(1 << 5, 1).done, "Passing 0x208 during the frame missed");
+        env.reset(1);
+        assert!(!env.step(0, 1).done, "Hit kept after reset");
+    }
+
+    #[test]
+    fn test_vec_env() {
+        fn assert_send<T: Send>() {}
+        assert_send::<CPU>();
+        assert_send::<Env>();
+
+        let mut envs = VecEnv::new((0..4).map(|_| counter()).collect());
+        envs.reset(&[1, 2, 3, 4]);
+        let steps = envs.step(&[0, 1 << 5, 0, 1 << 5], 1);
+        let rewards = steps.iter().map(|step| step.reward).collect::<Vec<_>>();
+        assert_eq!(rewards, [0.0, 1.0, 0.0, 1.0], "Actions not applied per environment");
+        envs.step(&[1 << 5; 4], 1);
+
+        let envs = envs.into_envs();
+        let counts = envs.iter().map(|env| env.cpu().v[0x1]).collect::<Vec<_>>();
+        assert_eq!(counts, [1, 2, 1, 2], "Workers did not keep their environments between steps");
+    }
+
+    #[test]
+    #[should_panic(expected = "Need one action per environment")]
+    fn test_vec_env_actions() {
+        let mut envs = VecEnv::new(vec![counter(), counter()]);
+        envs.step(&[0], 1);
+    }
+}
@@ -0,0 +1,76 @@
+//! The HP-48 RPL user flags. SUPER-CHIP programs save high scores there
+//! with FX75 and read them back with FX85; XO-CHIP has 16 instead of 8.
+//! Unlike the rest of the machine they outlive the program, so frontends
+//! persist them per ROM: after `take_flags_changed` says FX75 ran, they
+//! store `CPU::flags` under the ROM's SHA-1 (`file_name` on disk) and
+//! restore them with `set_flags` after loading the ROM again.
+
+use crate::bus::Bus;
+use crate::cpu::{Byte, CPU};
+
+/// Flags on the platform with the most.
+pub const COUNT: usize = 16;
+
+/// The name of the flags file for the ROM with the given SHA-1.
+pub fn file_name(sha1: &str) -> String {
+    format!("{}.flags", sha1)
+}
+
+impl<B: Bus> CPU<B> {
+    /// FX75: V0 through VX into the flags, as many as the platform has.
+    pub(crate) fn store_flags(&mut self, x: u8) {
+        let len = (x as usize + 1).min(self.platform.flag_count());
+        self.flags[..len].copy_from_slice(&self.v[..len]);
+        self.flags_changed = true;
+    }
+
+    /// FX85: the flags into V0 through VX.
+    pub(crate) fn load_flags(&mut self, x: u8) {
+        let len = (x as usize + 1).min(self.platform.flag_count());
+        self.v[..len].copy_from_slice(&self.flags[..len]);
+    }
+
+    /// Restores saved flags, missing ones are 0.
+    pub fn set_flags(&mut self, flags: &[Byte]) {
+        self.flags = [0; COUNT];
+        let len = flags.len().min(COUNT);
+        self.flags[..len].copy_from_slice(&flags[..len]);
+    }
+
+    /// Whether FX75 ran since the last call, so the flags need saving.
+    pub fn take_flags_changed(&mut self) -> bool {
+        std::mem::take(&mut self.flags_changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::decode;
+    use crate::platform::Platform;
+
+    #[test]
+    fn test_flags() {
+        let mut cpu = CPU::default();
+        cpu.set_platform(Platform::SuperChip);
+        cpu.v = core::array::from_fn(|i| i as Byte + 1);
+        cpu.exec(decode(0xF275));
+        assert_eq!(cpu.flags[..4], [1, 2, 3, 0], "FX75 did not store V0 through VX");
+        assert!(cpu.take_flags_changed(), "FX75 not reported");
+        assert!(!cpu.take_flags_changed(), "Change reported twice");
+
+        cpu.exec(decode(0xFF75));
+        assert_eq!(cpu.flags[7..9], [8, 0], "More than 8 flags on SUPER-CHIP");
+        cpu.set_platform(Platform::XoChip);
+        cpu.exec(decode(0xFF75));
+        assert_eq!(cpu.flags[15], 16, "XO-CHIP has 16 flags");
+
+        let saved = cpu.flags;
+        cpu.set_flags(&[]);
+        cpu.reset();
+        cpu.set_flags(&saved);
+        cpu.v = [0; 16];
+        cpu.exec(decode(0xF185));
+        assert_eq!(cpu.v[..3], [1, 2, 0], "FX85 did not load V0 through VX");
+    }
+}
@@ -0,0 +1,68 @@
+//! Two-page hires CHIP-8, a VIP interpreter variant with a 64x64 display.
+//! Its programs start with `JP 0x260`, which entered the interpreter's
+//! display setup in machine code before continuing at 0x2C0, and clear the
+//! screen with 0230.
+
+use crate::cpu::{Byte, Word};
+use crate::decode::{decode as decode_chip8, Instruction};
+
+/// The platform id in the ROM database.
+pub const PLATFORM: &str = "hiresChip8";
+
+pub const HEIGHT: usize = 64;
+/// The first opcode of every hires program.
+pub const STARTUP_JUMP: Word = 0x1260;
+/// Where the CHIP-8 part of a hires program starts.
+pub const START: Word = 0x2C0;
+pub const CLS: Word = 0x0230;
+
+/// Whether `rom` starts like a hires program.
+pub fn detect(rom: &[Byte]) -> bool {
+    rom.starts_with(&STARTUP_JUMP.to_be_bytes())
+}
+
+/// CHIP-8 with 0230 as CLS. The startup jump goes straight to the program,
+/// as the machine code at 0x260 cannot run here.
+pub fn decode(ins: Word) -> Instruction {
+    match ins {
+        STARTUP_JUMP => Instruction::Jp(START),
+        CLS => Instruction::Cls,
+        _ => decode_chip8(ins),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+    use crate::database::Database;
+    use crate::platform::Platform;
+    use crate::rom::Rom;
+
+    #[test]
+    fn test_hires() {
+        // JP 0x260, then at 0x2C0: LD V0, 63; LD I, 0x2CA; DRW V0, V0, 1; 0230; JP 0x2C8
+        let mut data = vec![0; 0xCB];
+        data[..2].copy_from_slice(&[0x12, 0x60]);
+        data[0xC0..].copy_from_slice(&[0x60, 0x3F, 0xA2, 0xCA, 0xD0, 0x01, 0x02, 0x30, 0x12, 0xC8, 0x80]);
+        let rom = Rom::new(&data);
+        assert!(detect(&rom.data), "Hires program not detected");
+
+        let config = rom.config(Database::embedded());
+        assert_eq!(config.platform.as_deref(), Some(PLATFORM), "Unknown hires ROM not run as hires");
+        let mut cpu = CPU::default();
+        cpu.load_rom(&rom, &config).unwrap();
+        assert_eq!(cpu.display.height(), 64, "Display not 64x64");
+
+        cpu.execute();
+        assert_eq!(cpu.pc, START, "Startup jump not redirected");
+        // The VIP display wait holds 0230 back until the next frame.
+        cpu.run_frame();
+        assert!(cpu.display.get(63, 63), "Sprite not drawn on the lower page");
+        cpu.run_frame();
+        assert!(!cpu.display.get(63, 63), "0230 did not clear the screen");
+
+        cpu.load_rom(&Rom::new(&[0x00, 0xE0]), &Database::embedded().platform_config("originalChip8").unwrap()).unwrap();
+        assert_eq!((cpu.platform(), cpu.display.height()), (Platform::Chip8, 32), "Hires mode kept on another platform");
+    }
+}
@@ -0,0 +1,78 @@
+//! Image encoding for sprite sheets and screenshots.
+
+/// An RGB image, 3 bytes per pixel, row by row.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub rgb: Vec<u8>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize, color: [u8; 3]) -> Self {
+        Image { width, height, rgb: color.repeat(width * height) }
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, color: [u8; 3]) {
+        let offset = (y * self.width + x) * 3;
+        self.rgb[offset..offset + 3].copy_from_slice(&color);
+    }
+
+    /// Fills the `scale` x `scale` square for the pixel at (`x`, `y`) of a
+    /// picture scaled up by `scale`.
+    pub fn set_scaled(&mut self, x: usize, y: usize, scale: usize, color: [u8; 3]) {
+        for dy in 0..scale {
+            for dx in 0..scale {
+                self.set(x * scale + dx, y * scale + dy, color);
+            }
+        }
+    }
+
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut encoder = png::Encoder::new(&mut out, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        // Writing into a Vec only fails for a wrongly sized image.
+        let mut writer = encoder.write_header().expect("Invalid image size");
+        writer.write_image_data(&self.rgb).expect("Image data does not match its size");
+        writer.finish().expect("Invalid image size");
+        out
+    }
+}
+
+/// Parses a "#rrggbb" colour.
+pub fn parse_color(color: &str) -> Option<[u8; 3]> {
+    let hex = color.strip_prefix('#')?;
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_png() {
+        let mut image = Image::new(4, 2, [0, 0, 0]);
+        image.set_scaled(1, 0, 2, [255, 255, 0]);
+        assert_eq!(&image.rgb[6..9], [255, 255, 0], "Scaled pixel not filled");
+        assert_eq!(&image.rgb[12 + 9..12 + 12], [255, 255, 0], "Scaled pixel not filled");
+
+        let png = image.to_png();
+        assert_eq!(&png[1..4], b"PNG", "Not a PNG");
+        let decoder = png::Decoder::new(&png[..]);
+        let reader = decoder.read_info().unwrap();
+        assert_eq!((reader.info().width, reader.info().height), (4, 2), "Wrong size");
+    }
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!(parse_color("#ff8000"), Some([255, 128, 0]));
+        assert_eq!(parse_color("ff8000"), None);
+        assert_eq!(parse_color("#ff80"), None);
+    }
+}
@@ -2,49 +2,158 @@
 #![allow(deprecated)]
 use wasm_bindgen::prelude::*;
 use web_sys::{CanvasRenderingContext2d, HtmlCanvasElement, console};
-mod cpu;
-use cpu::CPU;
+pub mod analysis;
+pub mod audio;
+pub mod block;
+pub mod bus;
+pub mod capture;
+pub mod cheats;
+pub mod chip8x;
+pub mod clock;
+pub mod cpu;
+pub mod database;
+pub mod decode;
+pub mod display;
+pub mod env;
+pub mod flags;
+pub mod hires;
+pub mod image;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod libretro;
+pub mod megachip;
+pub mod patch;
+pub mod platform;
+pub mod probes;
+pub mod quirks;
+pub mod random;
+pub mod rom;
+pub mod sprites;
+pub mod state;
+use bus::Bus;
+use capture::GifRecorder;
+use cheats::{Comparison, Search};
+use clock::Clock;
+use cpu::{Byte, Word, CPU};
+use database::{Database, RomConfig};
+use platform::Platform;
+use random::VipRandom;
+use rom::Rom;
 
 #[wasm_bindgen]
 pub struct Chip8 {
     cpu: CPU,
-    display: [[bool; CPU::DISP_X]; CPU::DISP_Y]
+    clock: Clock,
+    /// How the loaded ROM is run, from the ROM database.
+    rom: RomConfig,
+    /// SHA-1 of the loaded ROM, which keys its cheats.
+    sha1: String,
+    recorder: Option<GifRecorder>,
+    search: Option<Search>,
 }
 
+impl Default for Chip8 {
+    fn default() -> Self {
+        Chip8::new()
+    }
+}
 
 #[wasm_bindgen]
 impl Chip8 {
+    /// Creates an emulator with a random seed, so every run plays differently.
     #[wasm_bindgen(constructor)]
     pub fn new() -> Chip8 {
-        Chip8 {
-            cpu: CPU::default(),
-            display: [[false; CPU::DISP_X]; CPU::DISP_Y]
-        }
+        Chip8::with_seed(random::random_seed())
+    }
+
+    /// Creates an emulator whose random numbers repeat for the same seed,
+    /// for tests and replays.
+    #[wasm_bindgen]
+    pub fn with_seed(seed: u64) -> Chip8 {
+        let mut cpu = CPU::default();
+        cpu.seed(seed);
+        Chip8 { cpu, clock: Clock::new(), rom: RomConfig::default(), sha1: String::new(), recorder: None, search: None }
     }
 
+    #[wasm_bindgen]
+    pub fn set_seed(&mut self, seed: u64) {
+        self.cpu.seed(seed);
+    }
+
+    /// Switches CXNN to the COSMAC VIP style generator.
+    #[wasm_bindgen]
+    pub fn use_vip_random(&mut self, seed: u64) {
+        self.cpu.rng = Box::new(VipRandom::new(seed));
+    }
+
+    /// Loads the bundled quirks test.
     #[wasm_bindgen]
     pub fn init(&mut self) {
-        self.cpu.init(); // <-- Add safeguard here
+        self.load_rom(include_bytes!("roms/5-quirks.ch8"))
+            .expect("Bundled ROM does not fit into memory");
+    }
+
+    /// Loads a ROM and sets up the quirks, speed and colours the ROM
+    /// database has for it. ROMs not in the database keep the current
+    /// settings.
+    #[wasm_bindgen]
+    pub fn load_rom(&mut self, data: &[u8]) -> Result<(), JsValue> {
+        let rom = Rom::new(data);
+        let config = rom.config(Database::embedded());
+        self.load_rom_with(&rom, config)
+    }
+
+    /// Loads a ROM with an IPS or BPS patch applied, set up as the ROM
+    /// database says for the unpatched ROM.
+    #[wasm_bindgen]
+    pub fn load_rom_patched(&mut self, data: &[u8], patch: &[u8]) -> Result<(), JsValue> {
+        let rom = Rom::new(data);
+        let config = rom.config(Database::embedded());
+        let patched = rom.patched(patch).map_err(|err| JsValue::from_str(&err.to_string()))?;
+        self.load_rom_with(&patched, config)
+    }
+
+    /// Title, authors, platform, tickrate, keys and colours of the loaded
+    /// ROM as JSON. Fields are null for ROMs not in the database.
+    #[wasm_bindgen]
+    pub fn rom_info(&self) -> String {
+        serde_json::to_string(&self.rom).unwrap_or_default()
+    }
+
+    /// Background and foreground colour, as CSS colours.
+    #[wasm_bindgen]
+    pub fn pixel_colors(&self) -> Vec<String> {
+        self.palette().map(|[r, g, b]| format!("#{:02x}{:02x}{:02x}", r, g, b)).to_vec()
     }
 
    #[wasm_bindgen]
     pub fn step(&mut self) {
         self.cpu.execute();
-        self.display = self.cpu.get_display();
     }
 
+   /// Repaints the part of the screen that changed since the last call.
    #[wasm_bindgen]
-   pub fn render(&self, context: CanvasRenderingContext2d, scale: u32) {
-       context.set_fill_style(&JsValue::from_str("black"));
-       context.fill_rect(0.0, 0.0, (64 * scale) as f64, (32 * scale) as f64);
+   pub fn render(&mut self, context: CanvasRenderingContext2d, scale: u32) {
+       let Some(dirty) = self.cpu.display.take_dirty() else {
+           return;
+       };
+       let scale = scale as usize;
+       let (palette, color) = (self.palette(), self.cpu.color.as_ref());
+       let css = |[r, g, b]: [u8; 3]| JsValue::from_str(&format!("#{:02x}{:02x}{:02x}", r, g, b));
+       context.set_fill_style(&css(capture::pixel_color(palette, color, 0, 0, false)));
+       context.fill_rect(
+           (dirty.x * scale) as f64,
+           (dirty.y * scale) as f64,
+           ((dirty.x_end - dirty.x) * scale) as f64,
+           ((dirty.y_end - dirty.y) * scale) as f64,
+       );
 
-       for y in 0..32 {
-           for x in 0..64 {
-               if self.display[y][x] {
-                   context.set_fill_style(&JsValue::from_str("yellow"));
+       for y in dirty.y..dirty.y_end {
+           for x in dirty.x..dirty.x_end {
+               if self.cpu.display.get(x, y) {
+                   context.set_fill_style(&css(capture::pixel_color(palette, color, x, y, true)));
                    context.fill_rect(
-                       (x as u32 * scale) as f64,
-                       (y as u32 * scale) as f64,
+                       (x * scale) as f64,
+                       (y * scale) as f64,
                        scale as f64,
                        scale as f64,
                    );
@@ -53,10 +162,322 @@ impl Chip8 {
        }
    }
 
+   /// Emulates the frames due after `elapsed_ms` of real time, at most a few
+   /// after a long pause. Returns whether the screen changed.
+   #[wasm_bindgen]
+   pub fn run_for(&mut self, elapsed_ms: f64) -> bool {
+       let frames = self.clock.frames_due(elapsed_ms);
+       self.cpu.redraw = false;
+       for _ in 0..frames {
+           self.frame();
+       }
+       self.cpu.redraw
+   }
+
+   /// Emulates one 60 Hz frame. Returns whether the screen changed.
+   #[wasm_bindgen]
+   pub fn run_frame(&mut self) -> bool {
+       self.cpu.redraw = false;
+       self.frame();
+       self.cpu.redraw
+   }
+
+   /// The screen as a PNG in the ROM's colours, each pixel `scale` x
+   /// `scale` large.
+   #[wasm_bindgen]
+   pub fn screenshot(&self, scale: u32) -> Vec<u8> {
+       capture::cpu_screenshot(&self.cpu, self.palette(), scale as usize).to_png()
+   }
+
+   /// Starts recording every frame run from now on, dropping any recording
+   /// in progress.
+   #[wasm_bindgen]
+   pub fn start_recording(&mut self, scale: u32) {
+       self.recorder = Some(GifRecorder::new(self.palette(), scale as usize));
+   }
+
+   /// Ends the recording and returns it as an animated GIF, or nothing if
+   /// there was none.
+   #[wasm_bindgen]
+   pub fn stop_recording(&mut self) -> Vec<u8> {
+       self.recorder.take().map(GifRecorder::finish).unwrap_or_default()
+   }
+
+   #[wasm_bindgen]
+   pub fn is_recording(&self) -> bool {
+       self.recorder.is_some()
+   }
+
+   #[wasm_bindgen]
+   pub fn set_clock_hz(&mut self, hz: u32) {
+       self.cpu.set_clock_hz(hz);
+   }
+
+   #[wasm_bindgen]
+   pub fn clock_hz(&self) -> u32 {
+       self.cpu.clock_hz()
+   }
+
+   /// Runs as many instructions as fit into `duration_ms` at the CPU clock
+   /// and returns how many were executed, at most a few frames' worth after
+   /// a long pause. Meant for a worker loop that is not tied to the page's
+   /// animation frames.
+   #[wasm_bindgen]
+   pub fn step_for(&mut self, duration_ms: f64) -> u32 {
+       let cycles = self.clock.cycles_due(duration_ms, self.cpu.cycles_per_frame);
+       self.cpu.run(cycles)
+   }
+
+   /// Copies the screen into `target` (one byte per pixel, 0 or 1, row by
+   /// row), which may be backed by a `SharedArrayBuffer`. Nothing is copied
+   /// unless the screen changed since the last call; returns whether it did.
+   /// Shares the change tracking with `render`, so use one or the other.
+   #[wasm_bindgen]
+   pub fn export_framebuffer(&mut self, target: &js_sys::Uint8Array) -> bool {
+       if self.cpu.display.take_dirty().is_none() {
+           return false;
+       }
+       let display = &self.cpu.display;
+       let mut pixels = vec![0; display.width() * display.height()];
+       display.write_pixels(&mut pixels);
+       target.subarray(0, pixels.len() as u32).copy_from(&pixels);
+       true
+   }
+
+   #[wasm_bindgen]
+   pub fn display_width(&self) -> usize {
+       self.cpu.display.width()
+   }
+
+   #[wasm_bindgen]
+   pub fn display_height(&self) -> usize {
+       self.cpu.display.height()
+   }
+
+   /// Sets the keypad from a bitmask, bit N for key N.
+   #[wasm_bindgen]
+   pub fn set_keys(&mut self, mask: u16) {
+       self.cpu.set_keys(mask);
+   }
+
+   /// Sets the CHIP-8X second keypad from a bitmask, bit N for key N.
+   #[wasm_bindgen]
+   pub fn set_keys2(&mut self, mask: u16) {
+       self.cpu.set_keys2(mask);
+   }
+
+   /// Reads the key bitmask from `keys[0]` with an atomic load, so another
+   /// thread can update it in a `SharedArrayBuffer` at any time.
+   #[wasm_bindgen]
+   pub fn sync_keys(&mut self, keys: &js_sys::Int32Array) -> Result<(), JsValue> {
+       let mask = js_sys::Atomics::load(keys, 0)?;
+       self.cpu.set_keys(mask as u16);
+       Ok(())
+   }
+
    #[wasm_bindgen]
    pub fn update_keyboard(&mut self, key_states: &[u8]) {
        for (i, &state) in key_states.iter().enumerate().take(16) {
            self.cpu.keyboard[i] = state;
        }
    }
-}
\ No newline at end of file
+
+   /// V0 to VF.
+   #[wasm_bindgen]
+   pub fn registers(&self) -> Vec<Byte> {
+       self.cpu.v.to_vec()
+   }
+
+   #[wasm_bindgen]
+   pub fn i(&self) -> u32 {
+       self.cpu.i
+   }
+
+   #[wasm_bindgen]
+   pub fn pc(&self) -> Word {
+       self.cpu.pc
+   }
+
+   #[wasm_bindgen]
+   pub fn sp(&self) -> Byte {
+       self.cpu.sp
+   }
+
+   /// The return addresses currently on the stack, oldest first.
+   #[wasm_bindgen]
+   pub fn stack(&self) -> Vec<Word> {
+       self.cpu.stack[..self.cpu.sp as usize].to_vec()
+   }
+
+   #[wasm_bindgen]
+   pub fn dt(&self) -> Byte {
+       self.cpu.dt
+   }
+
+   #[wasm_bindgen]
+   pub fn st(&self) -> Byte {
+       self.cpu.st
+   }
+
+   /// The opcode at PC, executed by the next step.
+   #[wasm_bindgen]
+   pub fn opcode(&self) -> Word {
+       self.cpu.opcode()
+   }
+
+   /// A view of the emulated memory without copying. The view is detached
+   /// when the wasm memory grows, so take a new one each time it is used.
+   #[wasm_bindgen]
+   pub fn memory_view(&self) -> js_sys::Uint8Array {
+       // SAFETY: the view is only valid until the next allocation, which the
+       // caller is told above; nothing is allocated while creating it.
+       unsafe { js_sys::Uint8Array::view(self.cpu.memory.bytes()) }
+   }
+
+   /// Address of the emulated memory inside the wasm memory, for building
+   /// views in JavaScript.
+   #[wasm_bindgen]
+   pub fn memory_ptr(&self) -> *const Byte {
+       self.cpu.memory.bytes().as_ptr()
+   }
+
+   #[wasm_bindgen]
+   pub fn memory_len(&self) -> usize {
+       self.cpu.memory.size()
+   }
+
+   #[wasm_bindgen]
+   pub fn set_register(&mut self, index: usize, value: Byte) {
+       self.cpu.v[index & 0xF] = value;
+   }
+
+   #[wasm_bindgen]
+   pub fn set_i(&mut self, value: u32) {
+       self.cpu.i = value;
+   }
+
+   #[wasm_bindgen]
+   pub fn set_pc(&mut self, value: Word) {
+       self.cpu.pc = value;
+   }
+
+   #[wasm_bindgen]
+   pub fn set_dt(&mut self, value: Byte) {
+       self.cpu.dt = value;
+   }
+
+   #[wasm_bindgen]
+   pub fn set_st(&mut self, value: Byte) {
+       self.cpu.st = value;
+   }
+
+   #[wasm_bindgen]
+   pub fn peek(&self, addr: usize) -> Byte {
+       let bytes = self.cpu.memory.bytes();
+       bytes[addr % bytes.len()]
+   }
+
+   /// Writes a byte of memory, dropping any cached code at that address.
+   #[wasm_bindgen]
+   pub fn poke(&mut self, addr: usize, value: Byte) {
+       self.cpu.write_byte(addr, value);
+   }
+
+   /// SHA-1 of the loaded ROM, to store its cheats under.
+   #[wasm_bindgen]
+   pub fn rom_sha1(&self) -> String {
+       self.sha1.clone()
+   }
+
+   /// Replaces the cheats with the codes in `text`, see `cheats`.
+   #[wasm_bindgen]
+   pub fn set_cheats(&mut self, text: &str) -> Result<(), JsValue> {
+       self.cpu.cheats = cheats::parse(text).map_err(|err| JsValue::from_str(&err.to_string()))?;
+       Ok(())
+   }
+
+   /// The active cheats as a cheat file.
+   #[wasm_bindgen]
+   pub fn cheats(&self) -> String {
+       cheats::to_text(&self.cpu.cheats)
+   }
+
+   /// The RPL user flags, to store under `rom_sha1` when
+   /// `take_flags_changed` says they changed.
+   #[wasm_bindgen]
+   pub fn flags(&self) -> Vec<Byte> {
+       self.cpu.flags.to_vec()
+   }
+
+   /// Restores the flags saved for the loaded ROM.
+   #[wasm_bindgen]
+   pub fn set_flags(&mut self, flags: &[Byte]) {
+       self.cpu.set_flags(flags);
+   }
+
+   #[wasm_bindgen]
+   pub fn take_flags_changed(&mut self) -> bool {
+       self.cpu.take_flags_changed()
+   }
+
+   /// Starts a memory search with every address as a candidate.
+   #[wasm_bindgen]
+   pub fn search_start(&mut self) {
+       self.search = Some(Search::new(self.cpu.memory.bytes()));
+   }
+
+   /// Narrows the search down: `comparison` is "equal", "changed",
+   /// "unchanged", "increased" or "decreased", the last two by `value`.
+   /// Returns how many addresses are left.
+   #[wasm_bindgen]
+   pub fn search_filter(&mut self, comparison: &str, value: Byte) -> Result<usize, JsValue> {
+       let comparison = match comparison {
+           "equal" => Comparison::Equal(value),
+           "changed" => Comparison::Changed,
+           "unchanged" => Comparison::Unchanged,
+           "increased" => Comparison::Increased(value),
+           "decreased" => Comparison::Decreased(value),
+           _ => return Err(JsValue::from_str(&format!("Unknown comparison: {}", comparison))),
+       };
+       let search = self.search.get_or_insert_with(|| Search::new(self.cpu.memory.bytes()));
+       Ok(search.filter(self.cpu.memory.bytes(), comparison))
+   }
+
+   /// Addresses left in the search.
+   #[wasm_bindgen]
+   pub fn search_results(&self) -> Vec<u32> {
+       self.search.as_ref().map_or(Vec::new(), |search| search.candidates().iter().map(|&addr| addr as u32).collect())
+   }
+}
+
+impl Chip8 {
+    /// Loads `rom` with `config`, which may differ from what the database
+    /// says, e.g. to force a platform's quirks. MegaChip ROMs are refused:
+    /// they need 16 MB of memory, and the canvas and the worker's shared
+    /// framebuffer have no room for the 256x192 colour screen.
+    pub fn load_rom_with(&mut self, rom: &Rom, config: RomConfig) -> Result<(), JsValue> {
+        if config.platform.as_deref().map(Platform::from_id) == Some(Platform::MegaChip) {
+            return Err(JsValue::from_str("MegaChip ROMs are not supported on the web page"));
+        }
+        self.cpu.load_rom(rom, &config).map_err(|err| JsValue::from_str(&err.to_string()))?;
+        self.rom = config;
+        if self.sha1 != rom.sha1 {
+            self.cpu.cheats.clear();
+            self.cpu.set_flags(&[]);
+            self.sha1 = rom.sha1.clone();
+        }
+        self.search = None;
+        Ok(())
+    }
+
+    fn palette(&self) -> capture::Palette {
+        capture::palette(self.rom.colors.as_ref())
+    }
+
+    fn frame(&mut self) {
+        self.cpu.run_frame();
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.add_cpu_frame(&self.cpu);
+        }
+    }
+}
@@ -0,0 +1,639 @@
+//! A libretro core, so frontends such as RetroArch can run the interpreter.
+//! The native `cdylib` exports the `retro_*` functions below.
+
+use std::collections::BTreeMap;
+use std::ffi::{c_char, c_uint, c_void, CStr, CString};
+use std::ptr;
+use std::sync::Mutex;
+use crate::bus::{Bus, Ram16M, Ram4K, Ram64K};
+use crate::capture;
+use crate::cheats::{self, Cheat};
+use crate::cpu::CPU;
+use crate::database::{Database, RomConfig};
+use crate::megachip;
+use crate::platform::Platform;
+use crate::quirks::Quirks;
+use crate::random::random_seed;
+use crate::rom::Rom;
+
+pub const RETRO_API_VERSION: c_uint = 1;
+
+const RETRO_DEVICE_JOYPAD: c_uint = 1;
+const RETRO_REGION_NTSC: c_uint = 0;
+const RETRO_MEMORY_SAVE_RAM: c_uint = 0;
+const RETRO_MEMORY_SYSTEM_RAM: c_uint = 2;
+
+const RETRO_ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+const RETRO_ENVIRONMENT_GET_VARIABLE: c_uint = 15;
+const RETRO_ENVIRONMENT_SET_VARIABLES: c_uint = 16;
+const RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE: c_uint = 17;
+const RETRO_PIXEL_FORMAT_XRGB8888: i32 = 1;
+
+const RETRO_DEVICE_ID_JOYPAD_B: usize = 0;
+const RETRO_DEVICE_ID_JOYPAD_UP: usize = 4;
+const RETRO_DEVICE_ID_JOYPAD_DOWN: usize = 5;
+const RETRO_DEVICE_ID_JOYPAD_LEFT: usize = 6;
+const RETRO_DEVICE_ID_JOYPAD_RIGHT: usize = 7;
+const RETRO_DEVICE_ID_JOYPAD_A: usize = 8;
+
+const SAMPLE_RATE: u32 = 48_000;
+
+const OPTION_PROFILE: &CStr = c"chip8_quirk_profile";
+const OPTION_SPEED: &CStr = c"chip8_speed";
+const SPEEDS: &str = "7|9|11|15|20|30|50|100|200|500|1000";
+
+pub type RetroEnvironment = extern "C" fn(cmd: c_uint, data: *mut c_void) -> bool;
+pub type RetroVideoRefresh = extern "C" fn(data: *const c_void, width: c_uint, height: c_uint, pitch: usize);
+pub type RetroAudioSample = extern "C" fn(left: i16, right: i16);
+pub type RetroAudioSampleBatch = extern "C" fn(data: *const i16, frames: usize) -> usize;
+pub type RetroInputPoll = extern "C" fn();
+pub type RetroInputState = extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16;
+
+#[repr(C)]
+pub struct RetroSystemInfo {
+    pub library_name: *const c_char,
+    pub library_version: *const c_char,
+    pub valid_extensions: *const c_char,
+    pub need_fullpath: bool,
+    pub block_extract: bool,
+}
+
+#[repr(C)]
+pub struct RetroGameGeometry {
+    pub base_width: c_uint,
+    pub base_height: c_uint,
+    pub max_width: c_uint,
+    pub max_height: c_uint,
+    pub aspect_ratio: f32,
+}
+
+#[repr(C)]
+pub struct RetroSystemTiming {
+    pub fps: f64,
+    pub sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct RetroSystemAvInfo {
+    pub geometry: RetroGameGeometry,
+    pub timing: RetroSystemTiming,
+}
+
+#[repr(C)]
+pub struct RetroGameInfo {
+    pub path: *const c_char,
+    pub data: *const c_void,
+    pub size: usize,
+    pub meta: *const c_char,
+}
+
+#[repr(C)]
+pub struct RetroVariable {
+    pub key: *const c_char,
+    pub value: *const c_char,
+}
+
+#[derive(Default)]
+struct Callbacks {
+    environment: Option<RetroEnvironment>,
+    video_refresh: Option<RetroVideoRefresh>,
+    audio_sample_batch: Option<RetroAudioSampleBatch>,
+    input_poll: Option<RetroInputPoll>,
+    input_state: Option<RetroInputState>,
+}
+
+impl Callbacks {
+    /// The value of a core option, if the frontend has one.
+    fn variable(&self, key: &CStr) -> Option<String> {
+        let environment = self.environment?;
+        let mut variable = RetroVariable { key: key.as_ptr(), value: ptr::null() };
+        if !environment(RETRO_ENVIRONMENT_GET_VARIABLE, &mut variable as *mut _ as *mut c_void)
+            || variable.value.is_null()
+        {
+            return None;
+        }
+        // SAFETY: the frontend returns a NUL terminated string that stays
+        // valid until the next environment call.
+        Some(unsafe { CStr::from_ptr(variable.value) }.to_string_lossy().into_owned())
+    }
+
+    fn variables_changed(&self) -> bool {
+        let Some(environment) = self.environment else {
+            return false;
+        };
+        let mut updated = false;
+        environment(RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE, &mut updated as *mut _ as *mut c_void) && updated
+    }
+}
+
+/// A loaded game.
+struct Core<B: Bus = Ram4K> {
+    cpu: CPU<B>,
+    rom: Rom,
+    config: RomConfig,
+    /// CHIP-8 key for each RetroPad button.
+    keymap: [u8; 16],
+    palette: capture::Palette,
+    video: Vec<u32>,
+    /// One frame of mono samples.
+    samples: Vec<i16>,
+    /// The same, as interleaved stereo for the frontend.
+    audio: Vec<i16>,
+    /// Enabled cheats by the frontend's index.
+    cheats: BTreeMap<c_uint, Vec<Cheat>>,
+}
+
+impl<B: Bus> Core<B> {
+    fn new(mut cpu: CPU<B>, rom: Rom, config: RomConfig) -> Core<B> {
+        cpu.seed(random_seed());
+        let keymap = keymap(&config.keys);
+        let palette = capture::palette(config.colors.as_ref());
+        Core { cpu, rom, config, keymap, palette, video: Vec::new(), samples: Vec::new(), audio: Vec::new(), cheats: BTreeMap::new() }
+    }
+
+    /// Loads the ROM from the start, set up as the core options ask.
+    fn reset(&mut self, callbacks: &Callbacks) -> bool {
+        if self.cpu.load_rom(&self.rom, &self.config).is_err() {
+            return false;
+        }
+        self.apply_options(callbacks);
+        true
+    }
+
+    fn apply_options(&mut self, callbacks: &Callbacks) {
+        let defaults = CPU::default();
+        self.cpu.quirks = callbacks.variable(OPTION_PROFILE)
+            .and_then(|id| Database::embedded().platform(&id).map(|platform| platform.quirks.apply(Quirks::default())))
+            .or(self.config.quirks)
+            .unwrap_or(defaults.quirks);
+        self.cpu.cycles_per_frame = callbacks.variable(OPTION_SPEED)
+            .and_then(|speed| speed.parse().ok())
+            .or(self.config.tickrate)
+            .unwrap_or(defaults.cycles_per_frame)
+            .max(1);
+    }
+
+    fn run(&mut self, callbacks: &Callbacks) {
+        if callbacks.variables_changed() {
+            self.apply_options(callbacks);
+        }
+
+        if let (Some(poll), Some(state)) = (callbacks.input_poll, callbacks.input_state) {
+            poll();
+            // The second pad is the CHIP-8X second keypad.
+            let mut masks = [0; 2];
+            for (port, mask) in masks.iter_mut().enumerate() {
+                for (button, &key) in self.keymap.iter().enumerate() {
+                    if state(port as c_uint, RETRO_DEVICE_JOYPAD, 0, button as c_uint) != 0 {
+                        *mask |= 1 << key;
+                    }
+                }
+            }
+            self.cpu.set_keys(masks[0]);
+            self.cpu.set_keys2(masks[1]);
+        }
+
+        self.cpu.run_frame();
+
+        if let Some(video_refresh) = callbacks.video_refresh {
+            let (display, color) = (&self.cpu.display, self.cpu.color.as_ref());
+            let mega = self.cpu.mega.as_ref().filter(|mega| mega.enabled);
+            let (width, height) = match mega {
+                Some(_) => (megachip::WIDTH, megachip::HEIGHT),
+                None => (display.width(), display.height()),
+            };
+            self.video.clear();
+            for y in 0..height {
+                for x in 0..width {
+                    let [r, g, b] = match mega {
+                        Some(mega) => mega.pixel(x, y),
+                        None => capture::pixel_color(self.palette, color, x, y, display.get(x, y)),
+                    };
+                    self.video.push(u32::from_be_bytes([0, r, g, b]));
+                }
+            }
+            video_refresh(self.video.as_ptr() as *const c_void, width as c_uint, height as c_uint, width * 4);
+        }
+
+        if let Some(audio_sample_batch) = callbacks.audio_sample_batch {
+            self.samples.clear();
+            self.cpu.render_audio(SAMPLE_RATE, &mut self.samples);
+            self.audio.clear();
+            self.audio.extend(self.samples.iter().flat_map(|&sample| [sample, sample]));
+            audio_sample_batch(self.audio.as_ptr(), self.samples.len());
+        }
+    }
+}
+
+/// A loaded game on as much memory as its platform has, as `run_rom` in
+/// the command line tool picks it.
+enum Machine {
+    Ram4K(Core<Ram4K>),
+    Ram64K(Core<Ram64K>),
+    Ram16M(Core<Ram16M>),
+}
+
+impl Machine {
+    fn new(rom: Rom, config: RomConfig) -> Machine {
+        match config.platform.as_deref().map(Platform::from_id).unwrap_or_default().memory_size() {
+            Ram16M::SIZE => Machine::Ram16M(Core::new(CPU::with_bus(Ram16M::new()), rom, config)),
+            Ram64K::SIZE => Machine::Ram64K(Core::new(CPU::with_bus(Ram64K::new()), rom, config)),
+            _ => Machine::Ram4K(Core::new(CPU::default(), rom, config)),
+        }
+    }
+}
+
+/// Evaluates `$body` with `$core` bound to the machine's `Core`, whatever its bus.
+macro_rules! with_core {
+    ($machine:expr, $core:ident => $body:expr) => {
+        match $machine {
+            Machine::Ram4K($core) => $body,
+            Machine::Ram64K($core) => $body,
+            Machine::Ram16M($core) => $body,
+        }
+    };
+}
+
+static CALLBACKS: Mutex<Callbacks> = Mutex::new(Callbacks {
+    environment: None,
+    video_refresh: None,
+    audio_sample_batch: None,
+    input_poll: None,
+    input_state: None,
+});
+static CORE: Mutex<Option<Machine>> = Mutex::new(None);
+
+/// Maps the RetroPad's 16 buttons onto the 16 keys, with the d-pad on
+/// 5/7/8/9 where most games expect it. `keys` from the ROM database moves
+/// "up", "down", "left", "right", "a" and "b" to the keys the game uses.
+fn keymap(keys: &BTreeMap<String, u8>) -> [u8; 16] {
+    // B, Y, Select, Start, Up, Down, Left, Right, A, X, L, R, L2, R2, L3, R3
+    let mut map = [0x4, 0x2, 0x0, 0xF, 0x5, 0x8, 0x7, 0x9, 0x6, 0x1, 0xA, 0xB, 0xC, 0xD, 0x3, 0xE];
+    for (name, button) in [
+        ("up", RETRO_DEVICE_ID_JOYPAD_UP),
+        ("down", RETRO_DEVICE_ID_JOYPAD_DOWN),
+        ("left", RETRO_DEVICE_ID_JOYPAD_LEFT),
+        ("right", RETRO_DEVICE_ID_JOYPAD_RIGHT),
+        ("a", RETRO_DEVICE_ID_JOYPAD_A),
+        ("b", RETRO_DEVICE_ID_JOYPAD_B),
+    ] {
+        if let Some(&key) = keys.get(name) {
+            map[button] = key & 0xF;
+        }
+    }
+    map
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(environment: RetroEnvironment) {
+    CALLBACKS.lock().unwrap().environment = Some(environment);
+
+    let profiles = Database::embedded().platforms().iter()
+        .map(|platform| platform.id.as_str())
+        .collect::<Vec<_>>()
+        .join("|");
+    let profile = CString::new(format!("Quirk profile; auto|{}", profiles)).unwrap();
+    let speed = CString::new(format!("Instructions per frame; auto|{}", SPEEDS)).unwrap();
+    let mut variables = [
+        RetroVariable { key: OPTION_PROFILE.as_ptr(), value: profile.as_ptr() },
+        RetroVariable { key: OPTION_SPEED.as_ptr(), value: speed.as_ptr() },
+        RetroVariable { key: ptr::null(), value: ptr::null() },
+    ];
+    environment(RETRO_ENVIRONMENT_SET_VARIABLES, variables.as_mut_ptr() as *mut c_void);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(video_refresh: RetroVideoRefresh) {
+    CALLBACKS.lock().unwrap().video_refresh = Some(video_refresh);
+}
+
+/// Unused, audio goes through `retro_set_audio_sample_batch`.
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_audio_sample: RetroAudioSample) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(audio_sample_batch: RetroAudioSampleBatch) {
+    CALLBACKS.lock().unwrap().audio_sample_batch = Some(audio_sample_batch);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(input_poll: RetroInputPoll) {
+    CALLBACKS.lock().unwrap().input_poll = Some(input_poll);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(input_state: RetroInputState) {
+    CALLBACKS.lock().unwrap().input_state = Some(input_state);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    *CORE.lock().unwrap() = None;
+}
+
+/// # Safety
+/// `info` must point to a writable `retro_system_info`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut RetroSystemInfo) {
+    *info = RetroSystemInfo {
+        library_name: c"chip8_rust".as_ptr(),
+        library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+        valid_extensions: c"ch8|c8|rom".as_ptr(),
+        need_fullpath: false,
+        block_extract: false,
+    };
+}
+
+/// # Safety
+/// `info` must point to a writable `retro_system_av_info`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut RetroSystemAvInfo) {
+    let (width, height) = CORE.lock().unwrap().as_ref()
+        .map_or((CPU::DISP_X, CPU::DISP_Y), |machine| with_core!(machine, core => (core.cpu.display.width(), core.cpu.display.height())));
+    *info = RetroSystemAvInfo {
+        geometry: RetroGameGeometry {
+            base_width: width as c_uint,
+            base_height: height as c_uint,
+            max_width: megachip::WIDTH as c_uint,
+            max_height: megachip::HEIGHT as c_uint,
+            aspect_ratio: width as f32 / height as f32,
+        },
+        timing: RetroSystemTiming { fps: 60.0, sample_rate: SAMPLE_RATE as f64 },
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    let callbacks = CALLBACKS.lock().unwrap();
+    if let Some(machine) = CORE.lock().unwrap().as_mut() {
+        with_core!(machine, core => core.reset(&callbacks));
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    let callbacks = CALLBACKS.lock().unwrap();
+    if let Some(machine) = CORE.lock().unwrap().as_mut() {
+        with_core!(machine, core => core.run(&callbacks));
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    CORE.lock().unwrap().as_ref().map_or(0, |machine| with_core!(machine, core => core.cpu.state_size()))
+}
+
+/// # Safety
+/// `data` must point to `size` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    let core = CORE.lock().unwrap();
+    let Some(machine) = core.as_ref() else {
+        return false;
+    };
+    let state = with_core!(machine, core => core.cpu.save_state());
+    if data.is_null() || size < state.len() {
+        return false;
+    }
+    ptr::copy_nonoverlapping(state.as_ptr(), data as *mut u8, state.len());
+    true
+}
+
+/// # Safety
+/// `data` must point to `size` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    let mut core = CORE.lock().unwrap();
+    let Some(machine) = core.as_mut() else {
+        return false;
+    };
+    if data.is_null() {
+        return false;
+    }
+    let state = std::slice::from_raw_parts(data as *const u8, size);
+    with_core!(machine, core => core.cpu.load_state(state).is_ok())
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {
+    if let Some(machine) = CORE.lock().unwrap().as_mut() {
+        with_core!(machine, core => {
+            core.cheats.clear();
+            core.cpu.cheats.clear();
+        });
+    }
+}
+
+/// Codes in the format of `cheats`, others are ignored.
+///
+/// # Safety
+/// `code` must be null or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn retro_cheat_set(index: c_uint, enabled: bool, code: *const c_char) {
+    let mut core = CORE.lock().unwrap();
+    let Some(machine) = core.as_mut() else {
+        return;
+    };
+    let codes = (!code.is_null()).then(|| CStr::from_ptr(code).to_string_lossy()).and_then(|code| cheats::parse(&code).ok());
+    with_core!(machine, core => {
+        match codes {
+            Some(codes) if enabled => core.cheats.insert(index, codes),
+            _ => core.cheats.remove(&index),
+        };
+        core.cpu.cheats = core.cheats.values().flatten().copied().collect();
+    });
+}
+
+/// # Safety
+/// `game` must be null or point to a `retro_game_info` whose `data` holds
+/// `size` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const RetroGameInfo) -> bool {
+    if game.is_null() || (*game).data.is_null() {
+        return false;
+    }
+    let data = std::slice::from_raw_parts((*game).data as *const u8, (*game).size);
+    let rom = Rom::new(data);
+    let config = rom.config(Database::embedded());
+
+    let callbacks = CALLBACKS.lock().unwrap();
+    if let Some(environment) = callbacks.environment {
+        let mut format = RETRO_PIXEL_FORMAT_XRGB8888;
+        if !environment(RETRO_ENVIRONMENT_SET_PIXEL_FORMAT, &mut format as *mut _ as *mut c_void) {
+            return false;
+        }
+    }
+    let mut machine = Machine::new(rom, config);
+    if !with_core!(&mut machine, core => core.reset(&callbacks)) {
+        return false;
+    }
+    *CORE.lock().unwrap() = Some(machine);
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(_game_type: c_uint, _info: *const RetroGameInfo, _num_info: usize) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    *CORE.lock().unwrap() = None;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    RETRO_REGION_NTSC
+}
+
+/// The emulated RAM, for cheats and achievements, and the RPL flags as
+/// save RAM, which the frontend keeps per game.
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    let mut core = CORE.lock().unwrap();
+    let Some(machine) = core.as_mut() else {
+        return ptr::null_mut();
+    };
+    with_core!(machine, core => match id {
+        RETRO_MEMORY_SYSTEM_RAM => core.cpu.memory.bytes_mut().as_mut_ptr() as *mut c_void,
+        RETRO_MEMORY_SAVE_RAM => core.cpu.flags.as_mut_ptr() as *mut c_void,
+        _ => ptr::null_mut(),
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    let core = CORE.lock().unwrap();
+    let Some(machine) = core.as_ref() else {
+        return 0;
+    };
+    with_core!(machine, core => match id {
+        RETRO_MEMORY_SYSTEM_RAM => core.cpu.memory.size(),
+        RETRO_MEMORY_SAVE_RAM => core.cpu.flags.len(),
+        _ => 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+    static LIT_PIXELS: AtomicUsize = AtomicUsize::new(0);
+    static AUDIO_FRAMES: AtomicUsize = AtomicUsize::new(0);
+    static PRESSED: AtomicU32 = AtomicU32::new(0);
+
+    extern "C" fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+        match cmd {
+            RETRO_ENVIRONMENT_GET_VARIABLE => {
+                let variable = unsafe { &mut *(data as *mut RetroVariable) };
+                if unsafe { CStr::from_ptr(variable.key) } == OPTION_SPEED {
+                    variable.value = c"20".as_ptr();
+                }
+                true
+            }
+            RETRO_ENVIRONMENT_SET_PIXEL_FORMAT | RETRO_ENVIRONMENT_SET_VARIABLES => true,
+            _ => false,
+        }
+    }
+
+    extern "C" fn video_refresh(data: *const c_void, width: c_uint, height: c_uint, pitch: usize) {
+        assert_eq!(pitch, width as usize * 4, "Unexpected pitch");
+        let pixels = unsafe { std::slice::from_raw_parts(data as *const u32, (width * height) as usize) };
+        LIT_PIXELS.store(pixels.iter().filter(|&&pixel| pixel != 0).count(), Ordering::SeqCst);
+    }
+
+    extern "C" fn audio_sample_batch(_data: *const i16, frames: usize) -> usize {
+        AUDIO_FRAMES.fetch_add(frames, Ordering::SeqCst);
+        frames
+    }
+
+    extern "C" fn input_poll() {}
+
+    extern "C" fn input_state(_port: c_uint, _device: c_uint, _index: c_uint, id: c_uint) -> i16 {
+        ((PRESSED.load(Ordering::SeqCst) >> id) & 1) as i16
+    }
+
+    /// `f` on the loaded game's CPU, which the test ROMs run on 4K.
+    fn with_cpu<T>(f: impl FnOnce(&CPU) -> T) -> T {
+        match CORE.lock().unwrap().as_ref() {
+            Some(Machine::Ram4K(core)) => f(&core.cpu),
+            _ => panic!("No CHIP-8 game loaded"),
+        }
+    }
+
+    #[test]
+    fn test_keymap() {
+        let mut keys = BTreeMap::new();
+        let mut map = keymap(&keys);
+        map.sort();
+        assert_eq!(map, core::array::from_fn(|key| key as u8), "Default map does not cover every key");
+
+        keys.insert("up".to_string(), 0x2);
+        assert_eq!(keymap(&keys)[RETRO_DEVICE_ID_JOYPAD_UP], 0x2, "Database key not used");
+    }
+
+    #[test]
+    fn test_core() {
+        retro_set_environment(environment);
+        retro_set_video_refresh(video_refresh);
+        retro_set_audio_sample_batch(audio_sample_batch);
+        retro_set_input_poll(input_poll);
+        retro_set_input_state(input_state);
+        retro_init();
+
+        let data = include_bytes!("roms/2-ibm-logo.ch8");
+        let game = RetroGameInfo { path: ptr::null(), data: data.as_ptr() as *const c_void, size: data.len(), meta: ptr::null() };
+        assert!(unsafe { retro_load_game(&game) }, "Game not loaded");
+        assert_eq!(with_cpu(|cpu| cpu.cycles_per_frame), 20, "Speed option not applied");
+
+        for _ in 0..60 {
+            retro_run();
+        }
+        assert!(LIT_PIXELS.load(Ordering::SeqCst) > 0, "Logo not drawn");
+        assert_eq!(AUDIO_FRAMES.load(Ordering::SeqCst), 60 * 800, "Wrong amount of audio");
+
+        PRESSED.store(1 << RETRO_DEVICE_ID_JOYPAD_UP, Ordering::SeqCst);
+        retro_run();
+        assert_eq!(with_cpu(|cpu| cpu.keyboard[0x5]), 1, "Up not mapped to key 5");
+
+        unsafe { retro_cheat_set(0, true, c"VE:2A+3FF:01".as_ptr()) };
+        retro_run();
+        let (v, ram) = with_cpu(|cpu| (cpu.v[0xE], cpu.memory.bytes()[0x3FF]));
+        assert_eq!((v, ram), (0x2A, 0x01), "Cheat not applied");
+        retro_cheat_reset();
+        assert!(with_cpu(|cpu| cpu.cheats.is_empty()), "Cheats not reset");
+
+        let mut state = vec![0u8; retro_serialize_size()];
+        assert!(unsafe { retro_serialize(state.as_mut_ptr() as *mut c_void, state.len()) }, "State not saved");
+        retro_reset();
+        assert!(unsafe { retro_unserialize(state.as_ptr() as *const c_void, state.len()) }, "State not loaded");
+        assert_eq!(retro_get_memory_size(RETRO_MEMORY_SYSTEM_RAM), CPU::MEM_SIZE, "Wrong RAM size");
+        assert_eq!(retro_get_memory_size(RETRO_MEMORY_SAVE_RAM), 16, "RPL flags not saved");
+
+        retro_unload_game();
+        retro_deinit();
+        assert_eq!(retro_serialize_size(), 0, "Game still loaded");
+    }
+
+    #[test]
+    fn test_memory_size() {
+        let data = include_bytes!("roms/2-ibm-logo.ch8");
+        for (platform, size) in [("originalChip8", Ram4K::SIZE), ("xochip", Ram64K::SIZE), ("megachip8", Ram16M::SIZE)] {
+            let config = RomConfig { platform: Some(platform.to_string()), ..RomConfig::default() };
+            let machine = Machine::new(Rom::new(data), config);
+            assert_eq!(with_core!(&machine, core => core.cpu.memory.size()), size, "Wrong memory for {}", platform);
+        }
+    }
+}
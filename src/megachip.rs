@@ -0,0 +1,515 @@
+//! MegaChip, the 256x192 colour extension of SUPER-CHIP. 0011 turns mega
+//! mode on: DXYN then draws sprites of one palette index per byte, sized by
+//! 03NN/04NN and mixed with the screen in the 080N blend mode, and 00E0 shows
+//! the finished frame before clearing for the next. The other 0NNN opcodes
+//! load I with 24 bits, load the palette and play 8-bit sound from memory.
+
+use std::fmt;
+use crate::audio::VOLUME;
+use crate::bus::Bus;
+use crate::cpu::{Byte, Word, CPU};
+use crate::decode::{decode as decode_chip8, Instruction};
+
+/// The platform id in the ROM database.
+pub const PLATFORM: &str = "megachip8";
+
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 192;
+
+/// 1-bit sprites such as font characters are drawn in this colour.
+pub const MONO_COLOR: [u8; 3] = [0xFF; 3];
+
+/// Save state size: mode, palette, sprite size, blend, alpha, collision
+/// colour, the screens and the sound.
+pub(crate) const STATE_SIZE: usize = 1 + 256 * 3 + 5 + WIDTH * HEIGHT * 7 + SAMPLE_STATE_SIZE;
+/// Playing or not, start, length, rate, looping and position.
+const SAMPLE_STATE_SIZE: usize = 1 + 4 + 4 + 4 + 1 + 8;
+
+pub const MEGA_OFF: Word = 0x0010;
+pub const MEGA_ON: Word = 0x0011;
+
+/// The instructions MegaChip adds to CHIP-8.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    /// 0011 and 0010.
+    MegaOn,
+    MegaOff,
+    /// 01NN NNNN: I = NNNNNN, the low 16 bits in the next word.
+    LdILong(Byte),
+    /// 02NN: load NN palette colours from I.
+    LdPalette(Byte),
+    /// 03NN and 04NN: sprite width and height.
+    SprWidth(Byte),
+    SprHeight(Byte),
+    /// 05NN: screen alpha.
+    Alpha(Byte),
+    /// 060N: play the sound at I, once unless N is 0.
+    PlaySound(u8),
+    /// 0700.
+    StopSound,
+    /// 080N: blend mode.
+    Blend(u8),
+    /// 09NN: collision colour.
+    CollisionColor(Byte),
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Op::MegaOn => write!(f, "MEGAON"),
+            Op::MegaOff => write!(f, "MEGAOFF"),
+            Op::LdILong(nn) => write!(f, "LDHI I, 0x{:02X}", nn),
+            Op::LdPalette(nn) => write!(f, "LDPAL {}", nn),
+            Op::SprWidth(nn) => write!(f, "SPRW {}", nn),
+            Op::SprHeight(nn) => write!(f, "SPRH {}", nn),
+            Op::Alpha(nn) => write!(f, "ALPHA 0x{:02X}", nn),
+            Op::PlaySound(n) => write!(f, "DIGISND {}", n),
+            Op::StopSound => write!(f, "STOPSND"),
+            Op::Blend(n) => write!(f, "BMODE {}", n),
+            Op::CollisionColor(nn) => write!(f, "CCOL {}", nn),
+        }
+    }
+}
+
+/// CHIP-8 with the MegaChip 0NNN opcodes.
+pub fn decode(ins: Word) -> Instruction {
+    let nn = (ins & 0xFF) as Byte;
+    let n = (ins & 0xF) as u8;
+    let op = match ins {
+        MEGA_OFF => Op::MegaOff,
+        MEGA_ON => Op::MegaOn,
+        0x0700 => Op::StopSound,
+        _ => match ins & 0xFF00 {
+            0x0100 => Op::LdILong(nn),
+            0x0200 => Op::LdPalette(nn),
+            0x0300 => Op::SprWidth(nn),
+            0x0400 => Op::SprHeight(nn),
+            0x0500 => Op::Alpha(nn),
+            0x0600 if ins & 0xF0 == 0 => Op::PlaySound(n),
+            0x0800 if ins & 0xF0 == 0 => Op::Blend(n),
+            0x0900 => Op::CollisionColor(nn),
+            _ => return decode_chip8(ins),
+        },
+    };
+    Instruction::Mega(op)
+}
+
+/// How sprite pixels are mixed with the screen.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Blend {
+    #[default]
+    Normal,
+    /// 25% sprite, 75% screen.
+    Quarter,
+    Half,
+    Add,
+    Multiply,
+}
+
+impl Blend {
+    fn from_nibble(n: u8) -> Blend {
+        match n {
+            1 => Blend::Quarter,
+            2 => Blend::Half,
+            3 => Blend::Add,
+            4 => Blend::Multiply,
+            _ => Blend::Normal,
+        }
+    }
+
+    fn nibble(self) -> u8 {
+        match self {
+            Blend::Normal => 0,
+            Blend::Quarter => 1,
+            Blend::Half => 2,
+            Blend::Add => 3,
+            Blend::Multiply => 4,
+        }
+    }
+
+    fn mix(self, src: [u8; 3], dst: [u8; 3]) -> [u8; 3] {
+        let mix = |f: fn(u32, u32) -> u32| std::array::from_fn(|c| f(src[c] as u32, dst[c] as u32) as u8);
+        match self {
+            Blend::Normal => src,
+            Blend::Quarter => mix(|s, d| (s + 3 * d) / 4),
+            Blend::Half => mix(|s, d| (s + d) / 2),
+            Blend::Add => mix(|s, d| (s + d).min(255)),
+            Blend::Multiply => mix(|s, d| s * d / 255),
+        }
+    }
+}
+
+/// Digitised sound started by 060N: unsigned 8-bit samples in memory.
+#[derive(Clone, Debug, PartialEq)]
+struct Sample {
+    start: usize,
+    len: usize,
+    rate: u32,
+    looping: bool,
+    position: f64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MegaChip {
+    /// Switched by 0011 and 0010. The other opcodes only work while on.
+    pub enabled: bool,
+    /// Index 0 is transparent in sprites.
+    pub palette: [[u8; 3]; 256],
+    /// 03NN and 04NN, 0 meaning 256.
+    pub sprite_width: Byte,
+    pub sprite_height: Byte,
+    pub blend: Blend,
+    /// 05NN, the screen's opacity. Kept for frontends, not applied.
+    pub alpha: Byte,
+    /// Drawing over a pixel of this palette index sets VF.
+    pub collision_color: Byte,
+    /// Palette index last drawn at each pixel, for collisions.
+    indices: Vec<Byte>,
+    /// The frame being drawn.
+    back: Vec<[u8; 3]>,
+    /// The frame shown, as of the last 00E0.
+    front: Vec<[u8; 3]>,
+    sample: Option<Sample>,
+}
+
+impl Default for MegaChip {
+    fn default() -> Self {
+        MegaChip {
+            enabled: false,
+            palette: [[0; 3]; 256],
+            sprite_width: 0,
+            sprite_height: 0,
+            blend: Blend::Normal,
+            alpha: 0xFF,
+            collision_color: 0,
+            indices: vec![0; WIDTH * HEIGHT],
+            back: vec![[0; 3]; WIDTH * HEIGHT],
+            front: vec![[0; 3]; WIDTH * HEIGHT],
+            sample: None,
+        }
+    }
+}
+
+impl MegaChip {
+    /// A pixel of the frame shown.
+    pub fn pixel(&self, x: usize, y: usize) -> [u8; 3] {
+        self.front[y * WIDTH + x]
+    }
+
+    /// 00E0: shows the frame drawn so far and starts a blank one.
+    pub fn flip(&mut self) {
+        self.front.copy_from_slice(&self.back);
+        self.back.fill([0; 3]);
+        self.indices.fill(0);
+    }
+
+    fn sprite_size(&self) -> (usize, usize) {
+        let size = |n: Byte| if n == 0 { 256 } else { n as usize };
+        (size(self.sprite_width), size(self.sprite_height))
+    }
+
+    /// Puts palette index `index` at (`x`, `y`) in colour `rgb`, clipped at
+    /// the edges. Returns whether it hit the collision colour.
+    fn plot(&mut self, x: usize, y: usize, index: Byte, rgb: [u8; 3]) -> bool {
+        if x >= WIDTH || y >= HEIGHT || index == 0 {
+            return false;
+        }
+        let offset = y * WIDTH + x;
+        let collided = self.indices[offset] == self.collision_color;
+        self.indices[offset] = index;
+        self.back[offset] = self.blend.mix(rgb, self.back[offset]);
+        collided
+    }
+
+    /// Draws a sprite of one palette index per byte, `sprite_width` bytes a
+    /// row. Returns whether it collided.
+    pub fn draw(&mut self, x: usize, y: usize, sprite: &[Byte]) -> bool {
+        let (width, _) = self.sprite_size();
+        let mut collided = false;
+        for (offset, &color) in sprite.iter().enumerate() {
+            collided |= self.plot(x + offset % width, y + offset / width, color, self.palette[color as usize]);
+        }
+        collided
+    }
+
+    /// Draws a 1-bit sprite, such as a font character, in `MONO_COLOR`.
+    /// Its pixels count as palette index 0xFF for collisions.
+    pub fn draw_mono(&mut self, x: usize, y: usize, rows: &[Byte]) -> bool {
+        let mut collided = false;
+        for (row, &bits) in rows.iter().enumerate() {
+            for bit in 0..8 {
+                if bits & (0x80 >> bit) != 0 {
+                    collided |= self.plot(x + bit, y + row, 0xFF, MONO_COLOR);
+                }
+            }
+        }
+        collided
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(STATE_SIZE);
+        out.push(self.enabled as u8);
+        out.extend_from_slice(self.palette.as_flattened());
+        out.extend_from_slice(&[self.sprite_width, self.sprite_height, self.blend.nibble(), self.alpha, self.collision_color]);
+        out.extend_from_slice(&self.indices);
+        out.extend_from_slice(self.back.as_flattened());
+        out.extend_from_slice(self.front.as_flattened());
+        let sample = self.sample.as_ref();
+        out.push(sample.is_some() as u8);
+        let field = |f: fn(&Sample) -> usize| sample.map_or(0, f) as u32;
+        out.extend_from_slice(&field(|sample| sample.start).to_le_bytes());
+        out.extend_from_slice(&field(|sample| sample.len).to_le_bytes());
+        out.extend_from_slice(&field(|sample| sample.rate as usize).to_le_bytes());
+        out.push(sample.is_some_and(|sample| sample.looping) as u8);
+        out.extend_from_slice(&sample.map_or(0.0, |sample| sample.position).to_le_bytes());
+        out
+    }
+
+    /// Reads `to_bytes`' output, which has to be `STATE_SIZE` long.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Self {
+        let mut pos = 0;
+        let mut take = |len: usize| {
+            pos += len;
+            &bytes[pos - len..pos]
+        };
+        let enabled = take(1)[0] != 0;
+        let mut palette = [[0; 3]; 256];
+        palette.as_flattened_mut().copy_from_slice(take(256 * 3));
+        let [sprite_width, sprite_height, blend, alpha, collision_color] = take(5).try_into().unwrap();
+        let indices = take(WIDTH * HEIGHT).to_vec();
+        let mut screen = || take(WIDTH * HEIGHT * 3).chunks_exact(3).map(|rgb| rgb.try_into().unwrap()).collect::<Vec<_>>();
+        let (back, front) = (screen(), screen());
+        let playing = take(1)[0] != 0;
+        let mut number = || u32::from_le_bytes(take(4).try_into().unwrap());
+        let (start, len, rate) = (number() as usize, number() as usize, number());
+        let looping = take(1)[0] != 0;
+        let position = f64::from_le_bytes(take(8).try_into().unwrap());
+        MegaChip {
+            enabled,
+            palette,
+            sprite_width,
+            sprite_height,
+            blend: Blend::from_nibble(blend),
+            alpha,
+            collision_color,
+            indices,
+            back,
+            front,
+            sample: playing.then_some(Sample { start, len, rate, looping, position }),
+        }
+    }
+
+    /// Appends one 60 Hz frame of the playing sound, or returns false if
+    /// there is none.
+    fn render_sample(&mut self, memory: &[Byte], sample_rate: u32, out: &mut Vec<i16>) -> bool {
+        let Some(sample) = self.sample.as_mut() else {
+            return false;
+        };
+        let step = sample.rate as f64 / sample_rate as f64;
+        for _ in 0..sample_rate / 60 {
+            if sample.position as usize >= sample.len {
+                if !sample.looping {
+                    out.push(0);
+                    continue;
+                }
+                sample.position = 0.0;
+            }
+            let byte = memory[(sample.start + sample.position as usize) % memory.len()];
+            out.push((byte as i16 - 128) * (VOLUME / 128));
+            sample.position += step;
+        }
+        if !sample.looping && sample.position as usize >= sample.len {
+            self.sample = None;
+        }
+        true
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    pub fn mega_enabled(&self) -> bool {
+        self.mega.as_ref().is_some_and(|mega| mega.enabled)
+    }
+
+    fn read_bytes(&mut self, addr: usize, len: usize) -> Vec<Byte> {
+        (0..len).map(|offset| self.memory.read(addr + offset)).collect()
+    }
+
+    /// Runs `ins` if it is a MegaChip instruction, or 00E0 or DXYN while
+    /// mega mode is on, see `Platform::exec`.
+    pub(crate) fn exec_megachip(&mut self, ins: Instruction) -> bool {
+        match ins {
+            Instruction::Mega(op) => self.exec_mega(op),
+            Instruction::Cls if self.mega_enabled() => {
+                self.mega.as_mut().unwrap().flip();
+                self.redraw = true;
+            }
+            Instruction::DrwVxVy(vx, vy, n) if self.mega_enabled() => self.draw_mega(vx, vy, n),
+            _ => return false,
+        }
+        true
+    }
+
+    /// The MegaChip 0NNN opcodes. Only 0011 works while mega mode is off.
+    fn exec_mega(&mut self, op: Op) {
+        let i = self.i as usize;
+        let Some(mega) = self.mega.as_mut() else {
+            return;
+        };
+        if !mega.enabled && op != Op::MegaOn {
+            return;
+        }
+        match op {
+            Op::MegaOn => mega.enabled = true,
+            Op::MegaOff => {
+                mega.enabled = false;
+                self.display.mark_all();
+            }
+            Op::LdILong(nn) => {
+                let low = self.fetch();
+                self.i = (nn as u32) << 16 | low as u32;
+            }
+            // ARGB colours into palette 1 to NN.
+            Op::LdPalette(nn) => {
+                let colors = self.read_bytes(i, nn as usize * 4);
+                let mega = self.mega.as_mut().unwrap();
+                for (index, argb) in colors.chunks_exact(4).enumerate() {
+                    mega.palette[index + 1] = [argb[1], argb[2], argb[3]];
+                }
+            }
+            Op::SprWidth(nn) => mega.sprite_width = nn,
+            Op::SprHeight(nn) => mega.sprite_height = nn,
+            Op::Alpha(nn) => mega.alpha = nn,
+            // The sound starts with its rate (16 bits) and length (24 bits)
+            // and a reserved byte.
+            Op::PlaySound(n) => {
+                let header = self.read_bytes(i, 6);
+                let rate = u16::from_be_bytes([header[0], header[1]]) as u32;
+                let len = u32::from_be_bytes([0, header[2], header[3], header[4]]) as usize;
+                let sample = Sample { start: i + 6, len, rate, looping: n == 0, position: 0.0 };
+                self.mega.as_mut().unwrap().sample = Some(sample);
+            }
+            Op::StopSound => mega.sample = None,
+            Op::Blend(n) => mega.blend = Blend::from_nibble(n),
+            Op::CollisionColor(nn) => mega.collision_color = nn,
+        }
+        self.redraw = true;
+    }
+
+    /// DXYN in mega mode. Sprites in the font area are 1-bit and N rows
+    /// high, everything else is a colour sprite.
+    pub(crate) fn draw_mega(&mut self, vx: u8, vy: u8, n: u8) {
+        let (x, y) = (self.v[vx as usize] as usize, self.v[vy as usize] as usize);
+        let i = self.i as usize;
+        let collided = if i < CPU::CHARACTERS.len() {
+            let rows = self.read_bytes(i, n as usize);
+            self.mega.as_mut().unwrap().draw_mono(x, y, &rows)
+        } else {
+            let (width, height) = self.mega.as_ref().unwrap().sprite_size();
+            let sprite = self.read_bytes(i, width * height);
+            self.mega.as_mut().unwrap().draw(x, y, &sprite)
+        };
+        self.v[0xF] = collided as Byte;
+    }
+
+    /// Appends a frame of MegaChip sound if some is playing, see
+    /// `render_audio`.
+    pub(crate) fn render_mega_audio(&mut self, sample_rate: u32, out: &mut Vec<i16>) -> bool {
+        match self.mega.as_mut() {
+            Some(mega) => mega.render_sample(self.memory.bytes(), sample_rate, out),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Ram16M;
+    use crate::platform::Platform;
+
+    fn mega_cpu() -> CPU<Ram16M> {
+        let mut cpu = CPU::with_bus(Ram16M::new());
+        cpu.set_platform(Platform::MegaChip);
+        cpu
+    }
+
+    #[test]
+    fn test_mega_opcodes() {
+        let mut cpu = mega_cpu();
+        // 0011; 0112 3456; 0202; 0302; 0401; 0803; 0905
+        let program = [0x00, 0x11, 0x01, 0x12, 0x34, 0x56, 0x02, 0x02, 0x03, 0x02, 0x04, 0x01, 0x08, 0x03, 0x09, 0x05];
+        cpu.memory.load(0x200, &program);
+        cpu.memory.load(0x12_3456, &[0xFF, 0x10, 0x20, 0x30, 0xFF, 0x01, 0x02, 0x03]);
+        for _ in 0..7 {
+            cpu.execute();
+        }
+
+        let mega = cpu.mega.as_ref().unwrap();
+        assert!(mega.enabled, "0011 did not enable mega mode");
+        assert_eq!(cpu.i, 0x12_3456, "01NN NNNN did not load I");
+        assert_eq!(cpu.pc, 0x210, "Long I load not 4 bytes long");
+        assert_eq!(&mega.palette[1..3], [[0x10, 0x20, 0x30], [0x01, 0x02, 0x03]], "02NN did not load the palette");
+        assert_eq!(mega.sprite_size(), (2, 1), "03NN/04NN did not set the sprite size");
+        assert_eq!((mega.blend, mega.collision_color), (Blend::Add, 5), "080N/09NN not applied");
+    }
+
+    #[test]
+    fn test_draw_mega() {
+        let mut cpu = mega_cpu();
+        let mega = cpu.mega.as_mut().unwrap();
+        mega.enabled = true;
+        mega.palette[1] = [0x80, 0x00, 0x00];
+        mega.palette[2] = [0x00, 0x00, 0x80];
+        mega.sprite_width = 2;
+        mega.sprite_height = 2;
+        mega.collision_color = 1;
+        cpu.memory.load(0x300, &[1, 0, 2, 1]);
+        cpu.i = 0x300;
+        cpu.v[0x0] = 255;
+        cpu.v[0x1] = 10;
+
+        cpu.exec(Instruction::DrwVxVy(0x0, 0x1, 0));
+        assert_eq!(cpu.v[0xF], 0, "Collision on an empty screen");
+        cpu.mega.as_mut().unwrap().blend = Blend::Add;
+        cpu.exec(Instruction::DrwVxVy(0x0, 0x1, 0));
+        assert_eq!(cpu.v[0xF], 1, "No collision with the collision colour");
+
+        let mega = cpu.mega.as_ref().unwrap();
+        assert_eq!(mega.pixel(255, 10), [0; 3], "Drawn frame shown before 00E0");
+        cpu.exec(Instruction::Cls);
+        let mega = cpu.mega.as_ref().unwrap();
+        assert_eq!(mega.pixel(255, 10), [0xFF, 0x00, 0x00], "Additive blend wrong");
+        assert_eq!(mega.pixel(255, 11), [0x00, 0x00, 0xFF], "Second row wrong");
+        assert_eq!(mega.back[10 * WIDTH + 255], [0; 3], "Back buffer not cleared");
+    }
+
+    #[test]
+    fn test_draw_mono() {
+        let mut mega = MegaChip { enabled: true, ..MegaChip::default() };
+        mega.palette[0xFF] = [0x12, 0x34, 0x56];
+        mega.draw_mono(0, 0, &[0x80]);
+        mega.flip();
+        assert_eq!(mega.pixel(0, 0), MONO_COLOR, "1-bit sprite not drawn in white");
+        assert_eq!(mega.palette[0xFF], [0x12, 0x34, 0x56], "Palette changed by a 1-bit sprite");
+    }
+
+    #[test]
+    fn test_mega_sound() {
+        let mut cpu = mega_cpu();
+        cpu.mega.as_mut().unwrap().enabled = true;
+        // 6000 Hz, 150 samples, played once.
+        cpu.memory.load(0x400, &[0x17, 0x70, 0x00, 0x00, 150, 0]);
+        cpu.memory.load(0x406, &[0xFF; 150]);
+        cpu.i = 0x400;
+        cpu.exec(decode(0x0601));
+
+        let mut out = Vec::new();
+        cpu.render_audio(6_000, &mut out);
+        assert!(out.iter().all(|&sample| sample == 127 * (VOLUME / 128)), "Sample not played");
+        out.clear();
+        cpu.render_audio(6_000, &mut out);
+        assert!(out[..50].iter().all(|&sample| sample != 0), "Sample cut short");
+        assert!(out[50..].iter().all(|&sample| sample == 0), "Sample played past its end");
+        assert!(cpu.mega.as_ref().unwrap().sample.is_none(), "Finished sample kept");
+    }
+}
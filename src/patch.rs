@@ -0,0 +1,343 @@
+//! IPS and BPS patches, the formats fan translations and fixes ship in.
+//! IPS lists bytes to overwrite; BPS builds the new ROM from pieces of the
+//! old one and checks CRC-32s of both and of the patch itself.
+
+use std::fmt;
+use crate::bus::Ram16M;
+use crate::cpu::Byte;
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+/// Records are at most this long.
+const IPS_MAX_RECORD: usize = 0xFFFF;
+const BPS_MAGIC: &[u8] = b"BPS1";
+/// Source, target and patch CRC-32.
+const BPS_FOOTER: usize = 12;
+/// No ROM is larger than the largest memory.
+const MAX_ROM: usize = Ram16M::SIZE;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PatchError {
+    /// Neither IPS nor BPS.
+    UnknownFormat,
+    /// The patch ends in the middle of a record or action.
+    Truncated,
+    /// The patch reads or writes past the end of a ROM.
+    OutOfRange,
+    /// The BPS patch is corrupt.
+    PatchChecksum,
+    /// The BPS patch is for another ROM.
+    SourceChecksum,
+    /// Applying the BPS patch did not give the ROM it was made from.
+    TargetChecksum,
+    /// The ROM is too large for an IPS patch, or a BPS patch makes one
+    /// larger than any memory.
+    TooLarge,
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "Not an IPS or BPS patch"),
+            PatchError::Truncated => write!(f, "Patch is truncated"),
+            PatchError::OutOfRange => write!(f, "Patch goes past the end of the ROM"),
+            PatchError::PatchChecksum => write!(f, "Patch checksum does not match, the patch is corrupt"),
+            PatchError::SourceChecksum => write!(f, "ROM checksum does not match, the patch is for another ROM"),
+            PatchError::TargetChecksum => write!(f, "Patched ROM checksum does not match"),
+            PatchError::TooLarge => write!(f, "ROM is too large for the patch or for memory"),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+/// `rom` with the IPS or BPS `patch` applied.
+pub fn apply(rom: &[Byte], patch: &[u8]) -> Result<Vec<Byte>, PatchError> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(rom, patch)
+    } else {
+        Err(PatchError::UnknownFormat)
+    }
+}
+
+/// Reads the patch front to back.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        let end = self.pos.checked_add(len).ok_or(PatchError::Truncated)?;
+        let bytes = self.data.get(self.pos..end).ok_or(PatchError::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// A big-endian number, as IPS stores them.
+    fn number(&mut self, len: usize) -> Result<usize, PatchError> {
+        Ok(self.bytes(len)?.iter().fold(0, |n, &byte| (n << 8) | byte as usize))
+    }
+
+    /// A BPS variable-length number: 7 bits per byte, the last one has the
+    /// top bit set.
+    fn varint(&mut self) -> Result<usize, PatchError> {
+        let (mut n, mut shift) = (0usize, 1usize);
+        loop {
+            let byte = self.bytes(1)?[0];
+            n = ((byte & 0x7F) as usize).checked_mul(shift)
+                .and_then(|value| n.checked_add(value))
+                .ok_or(PatchError::OutOfRange)?;
+            if byte & 0x80 != 0 {
+                return Ok(n);
+            }
+            shift = shift.checked_mul(1 << 7).ok_or(PatchError::OutOfRange)?;
+            n = n.checked_add(shift).ok_or(PatchError::OutOfRange)?;
+        }
+    }
+}
+
+fn apply_ips(rom: &[Byte], patch: &[u8]) -> Result<Vec<Byte>, PatchError> {
+    let mut out = rom.to_vec();
+    let mut reader = Reader { data: patch, pos: IPS_MAGIC.len() };
+    loop {
+        if reader.bytes(IPS_EOF.len())? == IPS_EOF {
+            break;
+        }
+        reader.pos -= IPS_EOF.len();
+        let offset = reader.number(3)?;
+        let (len, fill) = match reader.number(2)? {
+            // Run-length encoded: a count and the byte to repeat.
+            0 => {
+                let len = reader.number(2)?;
+                (len, Some(reader.bytes(1)?[0]))
+            }
+            len => (len, None),
+        };
+        if out.len() < offset + len {
+            out.resize(offset + len, 0);
+        }
+        match fill {
+            Some(byte) => out[offset..offset + len].fill(byte),
+            None => out[offset..offset + len].copy_from_slice(reader.bytes(len)?),
+        }
+    }
+    // Some tools append the size to truncate the ROM to.
+    if let Ok(len) = reader.number(3) {
+        out.truncate(len);
+    }
+    Ok(out)
+}
+
+fn apply_bps(rom: &[Byte], patch: &[u8]) -> Result<Vec<Byte>, PatchError> {
+    let footer = patch.len().checked_sub(BPS_FOOTER).filter(|&end| end >= BPS_MAGIC.len()).ok_or(PatchError::Truncated)?;
+    let crc = |index: usize| u32::from_le_bytes(patch[footer + index * 4..footer + index * 4 + 4].try_into().unwrap());
+    if crc32(&patch[..patch.len() - 4]) != crc(2) {
+        return Err(PatchError::PatchChecksum);
+    }
+    if crc32(rom) != crc(0) {
+        return Err(PatchError::SourceChecksum);
+    }
+
+    let mut reader = Reader { data: &patch[..footer], pos: BPS_MAGIC.len() };
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    let metadata = reader.varint()?;
+    reader.bytes(metadata)?;
+    if source_size != rom.len() {
+        return Err(PatchError::SourceChecksum);
+    }
+    if target_size > MAX_ROM {
+        return Err(PatchError::TooLarge);
+    }
+
+    let mut out = Vec::with_capacity(target_size);
+    let (mut source_offset, mut target_offset) = (0usize, 0usize);
+    while reader.pos < footer {
+        let command = reader.varint()?;
+        let len = (command >> 2) + 1;
+        if len > target_size - out.len() {
+            return Err(PatchError::OutOfRange);
+        }
+        match command & 3 {
+            // Source read: the same bytes as in the ROM.
+            0 => out.extend_from_slice(rom.get(out.len()..out.len() + len).ok_or(PatchError::OutOfRange)?),
+            // Target read: new bytes from the patch.
+            1 => out.extend_from_slice(reader.bytes(len)?),
+            // Source copy: bytes from elsewhere in the ROM.
+            2 => {
+                source_offset = relative(source_offset, reader.varint()?)?;
+                let source = source_offset.checked_add(len).and_then(|end| rom.get(source_offset..end));
+                out.extend_from_slice(source.ok_or(PatchError::OutOfRange)?);
+                source_offset += len;
+            }
+            // Target copy: bytes already written, which may overlap.
+            _ => {
+                target_offset = relative(target_offset, reader.varint()?)?;
+                for _ in 0..len {
+                    let byte = *out.get(target_offset).ok_or(PatchError::OutOfRange)?;
+                    out.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+    if out.len() != target_size || crc32(&out) != crc(1) {
+        return Err(PatchError::TargetChecksum);
+    }
+    Ok(out)
+}
+
+/// Moves `offset` by a BPS signed delta, whose lowest bit is the sign.
+fn relative(offset: usize, delta: usize) -> Result<usize, PatchError> {
+    let moved = if delta & 1 != 0 { offset.checked_sub(delta >> 1) } else { offset.checked_add(delta >> 1) };
+    moved.ok_or(PatchError::OutOfRange)
+}
+
+/// An IPS patch that turns `original` into `modified`.
+pub fn create_ips(original: &[Byte], modified: &[Byte]) -> Result<Vec<u8>, PatchError> {
+    // Offsets are 3 bytes and one that reads "EOF" would end the patch.
+    if modified.len() >= 0x45_4F46 {
+        return Err(PatchError::TooLarge);
+    }
+    let mut patch = IPS_MAGIC.to_vec();
+    let mut offset = 0;
+    while offset < modified.len() {
+        if original.get(offset) == Some(&modified[offset]) {
+            offset += 1;
+            continue;
+        }
+        let end = (offset..modified.len())
+            .take_while(|&addr| original.get(addr) != Some(&modified[addr]))
+            .take(IPS_MAX_RECORD)
+            .last()
+            .unwrap_or(offset)
+            + 1;
+        patch.extend_from_slice(&(offset as u32).to_be_bytes()[1..]);
+        patch.extend_from_slice(&((end - offset) as u16).to_be_bytes());
+        patch.extend_from_slice(&modified[offset..end]);
+        offset = end;
+    }
+    patch.extend_from_slice(IPS_EOF);
+    if modified.len() < original.len() {
+        patch.extend_from_slice(&(modified.len() as u32).to_be_bytes()[1..]);
+    }
+    Ok(patch)
+}
+
+/// A BPS patch that turns `original` into `modified`, made of reads from
+/// the ROM where the bytes are unchanged and new bytes elsewhere.
+pub fn create_bps(original: &[Byte], modified: &[Byte]) -> Vec<u8> {
+    let mut patch = BPS_MAGIC.to_vec();
+    push_varint(&mut patch, original.len());
+    push_varint(&mut patch, modified.len());
+    push_varint(&mut patch, 0);
+
+    let unchanged = |addr: usize| original.get(addr) == Some(&modified[addr]);
+    let mut offset = 0;
+    while offset < modified.len() {
+        let same = unchanged(offset);
+        let len = (offset..modified.len()).take_while(|&addr| unchanged(addr) == same).count();
+        push_varint(&mut patch, ((len - 1) << 2) | if same { 0 } else { 1 });
+        if !same {
+            patch.extend_from_slice(&modified[offset..offset + len]);
+        }
+        offset += len;
+    }
+
+    patch.extend_from_slice(&crc32(original).to_le_bytes());
+    patch.extend_from_slice(&crc32(modified).to_le_bytes());
+    let crc = crc32(&patch);
+    patch.extend_from_slice(&crc.to_le_bytes());
+    patch
+}
+
+fn push_varint(out: &mut Vec<u8>, mut n: usize) {
+    loop {
+        let low = (n & 0x7F) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(0x80 | low);
+            return;
+        }
+        out.push(low);
+        n -= 1;
+    }
+}
+
+/// CRC-32 as in zip and PNG.
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926, "Wrong CRC-32");
+    }
+
+    #[test]
+    fn test_ips() {
+        // 0x0002 = AB CD, then 3 times EE at 0x0006.
+        let patch = b"PATCH\x00\x00\x02\x00\x02\xAB\xCD\x00\x00\x06\x00\x00\x00\x03\xEEEOF";
+        assert_eq!(apply(&[0; 4], patch).unwrap(), [0, 0, 0xAB, 0xCD, 0, 0, 0xEE, 0xEE, 0xEE], "IPS not applied");
+        assert_eq!(apply(&[0; 4], &patch[..12]), Err(PatchError::Truncated), "Truncated patch applied");
+        assert_eq!(apply(&[0; 4], b"NOPE"), Err(PatchError::UnknownFormat));
+
+        let original = [1, 2, 3, 4, 5, 6];
+        for modified in [&[1, 9, 9, 4, 5, 6, 7][..], &[1, 2, 3][..], &[0x45; 3][..]] {
+            let patch = create_ips(&original, modified).unwrap();
+            assert_eq!(apply(&original, &patch).unwrap(), modified, "IPS round trip failed for {:?}", modified);
+        }
+    }
+
+    #[test]
+    fn test_bps() {
+        let original = b"CHIP-8 ROM, version 1".to_vec();
+        let modified = b"CHIP-8 ROM, version 2 (fixed)".to_vec();
+        let patch = create_bps(&original, &modified);
+        assert_eq!(apply(&original, &patch).unwrap(), modified, "BPS round trip failed");
+        assert_eq!(apply(b"another ROM", &patch), Err(PatchError::SourceChecksum), "Patch applied to another ROM");
+
+        let mut corrupt = patch.clone();
+        corrupt[6] ^= 1;
+        assert_eq!(apply(&original, &corrupt), Err(PatchError::PatchChecksum), "Corrupt patch applied");
+
+        // Copies from the source and the target: "ABAB" from "AB", then
+        // "BA" out of the source again.
+        let mut patch = b"BPS1\x82\x86\x80".to_vec();
+        push_varint(&mut patch, 1 << 2); // source read "AB"
+        push_varint(&mut patch, (1 << 2) | 3); // target copy "AB" from 0
+        push_varint(&mut patch, 0);
+        push_varint(&mut patch, 2); // source copy "B" from 1
+        push_varint(&mut patch, 1 << 1);
+        push_varint(&mut patch, 2); // source copy "A" from 0
+        push_varint(&mut patch, (2 << 1) | 1);
+        patch.extend_from_slice(&crc32(b"AB").to_le_bytes());
+        patch.extend_from_slice(&crc32(b"ABABBA").to_le_bytes());
+        let crc = crc32(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        assert_eq!(apply(b"AB", &patch).unwrap(), b"ABABBA", "Copy actions not applied");
+
+        // A target size of 2^64 and more, and one larger than any memory.
+        let bps = |sizes: &[u8]| {
+            let mut patch = [b"BPS1", sizes, b"\x80"].concat();
+            patch.extend_from_slice(&crc32(b"AB").to_le_bytes());
+            patch.extend_from_slice(&[0; 4]);
+            let crc = crc32(&patch);
+            patch.extend_from_slice(&crc.to_le_bytes());
+            patch
+        };
+        assert_eq!(apply(b"AB", &bps(&[[0x82].as_slice(), &[0x7F; 10], &[0x80]].concat())), Err(PatchError::OutOfRange), "Oversized varint read");
+        let mut sizes = vec![0x82];
+        push_varint(&mut sizes, MAX_ROM + 1);
+        assert_eq!(apply(b"AB", &bps(&sizes)), Err(PatchError::TooLarge), "Target larger than memory allowed");
+    }
+}
@@ -0,0 +1,160 @@
+//! The CHIP-8 variants the interpreter runs. A platform owns everything
+//! that differs between them apart from the quirks: which opcodes exist,
+//! the display and memory size, where programs start, the font and the
+//! usual speed. Variant specific state and instructions live in their own
+//! modules (`chip8x`, `hires`, `megachip`), `CPU` only asks the platform.
+
+use crate::bus::{Bus, Ram16M, Ram4K, Ram64K};
+use crate::chip8x::{self, ColorBoard};
+use crate::cpu::{Byte, Word, CPU};
+use crate::decode::{decode, Instruction};
+use crate::display::Framebuffer;
+use crate::flags;
+use crate::hires;
+use crate::megachip::{self, MegaChip};
+use crate::rom::START_ADDRESS;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Platform {
+    /// The VIP's CHIP-8 and the interpreters after it that need no
+    /// instructions of their own, told apart by their quirks only.
+    #[default]
+    Chip8,
+    Hires,
+    /// SUPER-CHIP, which adds the RPL user flags.
+    SuperChip,
+    Chip8X,
+    XoChip,
+    MegaChip,
+}
+
+impl Platform {
+    /// The platform for a ROM database platform id. Ids of interpreters
+    /// without a variant of their own run as plain CHIP-8.
+    pub fn from_id(id: &str) -> Platform {
+        match id {
+            hires::PLATFORM => Platform::Hires,
+            "superchip1" | "superchip" => Platform::SuperChip,
+            chip8x::PLATFORM => Platform::Chip8X,
+            "xochip" => Platform::XoChip,
+            megachip::PLATFORM => Platform::MegaChip,
+            _ => Platform::Chip8,
+        }
+    }
+
+    pub fn decode(self, ins: Word) -> Instruction {
+        let decoded = match self {
+            Platform::Hires => hires::decode(ins),
+            Platform::Chip8X => chip8x::decode(ins),
+            Platform::MegaChip => megachip::decode(ins),
+            Platform::Chip8 | Platform::SuperChip | Platform::XoChip => decode(ins),
+        };
+        match decoded {
+            Instruction::LdRVx(_) | Instruction::LdVxR(_) if self.flag_count() == 0 => Instruction::Unknown(ins),
+            decoded => decoded,
+        }
+    }
+
+    /// Runs `ins` if it is one of the platform's own instructions or one it
+    /// changes, and says whether it did. `CPU::exec` runs everything else.
+    pub(crate) fn exec<B: Bus>(self, cpu: &mut CPU<B>, ins: Instruction) -> bool {
+        match self {
+            Platform::Chip8X => cpu.exec_chip8x(ins),
+            Platform::MegaChip => cpu.exec_megachip(ins),
+            Platform::Chip8 | Platform::Hires | Platform::SuperChip | Platform::XoChip => false,
+        }
+    }
+
+    /// Rows of the 64 pixel wide display. MegaChip's own screen is in
+    /// `megachip`.
+    pub fn display_height(self) -> usize {
+        match self {
+            Platform::Hires => hires::HEIGHT,
+            _ => CPU::DISP_Y,
+        }
+    }
+
+    /// Bytes of memory programs expect, the size of the bus to run them on.
+    pub fn memory_size(self) -> usize {
+        match self {
+            Platform::XoChip => Ram64K::SIZE,
+            Platform::MegaChip => Ram16M::SIZE,
+            _ => Ram4K::SIZE,
+        }
+    }
+
+    pub fn start_address(self) -> Word {
+        match self {
+            Platform::Chip8X => chip8x::START,
+            _ => START_ADDRESS,
+        }
+    }
+
+    /// Loaded at address 0.
+    pub fn font(self) -> &'static [Byte] {
+        &CPU::CHARACTERS
+    }
+
+    /// RPL user flags for FX75 and FX85, which only exist where there are
+    /// flags.
+    pub fn flag_count(self) -> usize {
+        match self {
+            Platform::Chip8 | Platform::Hires | Platform::Chip8X => 0,
+            Platform::SuperChip | Platform::MegaChip => 8,
+            Platform::XoChip => flags::COUNT,
+        }
+    }
+
+    /// Instructions per frame, for configurations that do not say.
+    pub fn tickrate(self) -> u32 {
+        match self {
+            Platform::Chip8 | Platform::Hires | Platform::Chip8X => 15,
+            Platform::SuperChip => 30,
+            Platform::XoChip => 100,
+            Platform::MegaChip => 1000,
+        }
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    pub fn platform(&self) -> Platform {
+        self.platform
+    }
+
+    /// Switches to `platform`: its decoder, display size, font and state.
+    /// Memory keeps its size, it is chosen with the bus.
+    pub fn set_platform(&mut self, platform: Platform) {
+        self.platform = platform;
+        self.color = (platform == Platform::Chip8X).then(ColorBoard::default);
+        self.mega = (platform == Platform::MegaChip).then(MegaChip::default);
+        if self.display.height() != platform.display_height() {
+            self.display = Framebuffer::new(platform.display_height());
+        }
+        self.load_sprites();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_platform() {
+        assert_eq!(Platform::from_id("chip8x"), Platform::Chip8X);
+        assert_eq!(Platform::from_id("superchip"), Platform::SuperChip);
+        assert_eq!(Platform::from_id("chip48"), Platform::Chip8, "Unsupported variant not run as CHIP-8");
+
+        assert_eq!(Platform::Chip8.decode(0xB123), Instruction::JpV0(0x123));
+        assert_eq!(Platform::Chip8X.decode(0xB123), Instruction::Chip8X(chip8x::Op::ColorRows(0x1, 0x2, 3)), "CHIP-8X decoder not used");
+        assert_eq!(Platform::MegaChip.decode(0x0011), Instruction::Mega(megachip::Op::MegaOn), "MegaChip decoder not used");
+        assert_eq!(Platform::Chip8.decode(0x0011), Instruction::Sys(0x011), "MegaChip opcode outside MegaChip");
+        assert_eq!(Platform::Hires.decode(0x1260), Instruction::Jp(hires::START), "Hires startup jump not decoded");
+        assert_eq!(Platform::SuperChip.decode(0xF375), Instruction::LdRVx(0x3));
+        assert_eq!(Platform::Chip8.decode(0xF375), Instruction::Unknown(0xF375), "FX75 without flags");
+        assert_eq!(Platform::Chip8X.decode(0xF385), Instruction::Unknown(0xF385), "FX85 without flags");
+
+        assert_eq!(Platform::Hires.display_height(), 64);
+        assert_eq!(Platform::MegaChip.memory_size(), 0x100_0000);
+        assert_eq!(Platform::Chip8X.start_address(), 0x300);
+    }
+}
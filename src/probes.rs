@@ -0,0 +1,293 @@
+//! Probes: named values read from the machine after every frame, such as
+//! the score at an address or the lives in a register, for bots and test
+//! scripts. A ROM's probes are defined in JSON:
+//!
+//! ```json
+//! {
+//!   "probes": [
+//!     { "name": "score", "bcd": 768 },
+//!     { "name": "lives", "register": 14 },
+//!     { "name": "level", "byte": 1000 },
+//!     { "name": "gameOver", "pcAt": 826 }
+//!   ],
+//!   "expect": { "level": 2 }
+//! }
+//! ```
+//!
+//! `Probes::update` reports every change as an `Event`. `pcAt` is 1 when
+//! the program ran the instruction at the address during the frame, not
+//! only when PC ends the frame there, once `Probes::watch` set up the CPU.
+//! `expect` lists
+//! values that a run has to reach or pass, so a test can check that a
+//! replayed game gets to level 2 even if it jumps from 1 to 3.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use serde::Deserialize;
+use crate::bus::Bus;
+use crate::cpu::{Word, CPU};
+
+/// Where a probe reads its value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Source {
+    /// The byte at this address.
+    Byte(usize),
+    /// Three BCD digits from this address on, as FX33 writes them.
+    Bcd(usize),
+    /// Register VX.
+    Register(u8),
+    /// 1 if PC reached this address during the last frame, 0 otherwise,
+    /// see `CPU::reached`.
+    PcAt(Word),
+}
+
+impl Source {
+    pub fn read<B: Bus>(self, cpu: &CPU<B>) -> u32 {
+        let memory = cpu.memory.bytes();
+        let byte = |addr: usize| memory[addr % memory.len()] as u32;
+        match self {
+            Source::Byte(addr) => byte(addr),
+            Source::Bcd(addr) => (addr..addr + 3).fold(0, |value, addr| value * 10 + byte(addr)),
+            Source::Register(x) => cpu.v[x as usize & 0xF] as u32,
+            Source::PcAt(addr) => cpu.reached(addr) as u32,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct Probe {
+    pub name: String,
+    #[serde(flatten)]
+    pub source: Source,
+}
+
+/// The probes of one ROM.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+pub struct ProbeSet {
+    pub probes: Vec<Probe>,
+    /// Values probes have to reach or pass during a run, by name.
+    #[serde(default)]
+    pub expect: BTreeMap<String, u32>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ProbeError {
+    Json(String),
+    /// `expect` names a probe that is not defined.
+    UnknownProbe(String),
+}
+
+impl fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProbeError::Json(err) => write!(f, "Invalid probe file: {}", err),
+            ProbeError::UnknownProbe(name) => write!(f, "Expectation for unknown probe: {}", name),
+        }
+    }
+}
+
+impl std::error::Error for ProbeError {}
+
+impl ProbeSet {
+    pub fn parse(json: &str) -> Result<Self, ProbeError> {
+        let set: ProbeSet = serde_json::from_str(json).map_err(|err| ProbeError::Json(err.to_string()))?;
+        if let Some(name) = set.expect.keys().find(|name| !set.probes.iter().any(|probe| &probe.name == *name)) {
+            return Err(ProbeError::UnknownProbe(name.clone()));
+        }
+        Ok(set)
+    }
+}
+
+/// A probe's value changed, or was read for the first time (`old` is
+/// `None`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    /// Frames since the probes were created, counting from 1.
+    pub frame: u32,
+    pub name: String,
+    pub old: Option<u32>,
+    pub new: u32,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.old {
+            Some(old) => write!(f, "frame {}: {} {} -> {}", self.frame, self.name, old, self.new),
+            None => write!(f, "frame {}: {} = {}", self.frame, self.name, self.new),
+        }
+    }
+}
+
+/// Evaluates a `ProbeSet` frame by frame.
+pub struct Probes {
+    set: ProbeSet,
+    values: Vec<Option<u32>>,
+    /// Names of the expectations met so far.
+    met: BTreeSet<String>,
+    frame: u32,
+}
+
+impl Probes {
+    pub fn new(set: ProbeSet) -> Self {
+        Probes { values: vec![None; set.probes.len()], set, met: BTreeSet::new(), frame: 0 }
+    }
+
+    /// Has `cpu` watch the addresses of the `pcAt` probes, so they see PC
+    /// passing through during a frame.
+    pub fn watch<B: Bus>(&self, cpu: &mut CPU<B>) {
+        for probe in &self.set.probes {
+            if let Source::PcAt(addr) = probe.source {
+                cpu.watch_pc(addr);
+            }
+        }
+    }
+
+    /// Reads every probe, to be called after each frame, and returns the
+    /// values that changed.
+    pub fn update<B: Bus>(&mut self, cpu: &CPU<B>) -> Vec<Event> {
+        self.frame += 1;
+        let mut events = Vec::new();
+        for (probe, value) in self.set.probes.iter().zip(self.values.iter_mut()) {
+            let new = probe.source.read(cpu);
+            if *value == Some(new) {
+                continue;
+            }
+            events.push(Event { frame: self.frame, name: probe.name.clone(), old: *value, new });
+            *value = Some(new);
+            if self.set.expect.get(&probe.name).is_some_and(|&expected| new >= expected) {
+                self.met.insert(probe.name.clone());
+            }
+        }
+        events
+    }
+
+    /// The value read by the last `update`.
+    pub fn value(&self, name: &str) -> Option<u32> {
+        let index = self.set.probes.iter().position(|probe| probe.name == name)?;
+        self.values[index]
+    }
+
+    /// Expectations not met yet, with the value they wait for at least.
+    pub fn unmet(&self) -> Vec<(&str, u32)> {
+        self.set.expect.iter()
+            .filter(|(name, _)| !self.met.contains(*name))
+            .map(|(name, &value)| (name.as_str(), value))
+            .collect()
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    /// Records from now on whether the instruction at `addr` runs during a
+    /// frame, for `reached`.
+    pub fn watch_pc(&mut self, addr: Word) {
+        if !self.pc_watch.iter().any(|&(watched, _)| watched == addr) {
+            self.pc_watch.push((addr, false));
+        }
+    }
+
+    /// Whether PC is at `addr` or was there during the frame that ran last,
+    /// or is running. Addresses not given to `watch_pc` are only compared
+    /// with PC.
+    pub fn reached(&self, addr: Word) -> bool {
+        let hit = self.pc_watch.iter().any(|&(watched, hit)| watched == addr && hit);
+        hit || self.pc == addr
+    }
+
+    /// Called with the address of each instruction before it runs.
+    #[inline]
+    pub(crate) fn latch_pc(&mut self, pc: Word) {
+        if !self.pc_watch.is_empty() {
+            self.latch_watched(pc);
+        }
+    }
+
+    fn latch_watched(&mut self, pc: Word) {
+        if self.ticks.is_multiple_of(self.cycles_per_frame) {
+            self.pc_watch.iter_mut().for_each(|(_, hit)| *hit = false);
+        }
+        for (watched, hit) in self.pc_watch.iter_mut() {
+            *hit |= *watched == pc;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::Backend;
+
+    #[test]
+    fn test_parse() {
+        let set = ProbeSet::parse(r#"{"probes": [{"name": "score", "bcd": 768}, {"name": "over", "pcAt": 530}], "expect": {"score": 5}}"#).unwrap();
+        assert_eq!(set.probes[0], Probe { name: "score".to_string(), source: Source::Bcd(0x300) }, "Probe not read");
+        assert_eq!(set.probes[1].source, Source::PcAt(0x212));
+        assert_eq!(set.expect["score"], 5, "Expectation not read");
+
+        let unknown = ProbeSet::parse(r#"{"probes": [], "expect": {"level": 2}}"#);
+        assert_eq!(unknown, Err(ProbeError::UnknownProbe("level".to_string())), "Unknown probe accepted");
+        assert!(matches!(ProbeSet::parse(r#"{"probes": [{"name": "x", "nope": 1}]}"#), Err(ProbeError::Json(_))));
+    }
+
+    #[test]
+    fn test_probes() {
+        let mut cpu = CPU::default();
+        let set = ProbeSet {
+            probes: vec![
+                Probe { name: "score".to_string(), source: Source::Bcd(0x300) },
+                Probe { name: "lives".to_string(), source: Source::Register(0xE) },
+            ],
+            expect: BTreeMap::from([("score".to_string(), 120)]),
+        };
+        let mut probes = Probes::new(set);
+        cpu.v[0xE] = 3;
+        let events = probes.update(&cpu);
+        assert_eq!(events.len(), 2, "First values not reported");
+        assert_eq!(events[1].to_string(), "frame 1: lives = 3");
+
+        assert!(probes.update(&cpu).is_empty(), "Unchanged values reported");
+        assert_eq!(probes.unmet(), [("score", 120)], "Expectation met too early");
+        cpu.memory.load(0x300, &[1, 2, 0]);
+        let events = probes.update(&cpu);
+        assert_eq!(events, [Event { frame: 3, name: "score".to_string(), old: Some(0), new: 120 }], "Score change not reported");
+        assert_eq!(probes.value("score"), Some(120));
+        assert!(probes.unmet().is_empty(), "Expectation not met");
+    }
+
+    #[test]
+    fn test_expect_passed() {
+        let mut cpu = CPU::default();
+        let set = ProbeSet {
+            probes: vec![Probe { name: "score".to_string(), source: Source::Bcd(0x300) }],
+            expect: BTreeMap::from([("score".to_string(), 100)]),
+        };
+        let mut probes = Probes::new(set);
+        probes.update(&cpu);
+        cpu.memory.load(0x300, &[1, 5, 0]);
+        probes.update(&cpu);
+        assert!(probes.unmet().is_empty(), "Score going from 0 to 150 did not meet 100");
+    }
+
+    #[test]
+    fn test_pc_at() {
+        // A loop through 0x202 that ends every frame back at 0x200.
+        let program = [0x60, 0x01, 0x61, 0x01, 0x12, 0x00];
+        for backend in [Backend::Interpreter, Backend::Blocks] {
+            let mut cpu = CPU::default();
+            cpu.backend = backend;
+            cpu.cycles_per_frame = 3;
+            cpu.memory.load(0x200, &program);
+            let probes = Probes::new(ProbeSet::parse(r#"{"probes": [{"name": "loop", "pcAt": 514}, {"name": "never", "pcAt": 518}]}"#).unwrap());
+            probes.watch(&mut cpu);
+            cpu.run_frame();
+            assert_eq!(cpu.pc, 0x200, "Frame did not end at the start of the loop");
+            assert!(cpu.reached(0x202), "PC passing through during the frame missed on {:?}", backend);
+            assert!(!cpu.reached(0x206), "Unreached address reported on {:?}", backend);
+            // JP 0x200 keeps PC away from 0x202 from now on.
+            cpu.memory.load(0x200, &[0x12, 0x00]);
+            cpu.flush_decoded();
+            cpu.run_frame();
+            assert!(!cpu.reached(0x202), "Hit not cleared when the next frame started on {:?}", backend);
+        }
+    }
+}
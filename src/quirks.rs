@@ -0,0 +1,61 @@
+/// What happens to sprite pixels drawn past an edge of the screen. The
+/// starting coordinate always wraps.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Edge {
+    /// Pixels past the edge are dropped, as on most platforms.
+    #[default]
+    Clip,
+    /// Pixels continue on the opposite side, as on XO-CHIP.
+    Wrap,
+}
+
+/// What FX55 and FX65 leave in I.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MemoryQuirk {
+    /// I += X + 1, as on the COSMAC VIP.
+    IncrementByXPlusOne,
+    /// I += X, as on CHIP-48 and SUPER-CHIP 1.0.
+    IncrementByX,
+    /// I is left unchanged, as on SUPER-CHIP 1.1.
+    #[default]
+    Unchanged,
+}
+
+/// Behaviours that differ between CHIP-8 implementations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    /// 8XY1, 8XY2 and 8XY3 reset VF to 0.
+    pub vf_reset: bool,
+    /// 8XY6 and 8XYE shift VX in place instead of storing VY shifted in VX.
+    pub shift: bool,
+    pub memory: MemoryQuirk,
+    /// BXNN jumps to XNN + VX instead of BNNN jumping to NNN + V0.
+    pub jump: bool,
+    /// DXYN waits for the vertical blank, so at most one sprite is drawn
+    /// per frame (COSMAC VIP in lores mode).
+    pub display_wait: bool,
+    /// Sprite behaviour at the left and right edges.
+    pub edge_x: Edge,
+    /// Sprite behaviour at the top and bottom edges.
+    pub edge_y: Edge,
+    /// DXYN sets VF to the number of rows that collided or were clipped at
+    /// the bottom instead of 0 or 1 (SCHIP in hires mode).
+    pub collision_rows: bool,
+}
+
+impl Default for Quirks {
+    /// The interpreter's behaviour before the quirks were configurable,
+    /// except that sprites clip at the edges instead of wrapping.
+    fn default() -> Self {
+        Quirks {
+            vf_reset: true,
+            shift: true,
+            memory: MemoryQuirk::Unchanged,
+            jump: false,
+            display_wait: false,
+            edge_x: Edge::Clip,
+            edge_y: Edge::Clip,
+            collision_rows: false,
+        }
+    }
+}
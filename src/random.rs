@@ -0,0 +1,101 @@
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use crate::cpu::{Byte, Word};
+
+/// Where CXNN gets its random bytes from.
+pub trait RandomSource: Send {
+    /// Returns the next byte. `memory` is the machine's memory, for sources
+    /// that read from it like the original interpreter did.
+    fn next_byte(&mut self, memory: &[Byte]) -> Byte;
+
+    /// Called on every 60 Hz timer tick.
+    fn frame(&mut self) {}
+}
+
+/// A seeded PRNG, the default source.
+pub struct SeededRandom {
+    rng: SmallRng,
+}
+
+impl SeededRandom {
+    pub fn new(seed: u64) -> Self {
+        Self { rng: SmallRng::seed_from_u64(seed) }
+    }
+}
+
+impl RandomSource for SeededRandom {
+    fn next_byte(&mut self, _memory: &[Byte]) -> Byte {
+        self.rng.gen_range(0..=255)
+    }
+}
+
+/// Modelled on the COSMAC VIP interpreter's RND routine. The VIP kept a
+/// 16-bit seed whose low byte the display interrupt incremented every
+/// frame. RND used the high byte as a pointer into the interpreter's own
+/// page of memory, added the byte found there to the low byte and kept the
+/// sum as the new high byte, so the sequence depends on frame timing.
+pub struct VipRandom {
+    seed: Word,
+}
+
+impl VipRandom {
+    pub fn new(seed: u64) -> Self {
+        Self { seed: seed as Word }
+    }
+}
+
+impl RandomSource for VipRandom {
+    fn next_byte(&mut self, memory: &[Byte]) -> Byte {
+        let [high, low] = self.seed.to_be_bytes();
+        let value = memory[high as usize].wrapping_add(low);
+        self.seed = Word::from_be_bytes([value, low]);
+        value
+    }
+
+    fn frame(&mut self) {
+        let [high, low] = self.seed.to_be_bytes();
+        self.seed = Word::from_be_bytes([high, low.wrapping_add(1)]);
+    }
+}
+
+/// A seed from the operating system (or `crypto.getRandomValues` on the web).
+pub fn random_seed() -> u64 {
+    let mut bytes = [0; 8];
+    getrandom::getrandom(&mut bytes).expect("No source of randomness available");
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+
+    fn sequence(cpu: &mut CPU, len: usize) -> Vec<Byte> {
+        (0..len).map(|_| cpu.rand_byte()).collect()
+    }
+
+    #[test]
+    fn test_seeded_random() {
+        let mut a = CPU::default();
+        let mut b = CPU::default();
+        a.seed(1234);
+        b.seed(1234);
+        assert_eq!(sequence(&mut a, 16), sequence(&mut b, 16), "Same seed gave different bytes");
+
+        b.seed(4321);
+        assert_ne!(sequence(&mut a, 16), sequence(&mut b, 16), "Different seeds gave the same bytes");
+    }
+
+    #[test]
+    fn test_vip_random() {
+        let mut memory = [0; 256];
+        memory[0x12] = 0x40;
+        memory[0x53] = 0x07;
+        let mut rng = VipRandom::new(0x1213);
+
+        assert_eq!(rng.next_byte(&memory), 0x53, "Byte at the high seed byte not added to the low byte");
+        assert_eq!(rng.next_byte(&memory), 0x1A, "Sum not kept as the new pointer");
+        rng.frame();
+        assert_eq!(rng.next_byte(&memory), 0x14, "Frame did not advance the low byte");
+    }
+}
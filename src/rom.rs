@@ -0,0 +1,194 @@
+use std::fmt;
+use crate::bus::Bus;
+use crate::chip8x::{self, ColorBoard};
+use crate::hires;
+use crate::cpu::{Byte, Word, CPU};
+use crate::database::{Database, RomConfig, UNKNOWN_PLATFORM};
+use crate::patch::{self, PatchError};
+use crate::platform::Platform;
+
+/// A program image and its SHA-1, which identifies it in the ROM database.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rom {
+    pub data: Vec<Byte>,
+    /// Lowercase hex, as used by the database.
+    pub sha1: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    /// The ROM does not fit between its start address and the end of memory.
+    TooLarge { len: usize, available: usize },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::TooLarge { len, available } => {
+                write!(f, "ROM is {} bytes but only {} fit into memory", len, available)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl Rom {
+    pub fn new(data: &[Byte]) -> Self {
+        Rom { data: data.to_vec(), sha1: sha1_smol::Sha1::from(data).digest().to_string() }
+    }
+
+    /// What the database knows about this ROM, or else the defaults of
+    /// `UNKNOWN_PLATFORM`. Unknown hires programs are recognised by their
+    /// startup jump.
+    pub fn config(&self, db: &Database) -> RomConfig {
+        let platform = if hires::detect(&self.data) { hires::PLATFORM } else { UNKNOWN_PLATFORM };
+        db.lookup(&self.sha1)
+            .or_else(|| db.platform_config(platform))
+            .unwrap_or_default()
+    }
+
+    /// This ROM with an IPS or BPS patch applied. Run it with the base
+    /// ROM's `config`, the database does not know patched ROMs.
+    pub fn patched(&self, patch: &[u8]) -> Result<Rom, PatchError> {
+        Ok(Rom::new(&patch::apply(&self.data, patch)?))
+    }
+}
+
+/// Where programs are loaded unless the platform or the database say
+/// otherwise.
+pub const START_ADDRESS: Word = 0x200;
+
+impl<B: Bus> CPU<B> {
+    /// Resets the machine, applies `config` and loads `rom` at its start
+    /// address. Memory, registers, timers, the stack and the screen start
+    /// out cleared, nothing of a previous program is left.
+    pub fn load_rom(&mut self, rom: &Rom, config: &RomConfig) -> Result<(), LoadError> {
+        let platform = config.platform.as_deref().map(Platform::from_id);
+        let start = config.start_address.unwrap_or(platform.unwrap_or(self.platform).start_address());
+        let available = self.memory.size().saturating_sub(start as usize);
+        if rom.data.len() > available {
+            return Err(LoadError::TooLarge { len: rom.data.len(), available });
+        }
+
+        if let Some(platform) = platform {
+            self.set_platform(platform);
+            self.cycles_per_frame = platform.tickrate();
+        }
+        if let Some(quirks) = config.quirks {
+            self.quirks = quirks;
+        }
+        if let Some(tickrate) = config.tickrate {
+            self.cycles_per_frame = tickrate.max(1);
+        }
+        self.reset();
+        self.memory.bytes_mut().fill(0);
+        self.v = [0; 16];
+        self.stack = [0; 16];
+        (self.dt, self.st) = (0, 0);
+        self.vblank_wait = false;
+        self.display.clear();
+        self.load_sprites();
+        self.memory.load(start as usize, &rom.data);
+        self.flush_decoded();
+        self.pc = start;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quirks::MemoryQuirk;
+
+    #[test]
+    fn test_load_rom() {
+        let rom = Rom::new(include_bytes!("roms/5-quirks.ch8"));
+        assert_eq!(rom.sha1, "e2149cb836131a142ca7e2dc2f2283381ae5faaa", "Wrong hash");
+
+        let mut cpu = CPU::default();
+        cpu.load_rom(&rom, &rom.config(Database::embedded())).unwrap();
+        assert_eq!(cpu.cycles_per_frame, 15, "Tickrate not applied");
+        assert_eq!(cpu.quirks.memory, MemoryQuirk::IncrementByXPlusOne, "Quirks not applied");
+        assert_eq!(&cpu.memory.bytes()[0x200..0x200 + rom.data.len()], &rom.data[..], "ROM not loaded");
+        assert_eq!(cpu.pc, 0x200, "PC not at the start address");
+    }
+
+    #[test]
+    fn test_load_rom_override() {
+        let rom = Rom::new(include_bytes!("roms/5-quirks.ch8"));
+        let mut config = rom.config(Database::embedded());
+        config.tickrate = Some(30);
+        config.quirks.as_mut().unwrap().display_wait = false;
+        config.start_address = Some(0x300);
+
+        let mut cpu = CPU::default();
+        cpu.load_rom(&rom, &config).unwrap();
+        assert_eq!(cpu.cycles_per_frame, 30, "Tickrate not overridden");
+        assert!(!cpu.quirks.display_wait, "Quirk not overridden");
+        assert_eq!(cpu.pc, 0x300, "Start address not overridden");
+    }
+
+    #[test]
+    fn test_load_clears() {
+        let mut cpu = CPU::default();
+        cpu.load_rom(&Rom::new(&[0xAA; 0x100]), &RomConfig::default()).unwrap();
+        cpu.v[0x3] = 7;
+        (cpu.dt, cpu.st, cpu.sp, cpu.stack[0]) = (10, 10, 1, 0x204);
+        cpu.display.set(0, 0, true);
+
+        cpu.load_rom(&Rom::new(&[0x12, 0x00]), &RomConfig::default()).unwrap();
+        assert!(cpu.memory.bytes()[0x202..].iter().all(|&byte| byte == 0), "Previous ROM left in memory");
+        assert_eq!((cpu.v, cpu.dt, cpu.st, cpu.sp, cpu.stack), ([0; 16], 0, 0, 0, [0; 16]), "Registers not cleared");
+        assert!(!cpu.display.get(0, 0), "Screen not cleared");
+        assert_eq!(&cpu.memory.bytes()[..CPU::CHARACTERS.len()], &CPU::CHARACTERS[..], "Font not loaded");
+    }
+
+    #[test]
+    fn test_load_patched() {
+        let rom = Rom::new(include_bytes!("roms/5-quirks.ch8"));
+        let mut data = rom.data.clone();
+        data[1] = 0xFF;
+        let patched = rom.patched(&patch::create_bps(&rom.data, &data)).unwrap();
+        assert_eq!(patched.data, data, "Patch not applied");
+
+        let mut cpu = CPU::default();
+        cpu.load_rom(&patched, &rom.config(Database::embedded())).unwrap();
+        assert_eq!(cpu.memory.bytes()[0x201], 0xFF, "Patched ROM not loaded");
+        assert_eq!(cpu.quirks.memory, MemoryQuirk::IncrementByXPlusOne, "Base ROM's quirks not applied");
+        assert!(rom.patched(b"PATCH").is_err(), "Truncated patch applied");
+    }
+
+    #[test]
+    fn test_load_chip8x() {
+        let rom = Rom::new(&[0x02, 0xA0]);
+        let config = Database::embedded().platform_config(chip8x::PLATFORM).unwrap();
+        let mut cpu = CPU::default();
+        cpu.load_rom(&rom, &config).unwrap();
+        assert_eq!(cpu.pc, 0x300, "CHIP-8X program not at 0x300");
+        assert_eq!(cpu.color, Some(ColorBoard::default()), "No colour board for CHIP-8X");
+
+        cpu.load_rom(&rom, &Database::embedded().platform_config("originalChip8").unwrap()).unwrap();
+        assert_eq!(cpu.color, None, "Colour board kept on another platform");
+    }
+
+    #[test]
+    fn test_load_unknown_rom() {
+        let rom = Rom::new(&[0x12, 0x00]);
+        let config = rom.config(Database::embedded());
+        assert_eq!(config, Database::embedded().platform_config(UNKNOWN_PLATFORM).unwrap(), "Unknown ROM not given the platform defaults");
+
+        let mut cpu = CPU::default();
+        cpu.quirks.shift = true;
+        cpu.load_rom(&rom, &config).unwrap();
+        assert!(!cpu.quirks.shift, "Quirks of the previous ROM kept for an unknown ROM");
+        assert_eq!(cpu.cycles_per_frame, 12, "Platform speed not applied to an unknown ROM");
+
+        let big = Rom::new(&[0; 4000]);
+        assert_eq!(
+            cpu.load_rom(&big, &RomConfig::default()),
+            Err(LoadError::TooLarge { len: 4000, available: 0xE00 }),
+            "Oversized ROM loaded"
+        );
+    }
+}
@@ -0,0 +1,132 @@
+//! Finds the sprites a ROM draws, either statically from the analysis or
+//! from the bytes DXYN actually read while running, and dumps them as text
+//! or a PNG sprite sheet.
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use crate::analysis::Analysis;
+use crate::cpu::{Byte, Word};
+use crate::image::Image;
+
+/// One DXYN as executed, recorded in `CPU::sprite_log`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpriteDraw {
+    /// Address of the DXYN instruction.
+    pub pc: Word,
+    /// I at the time, where the sprite was read from.
+    pub addr: Word,
+    pub bytes: Vec<Byte>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Sprite {
+    pub addr: usize,
+    /// 8, or 16 for SUPER-CHIP's 16x16 sprites.
+    pub width: usize,
+    pub height: usize,
+    /// Rows of `width / 8` bytes each.
+    pub data: Vec<Byte>,
+}
+
+impl Sprite {
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        let byte = self.data[y * self.width / 8 + x / 8];
+        byte & (0x80 >> (x % 8)) != 0
+    }
+}
+
+/// The sprites the analysis found after `LD I`, read from the ROM.
+pub fn from_analysis(analysis: &Analysis) -> Vec<Sprite> {
+    analysis.sprites.iter()
+        .filter_map(|(&addr, &len)| {
+            let data = analysis.bytes(addr, len)?.to_vec();
+            // 32 bytes come from DXY0, a 16x16 sprite.
+            let width = if len == 32 { 16 } else { 8 };
+            Some(Sprite { addr, width, height: len * 8 / width, data })
+        })
+        .collect()
+}
+
+/// Every distinct sprite in `log`, by address.
+pub fn from_draws(log: &[SpriteDraw]) -> Vec<Sprite> {
+    log.iter()
+        .filter(|draw| !draw.bytes.is_empty())
+        .map(|draw| Sprite { addr: draw.addr as usize, width: 8, height: draw.bytes.len(), data: draw.bytes.clone() })
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// Each sprite as a header with its address and size, then one line of
+/// `#` and `.` per row.
+pub fn text_grid(sprites: &[Sprite]) -> String {
+    let mut out = String::new();
+    for sprite in sprites {
+        writeln!(out, "0x{:03X} {}x{}", sprite.addr, sprite.width, sprite.height).unwrap();
+        for y in 0..sprite.height {
+            let row = (0..sprite.width).map(|x| if sprite.pixel(x, y) { '#' } else { '.' }).collect::<String>();
+            writeln!(out, "{}", row).unwrap();
+        }
+        writeln!(out).unwrap();
+    }
+    out
+}
+
+/// All sprites on a grid of 16x16 cells with 1 pixel gaps, `columns`
+/// cells wide, scaled up by `scale`.
+pub fn sprite_sheet(sprites: &[Sprite], columns: usize, scale: usize) -> Image {
+    const CELL: usize = 17;
+    const GAP: [u8; 3] = [0x40, 0x40, 0x40];
+    let columns = columns.max(1);
+    let rows = sprites.len().div_ceil(columns).max(1);
+    let (width, height) = (columns * CELL + 1, rows * CELL + 1);
+
+    let mut image = Image::new(width * scale, height * scale, GAP);
+    for (index, sprite) in sprites.iter().enumerate() {
+        let (left, top) = ((index % columns) * CELL + 1, (index / columns) * CELL + 1);
+        for y in 0..16 {
+            for x in 0..16 {
+                let on = x < sprite.width && y < sprite.height && sprite.pixel(x, y);
+                let color = if on { [0xFF; 3] } else { [0; 3] };
+                image.set_scaled(left + x, top + y, scale, color);
+            }
+        }
+    }
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::analyze;
+    use crate::cpu::CPU;
+    use crate::database::Database;
+    use crate::rom::Rom;
+
+    #[test]
+    fn test_from_analysis() {
+        let rom = include_bytes!("roms/2-ibm-logo.ch8");
+        let sprites = from_analysis(&analyze(rom, 0x200));
+
+        assert_eq!(sprites.len(), 6, "Not every logo sprite found");
+        assert_eq!((sprites[0].addr, sprites[0].width, sprites[0].height), (0x22A, 8, 15), "Wrong sprite");
+        assert!(text_grid(&sprites[..1]).starts_with("0x22A 8x15\n########\n........\n"), "Wrong text grid");
+    }
+
+    #[test]
+    fn test_from_draws() {
+        let rom = Rom::new(include_bytes!("roms/2-ibm-logo.ch8"));
+        let mut cpu = CPU::default();
+        cpu.load_rom(&rom, &rom.config(Database::embedded())).unwrap();
+        cpu.sprite_log = Some(Vec::new());
+        cpu.run(100);
+
+        let log = cpu.sprite_log.take().unwrap();
+        assert_eq!(log.len(), 6, "Draws not recorded");
+        assert_eq!((log[0].pc, log[0].addr), (0x208, 0x22A), "Wrong draw recorded");
+        assert_eq!(from_draws(&log), from_analysis(&analyze(&rom.data, 0x200)), "Drawn sprites differ from the ROM");
+
+        let sheet = sprite_sheet(&from_draws(&log), 4, 2);
+        assert_eq!((sheet.width, sheet.height), ((4 * 17 + 1) * 2, (2 * 17 + 1) * 2), "Wrong sheet size");
+    }
+}
@@ -0,0 +1,245 @@
+//! Save states: the machine state in a fixed binary layout, little endian.
+//! Configuration (quirks, speed, backend), the random source and the RPL
+//! flags, which are persisted on their own, are not part of it.
+
+use std::fmt;
+use crate::bus::Bus;
+use crate::chip8x::{self, ColorBoard};
+use crate::cpu::{Byte, Word, CPU};
+use crate::megachip::{self, MegaChip};
+
+const MAGIC: &[u8; 4] = b"C8SV";
+const VERSION: u8 = 5;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StateError {
+    /// Not a save state, or one from an incompatible version.
+    BadHeader,
+    /// The state is for a machine with a different memory or screen size.
+    WrongSize,
+    /// The state is for another platform, e.g. a MegaChip state on CHIP-8.
+    WrongPlatform,
+    /// The state holds values no machine could be in, e.g. a stack pointer past the stack.
+    Corrupt,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::BadHeader => write!(f, "Not a save state of this version"),
+            StateError::WrongSize => write!(f, "Save state is for a different machine size"),
+            StateError::WrongPlatform => write!(f, "Save state is for a different platform"),
+            StateError::Corrupt => write!(f, "Save state is corrupt"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+struct Reader<'a> {
+    data: &'a [Byte],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [Byte], StateError> {
+        if self.data.len() < len {
+            return Err(StateError::WrongSize);
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn byte(&mut self) -> Result<Byte, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn word(&mut self) -> Result<Word, StateError> {
+        Ok(Word::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    /// Size of `save_state`'s output, which only depends on the memory and
+    /// screen size and whether the machine is a MegaChip.
+    pub fn state_size(&self) -> usize {
+        MAGIC.len() + 1
+            + 4 + self.memory.size()
+            + CPU::NREG + CPU::NREG * 2
+            + 4 + 2 + 3 + 4 + 1
+            + 2 + self.display.height() * 8
+            + 16 + 1
+            + 1 + chip8x::STATE_SIZE
+            + 1 + if self.mega.is_some() { megachip::STATE_SIZE } else { 0 }
+    }
+
+    pub fn save_state(&self) -> Vec<Byte> {
+        let mut out = Vec::with_capacity(self.state_size());
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        out.extend_from_slice(&(self.memory.size() as u32).to_le_bytes());
+        out.extend_from_slice(self.memory.bytes());
+        out.extend_from_slice(&self.v);
+        for addr in self.stack {
+            out.extend_from_slice(&addr.to_le_bytes());
+        }
+        out.extend_from_slice(&self.i.to_le_bytes());
+        out.extend_from_slice(&self.pc.to_le_bytes());
+        out.extend_from_slice(&[self.sp, self.dt, self.st]);
+        out.extend_from_slice(&self.ticks.to_le_bytes());
+        out.push(self.vblank_wait as Byte);
+        out.extend_from_slice(&(self.display.height() as Word).to_le_bytes());
+        for row in self.display.rows() {
+            out.extend_from_slice(&row.to_le_bytes());
+        }
+        out.extend_from_slice(&self.audio.pattern);
+        out.push(self.audio.pitch);
+        out.push(self.color.is_some() as Byte);
+        out.extend_from_slice(&self.color.as_ref().map_or([0; chip8x::STATE_SIZE], ColorBoard::to_bytes));
+        out.push(self.mega.is_some() as Byte);
+        if let Some(mega) = self.mega.as_ref() {
+            out.extend_from_slice(&mega.to_bytes());
+        }
+        out
+    }
+
+    /// Restores a state from `save_state`. Nothing changes if it fails.
+    pub fn load_state(&mut self, data: &[Byte]) -> Result<(), StateError> {
+        let mut reader = Reader { data };
+        if reader.take(MAGIC.len())? != MAGIC || reader.byte()? != VERSION {
+            return Err(StateError::BadHeader);
+        }
+        if reader.u32()? as usize != self.memory.size() {
+            return Err(StateError::WrongSize);
+        }
+        let memory = reader.take(self.memory.size())?;
+        let v = reader.take(CPU::NREG)?;
+        let mut stack = [0; CPU::NREG];
+        for addr in stack.iter_mut() {
+            *addr = reader.word()?;
+        }
+        let i = reader.u32()?;
+        let pc = reader.word()?;
+        let [sp, dt, st] = reader.take(3)?.try_into().unwrap();
+        let ticks = reader.u32()?;
+        let vblank_wait = reader.byte()? != 0;
+        let height = reader.word()? as usize;
+        if height != self.platform.display_height() {
+            return Err(StateError::WrongSize);
+        }
+        let rows = reader.take(height * 8)?
+            .chunks_exact(8)
+            .map(|row| u64::from_le_bytes(row.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        let pattern = reader.take(16)?.try_into().unwrap();
+        let pitch = reader.byte()?;
+        let has_color = reader.byte()? != 0;
+        let color = ColorBoard::from_bytes(reader.take(chip8x::STATE_SIZE)?.try_into().unwrap());
+        let mega = match reader.byte()? {
+            0 => None,
+            _ => Some(MegaChip::from_bytes(reader.take(megachip::STATE_SIZE)?)),
+        };
+        if sp as usize > CPU::NREG {
+            return Err(StateError::Corrupt);
+        }
+        if mega.is_some() != self.mega.is_some() {
+            return Err(StateError::WrongPlatform);
+        }
+
+        self.memory.load(0, memory);
+        self.flush_decoded();
+        self.v.copy_from_slice(v);
+        self.stack = stack;
+        (self.i, self.pc, self.sp, self.dt, self.st) = (i, pc, sp, dt, st);
+        self.ticks = ticks;
+        self.vblank_wait = vblank_wait;
+        self.display.load_rows(&rows);
+        self.audio.pattern = pattern;
+        self.audio.pitch = pitch;
+        self.color = has_color.then_some(color);
+        self.mega = mega;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Ram16M;
+    use crate::platform::Platform;
+
+    #[test]
+    fn test_save_state() {
+        let mut cpu = CPU::default();
+        cpu.init();
+        cpu.run(5_000);
+        cpu.color = Some(ColorBoard::default());
+        cpu.color.as_mut().unwrap().fill_rows(8, 2, 3, 6);
+        let state = cpu.save_state();
+        assert_eq!(state.len(), cpu.state_size(), "State size not as announced");
+
+        let mut restored = CPU::default();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.save_state(), state, "State not restored");
+        assert_eq!(restored.color, cpu.color, "Colour board not restored");
+
+        cpu.run(1_000);
+        restored.run(1_000);
+        assert_eq!(restored.display, cpu.display, "Restored machine ran differently");
+
+        assert_eq!(restored.load_state(&state[1..]), Err(StateError::BadHeader), "Bad header accepted");
+        assert_eq!(restored.load_state(&state[..100]), Err(StateError::WrongSize), "Truncated state accepted");
+    }
+
+    #[test]
+    fn test_mega_state() {
+        let mut cpu = CPU::with_bus(Ram16M::new());
+        cpu.set_platform(Platform::MegaChip);
+        let mega = cpu.mega.as_mut().unwrap();
+        mega.enabled = true;
+        mega.palette[1] = [0x10, 0x20, 0x30];
+        mega.draw(5, 6, &[1]);
+        mega.flip();
+        cpu.memory.load(0x400, &[0x17, 0x70, 0x00, 0x01, 0x00, 0]);
+        cpu.i = 0x400;
+        cpu.exec(megachip::decode(0x0600));
+        let state = cpu.save_state();
+        assert_eq!(state.len(), cpu.state_size(), "State size not as announced");
+
+        let mut restored = CPU::with_bus(Ram16M::new());
+        assert_eq!(restored.load_state(&state), Err(StateError::WrongPlatform), "MegaChip state loaded on CHIP-8");
+        restored.set_platform(Platform::MegaChip);
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.mega, cpu.mega, "MegaChip screen, palette and sound not restored");
+        assert_eq!(restored.save_state(), state, "State not restored");
+
+        let (mut played, mut restored_played) = (Vec::new(), Vec::new());
+        cpu.render_audio(6_000, &mut played);
+        restored.render_audio(6_000, &mut restored_played);
+        assert!(played.iter().any(|&sample| sample != 0), "Sound not started");
+        assert_eq!(restored_played, played, "Sound not restored");
+    }
+
+    #[test]
+    fn test_reject_invalid() {
+        let mut cpu = CPU::default();
+        cpu.init();
+        cpu.run(1_000);
+        let state = cpu.save_state();
+        let before = cpu.save_state();
+
+        let mut bad_sp = state.clone();
+        let sp = MAGIC.len() + 1 + 4 + cpu.memory.size() + CPU::NREG + CPU::NREG * 2 + 4 + 2;
+        bad_sp[sp] = CPU::NREG as Byte + 1;
+        assert_eq!(cpu.load_state(&bad_sp), Err(StateError::Corrupt), "Stack pointer past the stack accepted");
+
+        let mut hires = CPU::default();
+        hires.set_platform(Platform::Hires);
+        assert_eq!(hires.load_state(&state), Err(StateError::WrongSize), "Screen height of another platform accepted");
+        assert_eq!(cpu.save_state(), before, "Machine changed by a rejected state");
+    }
+}
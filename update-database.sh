@@ -0,0 +1,21 @@
+#!/bin/bash
+# Merge the community CHIP-8 database into src/database/. The entries for
+# the ROMs bundled in src/roms stay first and win over the community's;
+# sha1-hashes.json is rebuilt from the programs. Needs curl and jq.
+set -e
+BASE=${CHIP8_DATABASE_URL:-https://raw.githubusercontent.com/chip-8/chip-8-database/master/database}
+DIR=$(dirname "$0")/src/database
+ROMS=$(cd "$(dirname "$0")/src/roms" && ls | jq -R . | jq -s .)
+TMP=$(mktemp -d)
+trap 'rm -rf "$TMP"' EXIT
+
+curl -fsSL "$BASE/programs.json" -o "$TMP/programs.json"
+
+jq -s --argjson roms "$ROMS" '
+    (.[0] | map(select([.roms[].file] | any(. as $file | $roms | index($file))))) + .[1]
+' "$DIR/programs.json" "$TMP/programs.json" > "$TMP/merged.json"
+
+jq '
+    to_entries | map(.key as $index | .value.roms | keys | map({(.): $index})) | flatten | reverse | add
+' "$TMP/merged.json" > "$DIR/sha1-hashes.json"
+mv "$TMP/merged.json" "$DIR/programs.json"